@@ -1,14 +1,19 @@
 extern crate bk_tree;
 extern crate clap;
 extern crate console;
+extern crate globset;
+extern crate ignore;
 extern crate image;
 extern crate img_hash;
 extern crate indicatif;
 #[macro_use]
 extern crate lazy_static;
+extern crate num_cpus;
+extern crate rusqlite;
 extern crate rustc_serialize as serialize;
 #[macro_use]
 extern crate serde_derive;
+extern crate serde_json;
 extern crate serde_yaml;
 extern crate sha2;
 extern crate subprocess;
@@ -1,60 +1,178 @@
 extern crate itools;
 
 use std::error::Error;
-use std::fs::File;
-use std::path::{Path, PathBuf};
+use std::fs;
+use std::path::PathBuf;
+use std::process;
 
 use itools::neardups::{
-    bool_to_option, expand_file_list, new_counter, output::Output, Config, Hasher, ItoolsError,
-    PersistedCache, Result, SpinnerReader,
+    bool_to_option, expand_file_list, new_counter,
+    output::{Output, Report},
+    print_summary, print_walk_errors, read_file_list, write_failures, CacheStore, Config,
+    FileStore, HashNeeds, Hasher, ItoolsError, LibraryRoot, PersistedCache, Result, SqliteStore,
+    StoreKind, WalkOptions,
 };
 
-fn load_or_create_cache_file<T>(cache_file: T) -> Result<PersistedCache>
-where
-    T: AsRef<Path>,
-{
-    // If we fail to load the cached file, report an error to avoid overwriting data.
-    // If the file doesn't exist, then go ahead and create a brand new one.
-    if !cache_file.as_ref().exists() {
-        Ok(PersistedCache::new())
-    } else {
-        let file = File::open(cache_file)?;
-        let r = SpinnerReader::new(file, "Loading cache file...");
-        PersistedCache::from_reader(r)
-    }
+fn open_cache(config: &Config) -> Result<PersistedCache> {
+    // The root has to be canonical to match the (canonical) paths of the files.
+    let root = match config.library_root {
+        Some(ref root) => LibraryRoot::new(Some(root.canonicalize()?)),
+        None => LibraryRoot::default(),
+    };
+
+    let kind = config
+        .cache_store
+        .unwrap_or_else(|| StoreKind::for_file(&config.cache_file));
+    let store: Box<CacheStore> = match kind {
+        StoreKind::File => {
+            let mut store = FileStore::new(config.cache_file.clone());
+            store.set_root(root);
+            store.set_journaled(config.cache_journal);
+            if let Some(format) = config.cache_format {
+                store.set_format(format);
+            }
+            Box::new(store)
+        }
+        StoreKind::Sqlite => {
+            let mut store = SqliteStore::open(&config.cache_file)?;
+            store.set_root(root);
+            Box::new(store)
+        }
+    };
+    PersistedCache::load(store)
 }
 
-fn filter_files_in_cache(files: &Vec<PathBuf>, cache: &PersistedCache) -> Vec<PathBuf> {
-    files
+fn filter_files_in_cache(
+    files: &Vec<PathBuf>,
+    cache: &PersistedCache,
+    needs: &HashNeeds,
+    retry_failed: bool,
+) -> Vec<PathBuf> {
+    let mut skipped_failures = 0;
+    let remaining = files
         .iter()
-        .flat_map(|f| bool_to_option(!cache.contains_file(f), || f.clone()))
-        .collect()
+        .filter(|f| !cache.contains_current_file(f, needs))
+        .filter(|f| {
+            let skip = !retry_failed && cache.contains_failed_file(f);
+            if skip {
+                skipped_failures += 1;
+            }
+            !skip
+        })
+        .cloned()
+        .collect();
+    if skipped_failures > 0 {
+        eprintln!(
+            "Skipping {} files that failed to hash before. (Use --retry_failed to try them again.)",
+            skipped_failures
+        );
+    }
+    remaining
 }
 
 fn run() -> Result<()> {
     let config = Config::new()?;
 
-    let mut cache = load_or_create_cache_file(&config.cache_file)?;
+    let mut cache = open_cache(&config)?;
 
-    // TODO: report the missing files.
-    let (files, _missing) = expand_file_list(config.files)?;
+    if let Some(ref command) = config.command {
+        let (table, store) = cache.join();
+        return command.run(&config.cache_file, table, store, &config.decoder);
+    }
 
-    let files_to_hash = filter_files_in_cache(&files, &cache);
+    let walk_options = WalkOptions {
+        by_extension: config.by_extension,
+        decoder: config.decoder.clone(),
+        include: config.include.clone(),
+        exclude: config.exclude.clone(),
+        follow_symlinks: config.follow_symlinks,
+        max_depth: config.max_depth,
+        one_file_system: config.one_file_system,
+        skip_hidden: config.skip_hidden,
+    };
+    let mut inputs = config.files;
+    if let Some(ref files_from) = config.files_from {
+        inputs.extend(read_file_list(files_from, config.null_separated)?);
+    }
+    let file_list = expand_file_list(inputs, &walk_options)?;
+    for path in &file_list.missing {
+        eprintln!("No such file or directory: {}", path.display());
+    }
+    print_walk_errors(&file_list.walk_errors);
+    if file_list.non_images > 0 {
+        eprintln!("Skipped {} files that aren't images.", file_list.non_images);
+    }
+    if file_list.needs_converter > 0 {
+        eprintln!(
+            "Skipped {} HEIC files, which need a converter. (See --heic_converter.)",
+            file_list.needs_converter
+        );
+    }
+    let mut files = file_list.files;
+
+    if config.size_filter.min_size.is_some() || config.size_filter.max_size.is_some() {
+        let before = files.len();
+        let size_filter = config.size_filter;
+        files.retain(|f| {
+            fs::metadata(f)
+                .map(|md| size_filter.accepts_file_size(md.len()))
+                .unwrap_or(true)
+        });
+        if files.len() < before {
+            eprintln!(
+                "Skipped {} files outside of the size limits.",
+                before - files.len()
+            );
+        }
+    }
+
+    let mut needs = config.search.hash_needs();
+    needs.dimensions = config.size_filter.min_pixels.is_some();
+    let mut files_to_hash = filter_files_in_cache(&files, &cache, &needs, config.retry_failed);
+    if config.search.compares_bytes() {
+        let (remaining, unique) = cache.files_with_shared_sizes(files_to_hash);
+        if unique > 0 {
+            eprintln!("Skipped hashing {} files with unique sizes.", unique);
+        }
+        files_to_hash = remaining;
+    }
+    let attempted = files_to_hash.clone();
 
     let num_files = files_to_hash.len() as u64;
-    let (hasher, agg_rx) = Hasher::run(files_to_hash);
+    let (hasher, agg_rx) = Hasher::run(
+        files_to_hash,
+        needs,
+        cache.sha2_index(),
+        config.jobs,
+        config.decoder.clone(),
+        config.memory_budget,
+    );
 
     let pb = bool_to_option(config.show_progress, || new_counter(num_files));
-    cache.run(config.cache_file, agg_rx, pb);
+    cache.run(agg_rx, pb);
 
-    hasher.join();
-    let fileinfo = cache.join();
+    let failures = hasher.join();
+    cache.record_failures(&attempted, &failures)?;
+    let (fileinfo, store) = cache.join();
+
+    print_summary(&failures);
+    if let Some(ref failures_file) = config.failures_file {
+        write_failures(failures_file, &failures)?;
+    }
 
     if !config.cache_only {
-        let matches = config.search.find_dups(files, fileinfo);
-        config.output.output(matches);
+        let matches = config
+            .search
+            .find_dups(files, fileinfo, store.as_ref(), &config.size_filter);
+        config.output.output(Report {
+            matches,
+            missing: file_list.missing.clone(),
+        });
     }
 
+    if config.strict && !file_list.missing.is_empty() {
+        return Err(ItoolsError::MissingInputs(file_list.missing.len()));
+    }
     Ok(())
 }
 
@@ -62,6 +180,9 @@ fn main() {
     match run() {
         Ok(_) => (),
         Err(ItoolsError::Clap(err)) => println!("{}", err.description()),
-        Err(e) => println!("Error: {:?}", e),
+        Err(e) => {
+            eprintln!("Error: {}", e);
+            process::exit(1);
+        }
     }
 }
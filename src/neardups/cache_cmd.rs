@@ -0,0 +1,326 @@
+use std::borrow::Cow;
+use std::collections::BTreeSet;
+use std::fs;
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use super::decoder::Decoder;
+use super::fileinfo::{FileInfo, HashNeeds, HashSpec};
+use super::hasher::hash_file;
+use super::pcache::HashTable;
+use super::result::{ItoolsError, Result};
+use super::store::CacheStore;
+use super::utils::xorshift;
+
+// Maintenance operations on the cache, run with `itools cache <command>`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum CacheCommand {
+    // Drop entries for files that no longer exist, or that aren't under any of the
+    // given roots (if there are any).
+    Prune(Vec<PathBuf>),
+    // Rename every entry under the first prefix to be under the second.
+    Relocate(PathBuf, PathBuf),
+    Stats,
+    // Re-hash this many randomly chosen entries, and report any that don't match.
+    Verify(usize),
+    Export(ExportFormat),
+    // List the files that couldn't be hashed.
+    Failures,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ExportFormat {
+    Csv,
+    Json,
+}
+
+impl CacheCommand {
+    pub fn run(
+        &self,
+        cache_file: &Path,
+        table: HashTable,
+        store: Box<CacheStore>,
+        decoder: &Decoder,
+    ) -> Result<()> {
+        use self::CacheCommand::*;
+        match *self {
+            Prune(ref roots) => prune(roots, table, store),
+            Relocate(ref old_prefix, ref new_prefix) => {
+                relocate(old_prefix, new_prefix, table, store)
+            }
+            Stats => stats(cache_file, &table),
+            Verify(sample_size) => verify(sample_size, &table, decoder),
+            Export(format) => export(format, &table),
+            Failures => failures(store),
+        }
+    }
+}
+
+fn prune(roots: &[PathBuf], mut table: HashTable, mut store: Box<CacheStore>) -> Result<()> {
+    let is_doomed = |path: &Path| {
+        !path.exists() || (!roots.is_empty() && !roots.iter().any(|r| path.starts_with(r)))
+    };
+
+    let total = table.len();
+    let doomed: Vec<PathBuf> = table
+        .keys()
+        .filter(|path| is_doomed(path))
+        .cloned()
+        .collect();
+
+    for path in &doomed {
+        table.remove(path);
+        store.remove(path)?;
+    }
+    store.checkpoint(&table, true)?;
+
+    let mut failed = store.load_failures()?;
+    let failed_total = failed.len();
+    failed.retain(|f| !is_doomed(&f.path));
+    if failed.len() != failed_total {
+        store.save_failures(&failed)?;
+    }
+
+    println!("Pruned {} of {} entries.", doomed.len(), total);
+    println!(
+        "Pruned {} of {} failed files.",
+        failed_total - failed.len(),
+        failed_total
+    );
+    Ok(())
+}
+
+fn relocate(
+    old_prefix: &Path,
+    new_prefix: &Path,
+    mut table: HashTable,
+    mut store: Box<CacheStore>,
+) -> Result<()> {
+    let moving: Vec<PathBuf> = table
+        .keys()
+        .filter(|path| path.starts_with(old_prefix))
+        .cloned()
+        .collect();
+    // Most likely a typo in the prefix.
+    if moving.is_empty() {
+        return Err(ItoolsError::UsageError(
+            "no entries in the cache are under the old prefix",
+        ));
+    }
+
+    for old_path in &moving {
+        // unwrap() is safe, since we just checked the prefix.
+        let new_path = new_prefix.join(old_path.strip_prefix(old_prefix).unwrap());
+        let mut fi = table.remove(old_path).unwrap();
+        store.remove(old_path)?;
+        fi.filename = new_path.clone();
+        store.insert(&fi)?;
+        table.insert(new_path, fi);
+    }
+    store.checkpoint(&table, true)?;
+
+    println!("Relocated {} entries.", moving.len());
+    Ok(())
+}
+
+fn stats(cache_file: &Path, table: &HashTable) -> Result<()> {
+    let cache_size = fs::metadata(cache_file).map(|md| md.len()).unwrap_or(0);
+    let image_size: u64 = table.values().map(|fi| fi.stamp.size).sum();
+
+    println!("Entries:     {}", table.len());
+    println!("Cache size:  {} bytes", cache_size);
+    println!("Image size:  {} bytes", image_size);
+    println!("Hash coverage:");
+    let specs: BTreeSet<HashSpec> = table.values().flat_map(|fi| fi.hash_specs()).collect();
+    for spec in specs {
+        let count = table.values().filter(|fi| fi.has_hash(spec)).count();
+        println!(
+            "   {:<9} {} ({:.1}%)",
+            spec.name(),
+            count,
+            percent(count, table.len())
+        );
+    }
+    Ok(())
+}
+
+fn verify(sample_size: usize, table: &HashTable, decoder: &Decoder) -> Result<()> {
+    // Sort first, so that the sample only depends on the seed.
+    let mut paths: Vec<&PathBuf> = table.keys().collect();
+    paths.sort();
+    let sample = random_sample(&mut paths, sample_size, seed());
+
+    let mut mismatches = 0;
+    for path in sample {
+        let cached = &table[*path];
+        // Only the hashes that were computed for the entry can be checked.
+        let specs = cached.hash_specs();
+        match hash_file(path, &HashNeeds::new(specs.clone()), decoder) {
+            Ok(fresh) => {
+                let differing: Vec<String> = specs
+                    .iter()
+                    .filter(|spec| fresh.hash(**spec) != cached.hash(**spec))
+                    .map(|spec| spec.name())
+                    .collect();
+                if !differing.is_empty() {
+                    mismatches += 1;
+                    println!("MISMATCH {}: {}", path.display(), differing.join(", "));
+                }
+            }
+            Err(err) => {
+                mismatches += 1;
+                println!("ERROR {}: {:?}", path.display(), err);
+            }
+        }
+    }
+
+    println!(
+        "Verified {} entries, {} mismatched.",
+        sample.len(),
+        mismatches
+    );
+    Ok(())
+}
+
+fn export(format: ExportFormat, table: &HashTable) -> Result<()> {
+    let mut entries: Vec<&FileInfo> = table.values().collect();
+    entries.sort_by(|a, b| a.filename.cmp(&b.filename));
+
+    let stdout = io::stdout();
+    let mut out = BufWriter::new(stdout.lock());
+    match format {
+        ExportFormat::Json => serde_json::to_writer_pretty(&mut out, &entries)?,
+        ExportFormat::Csv => {
+            writeln!(
+                out,
+                "path,size,mtime_secs,width,height,a_hash,d_hash,p_hash,sha2_hash,other_hashes"
+            )?;
+            for fi in entries {
+                // name=hash pairs, separated by spaces.
+                let other_hashes: Vec<String> = fi
+                    .hashes
+                    .iter()
+                    .map(|(name, hash)| format!("{}={}", name, hash))
+                    .collect();
+                writeln!(
+                    out,
+                    "{},{},{},{},{},{},{},{},{},{}",
+                    csv_field(&fi.filename.to_string_lossy()),
+                    fi.stamp.size,
+                    fi.stamp.mtime_secs,
+                    fi.width,
+                    fi.height,
+                    fi.a_hash,
+                    fi.d_hash,
+                    fi.p_hash,
+                    fi.sha2_hash,
+                    other_hashes.join(" ")
+                )?;
+            }
+        }
+    }
+    out.flush()?;
+    Ok(())
+}
+
+fn failures(mut store: Box<CacheStore>) -> Result<()> {
+    let mut failed = store.load_failures()?;
+    failed.sort_by(|a, b| a.path.cmp(&b.path));
+    for f in &failed {
+        println!("{}\t{}\t{}", f.kind, f.path.display(), f.message);
+    }
+    println!("{} files failed to hash.", failed.len());
+    Ok(())
+}
+
+fn percent(count: usize, total: usize) -> f64 {
+    if total == 0 {
+        0.0
+    } else {
+        100.0 * count as f64 / total as f64
+    }
+}
+
+// Quote a CSV field if it needs it. (Hashes are base64, so they never do.)
+fn csv_field(s: &str) -> Cow<str> {
+    if s.contains(|c: char| c == ',' || c == '"' || c == '\n' || c == '\r') {
+        Cow::Owned(format!("\"{}\"", s.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(s)
+    }
+}
+
+fn seed() -> u64 {
+    let now = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default();
+    // xorshift gets stuck on zero.
+    (now.as_secs() ^ u64::from(now.subsec_nanos())) | 1
+}
+
+// Shuffle `n` random items to the front of the vector (a partial Fisher-Yates
+// shuffle, driven by xorshift), and return them.
+fn random_sample<T>(items: &mut [T], n: usize, seed: u64) -> &[T] {
+    let n = n.min(items.len());
+    let mut state = seed;
+    for i in 0..n {
+        let j = i + (xorshift(&mut state) % (items.len() - i) as u64) as usize;
+        items.swap(i, j);
+    }
+    &items[..n]
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::path::{Path, PathBuf};
+
+    use super::super::fileinfo::FileInfo;
+    use super::super::pcache::HashTable;
+    use super::super::sqlite_store::SqliteStore;
+    use super::super::store::CacheStore;
+    use super::{csv_field, random_sample, relocate};
+
+    #[test]
+    fn test_csv_field() {
+        assert_eq!("plain.jpg", csv_field("plain.jpg"));
+        assert_eq!("\"a,b.jpg\"", csv_field("a,b.jpg"));
+        assert_eq!("\"say \"\"hi\"\".jpg\"", csv_field("say \"hi\".jpg"));
+    }
+
+    #[test]
+    fn test_random_sample() {
+        let mut items: Vec<u32> = (0..100).collect();
+        let sample = random_sample(&mut items, 10, 12345).to_vec();
+        assert_eq!(10, sample.len());
+        let unique: HashSet<u32> = sample.iter().cloned().collect();
+        assert_eq!(10, unique.len());
+
+        let mut few = vec![1, 2, 3];
+        assert_eq!(3, random_sample(&mut few, 10, 12345).len());
+    }
+
+    #[test]
+    fn test_relocate() {
+        let mut store = SqliteStore::open(Path::new(":memory:")).unwrap();
+        let fi = FileInfo {
+            filename: PathBuf::from("/old/2018/a.jpg"),
+            sha2_hash: "a".into(),
+            ..FileInfo::default()
+        };
+        store.insert(&fi).unwrap();
+        let table: HashTable = vec![(fi.filename.clone(), fi)].into_iter().collect();
+
+        // Nothing under the prefix is an error, rather than a silent no-op.
+        assert!(relocate(
+            Path::new("/elsewhere"),
+            Path::new("/new"),
+            table.clone(),
+            Box::new(SqliteStore::open(Path::new(":memory:")).unwrap())
+        )
+        .is_err());
+
+        relocate(Path::new("/old"), Path::new("/new"), table, Box::new(store)).unwrap();
+    }
+}
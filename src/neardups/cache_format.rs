@@ -0,0 +1,465 @@
+use std::collections::BTreeMap;
+use std::io::{self, Cursor, Read, Write};
+use std::path::{Path, PathBuf};
+
+use serialize::base64::{FromBase64, ToBase64, STANDARD};
+
+use super::fileinfo::{FileInfo, FileStamp};
+use super::pcache::HashTable;
+use super::result::{ItoolsError, Result};
+
+// Binary cache layout (all integers little-endian):
+//
+//   header:  MAGIC, version: u32, record count: u64
+//   record:  body length: u32, body
+//   body:    path (u32 length + bytes)
+//            size: u64, mtime_secs: u64, mtime_nanos: u32,
+//            flags: u8 (1 = has device, 2 = has inode), device: u64, inode: u64
+//            a, d, p, sha2 hashes (each u8 length + raw bytes)
+//            width: u32, height: u32 (may be missing in older records)
+//            other hashes (may be missing in older records): count: u16, then
+//                for each, a name (u8 length + bytes) and a raw hash (u16 length
+//                + bytes)
+//
+// Records are length-prefixed so that a newer version can append fields to the
+// body without breaking older readers.
+const MAGIC: &[u8; 8] = b"NDUPSBIN";
+const VERSION: u32 = 1;
+
+// The record count is read from the file, so a corrupt one could ask for any
+// amount of memory up front. The table grows past this as records are read.
+const MAX_PREALLOCATED_RECORDS: u64 = 1 << 16;
+
+const HAS_DEVICE: u8 = 1;
+const HAS_INODE: u8 = 2;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CacheFormat {
+    Yaml,
+    Binary,
+}
+
+impl Default for CacheFormat {
+    fn default() -> CacheFormat {
+        CacheFormat::Yaml
+    }
+}
+
+// Read a cache in either format, and report which format it was in.
+pub fn read_table<T>(mut reader: T) -> Result<(HashTable, CacheFormat)>
+where
+    T: Read,
+{
+    let mut header = Vec::with_capacity(MAGIC.len());
+    reader
+        .by_ref()
+        .take(MAGIC.len() as u64)
+        .read_to_end(&mut header)?;
+
+    if header.as_slice() == &MAGIC[..] {
+        Ok((read_binary(reader)?, CacheFormat::Binary))
+    } else {
+        // Not ours, so put the bytes back and let serde have a go at it.
+        let table = serde_yaml::from_reader(Cursor::new(header).chain(reader))?;
+        Ok((table, CacheFormat::Yaml))
+    }
+}
+
+pub fn write_table<T>(mut writer: T, table: &HashTable, format: CacheFormat) -> Result<()>
+where
+    T: Write,
+{
+    match format {
+        CacheFormat::Yaml => serde_yaml::to_writer(writer, table)?,
+        CacheFormat::Binary => {
+            writer.write_all(MAGIC)?;
+            writer.write_all(&VERSION.to_le_bytes())?;
+            writer.write_all(&(table.len() as u64).to_le_bytes())?;
+            for fi in table.values() {
+                write_record(&mut writer, fi)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+fn read_binary<T>(mut reader: T) -> Result<HashTable>
+where
+    T: Read,
+{
+    let version = read_u32(&mut reader)?;
+    if version != VERSION {
+        return Err(ItoolsError::CorruptCache(
+            "unsupported binary cache version",
+        ));
+    }
+
+    let count = read_u64(&mut reader)?;
+    let mut table = HashTable::with_capacity(count.min(MAX_PREALLOCATED_RECORDS) as usize);
+    for _ in 0..count {
+        let fi = read_record(&mut reader)?;
+        table.insert(fi.filename.clone(), fi);
+    }
+    Ok(table)
+}
+
+pub fn write_record<T>(writer: &mut T, fi: &FileInfo) -> Result<()>
+where
+    T: Write,
+{
+    let mut body = Vec::new();
+
+    let path = path_to_bytes(&fi.filename)?;
+    body.extend_from_slice(&(path.len() as u32).to_le_bytes());
+    body.extend_from_slice(&path);
+
+    let stamp = &fi.stamp;
+    let mut flags = 0u8;
+    if stamp.device.is_some() {
+        flags |= HAS_DEVICE;
+    }
+    if stamp.inode.is_some() {
+        flags |= HAS_INODE;
+    }
+    body.extend_from_slice(&stamp.size.to_le_bytes());
+    body.extend_from_slice(&stamp.mtime_secs.to_le_bytes());
+    body.extend_from_slice(&stamp.mtime_nanos.to_le_bytes());
+    body.push(flags);
+    body.extend_from_slice(&stamp.device.unwrap_or(0).to_le_bytes());
+    body.extend_from_slice(&stamp.inode.unwrap_or(0).to_le_bytes());
+
+    for hash in &[&fi.a_hash, &fi.d_hash, &fi.p_hash, &fi.sha2_hash] {
+        let raw = hash
+            .from_base64()
+            .map_err(|_| ItoolsError::CorruptCache("hash is not valid base64"))?;
+        if raw.len() > u8::max_value() as usize {
+            return Err(ItoolsError::CorruptCache("hash is too long"));
+        }
+        body.push(raw.len() as u8);
+        body.extend_from_slice(&raw);
+    }
+    body.extend_from_slice(&fi.width.to_le_bytes());
+    body.extend_from_slice(&fi.height.to_le_bytes());
+
+    body.extend_from_slice(&(fi.hashes.len() as u16).to_le_bytes());
+    for (name, hash) in &fi.hashes {
+        let raw = hash
+            .from_base64()
+            .map_err(|_| ItoolsError::CorruptCache("hash is not valid base64"))?;
+        if name.len() > u8::max_value() as usize || raw.len() > u16::max_value() as usize {
+            return Err(ItoolsError::CorruptCache("hash is too long"));
+        }
+        body.push(name.len() as u8);
+        body.extend_from_slice(name.as_bytes());
+        body.extend_from_slice(&(raw.len() as u16).to_le_bytes());
+        body.extend_from_slice(&raw);
+    }
+
+    writer.write_all(&(body.len() as u32).to_le_bytes())?;
+    writer.write_all(&body)?;
+    Ok(())
+}
+
+pub fn read_record<T>(reader: &mut T) -> Result<FileInfo>
+where
+    T: Read,
+{
+    let len = read_u32(reader)?;
+    // A record that stops short is an UnexpectedEof, just like one that stops in
+    // its length, so that a journal can tell a torn tail from corruption.
+    let body = match read_bytes(reader, len as usize) {
+        Err(ItoolsError::CorruptCache(msg)) => {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, msg).into())
+        }
+        body => body?,
+    };
+    // But the body is all there, so running out of it means that the lengths
+    // inside it are wrong.
+    read_body(Cursor::new(body), len).map_err(|err| match err {
+        ItoolsError::IO(ref io_err) if io_err.kind() == io::ErrorKind::UnexpectedEof => {
+            ItoolsError::CorruptCache("record is truncated")
+        }
+        err => err,
+    })
+}
+
+fn read_body(mut body: Cursor<Vec<u8>>, len: u32) -> Result<FileInfo> {
+    let path_len = read_u32(&mut body)?;
+    let filename = bytes_to_path(read_bytes(&mut body, path_len as usize)?)?;
+
+    let size = read_u64(&mut body)?;
+    let mtime_secs = read_u64(&mut body)?;
+    let mtime_nanos = read_u32(&mut body)?;
+    let flags = read_u8(&mut body)?;
+    let device = read_u64(&mut body)?;
+    let inode = read_u64(&mut body)?;
+    let stamp = FileStamp {
+        size,
+        mtime_secs,
+        mtime_nanos,
+        device: if flags & HAS_DEVICE != 0 {
+            Some(device)
+        } else {
+            None
+        },
+        inode: if flags & HAS_INODE != 0 {
+            Some(inode)
+        } else {
+            None
+        },
+    };
+
+    let a_hash = read_hash(&mut body)?;
+    let d_hash = read_hash(&mut body)?;
+    let p_hash = read_hash(&mut body)?;
+    let sha2_hash = read_hash(&mut body)?;
+
+    let (width, height) = if body.position() < u64::from(len) {
+        (read_u32(&mut body)?, read_u32(&mut body)?)
+    } else {
+        (0, 0)
+    };
+
+    let mut hashes = BTreeMap::new();
+    if body.position() < u64::from(len) {
+        for _ in 0..read_u16(&mut body)? {
+            let name_len = read_u8(&mut body)?;
+            let name = String::from_utf8(read_bytes(&mut body, name_len as usize)?)
+                .map_err(|_| ItoolsError::CorruptCache("hash name is not valid UTF-8"))?;
+            let hash_len = read_u16(&mut body)?;
+            let hash = read_bytes(&mut body, hash_len as usize)?.to_base64(STANDARD);
+            hashes.insert(name, hash);
+        }
+    }
+
+    Ok(FileInfo {
+        filename,
+        a_hash,
+        d_hash,
+        p_hash,
+        sha2_hash,
+        stamp,
+        width,
+        height,
+        hashes,
+    })
+}
+
+fn read_hash<T>(reader: &mut T) -> Result<String>
+where
+    T: Read,
+{
+    let len = read_u8(reader)?;
+    Ok(read_bytes(reader, len as usize)?.to_base64(STANDARD))
+}
+
+fn read_bytes<T>(reader: &mut T, len: usize) -> Result<Vec<u8>>
+where
+    T: Read,
+{
+    // The length was read from the file too, so the buffer only grows as the data
+    // actually arrives. A corrupt length runs out of data instead of memory.
+    let mut buf = Vec::new();
+    reader.by_ref().take(len as u64).read_to_end(&mut buf)?;
+    if buf.len() != len {
+        return Err(ItoolsError::CorruptCache("record is truncated"));
+    }
+    Ok(buf)
+}
+
+fn read_u8<T>(reader: &mut T) -> Result<u8>
+where
+    T: Read,
+{
+    let mut buf = [0u8; 1];
+    reader.read_exact(&mut buf)?;
+    Ok(buf[0])
+}
+
+fn read_u16<T>(reader: &mut T) -> Result<u16>
+where
+    T: Read,
+{
+    let mut buf = [0u8; 2];
+    reader.read_exact(&mut buf)?;
+    Ok(u16::from_le_bytes(buf))
+}
+
+fn read_u32<T>(reader: &mut T) -> Result<u32>
+where
+    T: Read,
+{
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+fn read_u64<T>(reader: &mut T) -> Result<u64>
+where
+    T: Read,
+{
+    let mut buf = [0u8; 8];
+    reader.read_exact(&mut buf)?;
+    Ok(u64::from_le_bytes(buf))
+}
+
+#[cfg(unix)]
+fn path_to_bytes(path: &Path) -> Result<Vec<u8>> {
+    use std::os::unix::ffi::OsStrExt;
+    Ok(path.as_os_str().as_bytes().to_vec())
+}
+
+#[cfg(not(unix))]
+fn path_to_bytes(path: &Path) -> Result<Vec<u8>> {
+    path.to_str()
+        .map(|s| s.as_bytes().to_vec())
+        .ok_or(ItoolsError::CorruptCache("path is not valid UTF-8"))
+}
+
+#[cfg(unix)]
+fn bytes_to_path(bytes: Vec<u8>) -> Result<PathBuf> {
+    use std::ffi::OsString;
+    use std::os::unix::ffi::OsStringExt;
+    Ok(OsString::from_vec(bytes).into())
+}
+
+#[cfg(not(unix))]
+fn bytes_to_path(bytes: Vec<u8>) -> Result<PathBuf> {
+    String::from_utf8(bytes)
+        .map(PathBuf::from)
+        .map_err(|_| ItoolsError::CorruptCache("path is not valid UTF-8"))
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+    use std::path::PathBuf;
+
+    use super::super::fileinfo::{FileInfo, FileStamp};
+    use super::super::pcache::HashTable;
+    use super::super::result::ItoolsError;
+    use super::{read_record, read_table, write_record, write_table, CacheFormat};
+
+    fn make_table() -> HashTable {
+        let fi = FileInfo {
+            filename: PathBuf::from("/photos/IMG_0001.JPG"),
+            a_hash: "AAECAwQFBgc=".into(),
+            d_hash: "CAkKCwwNDg8=".into(),
+            p_hash: "EBESExQVFhc=".into(),
+            sha2_hash: "47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=".into(),
+            stamp: FileStamp {
+                size: 12345,
+                mtime_secs: 1_500_000_000,
+                mtime_nanos: 42,
+                device: Some(7),
+                inode: None,
+            },
+            width: 4032,
+            height: 3024,
+            hashes: vec![(
+                "wavelet16".to_string(),
+                "AAECAwQFBgcICQoLDA0ODw==".to_string(),
+            )]
+            .into_iter()
+            .collect(),
+        };
+        let mut table = HashTable::new();
+        table.insert(fi.filename.clone(), fi);
+        table
+    }
+
+    fn round_trip(format: CacheFormat) {
+        let table = make_table();
+        let mut buf = Vec::new();
+        write_table(&mut buf, &table, format).unwrap();
+
+        let (read, read_format) = read_table(buf.as_slice()).unwrap();
+        assert_eq!(format, read_format);
+        assert_eq!(table.len(), read.len());
+        for (path, fi) in &table {
+            let other = &read[path];
+            assert_eq!(fi.filename, other.filename);
+            assert_eq!(fi.a_hash, other.a_hash);
+            assert_eq!(fi.d_hash, other.d_hash);
+            assert_eq!(fi.p_hash, other.p_hash);
+            assert_eq!(fi.sha2_hash, other.sha2_hash);
+            assert_eq!(fi.stamp, other.stamp);
+            assert_eq!(fi.width, other.width);
+            assert_eq!(fi.height, other.height);
+            assert_eq!(fi.hashes, other.hashes);
+        }
+    }
+
+    #[test]
+    fn test_yaml_round_trip() {
+        round_trip(CacheFormat::Yaml);
+    }
+
+    #[test]
+    fn test_binary_round_trip() {
+        round_trip(CacheFormat::Binary);
+    }
+
+    #[test]
+    fn test_record_without_dimensions() {
+        let mut fi = make_table().values().next().unwrap().clone();
+        fi.hashes.clear();
+        let mut buf = Vec::new();
+        write_record(&mut buf, &fi).unwrap();
+
+        // Make it look like a record from before the dimensions (and the other
+        // hashes' count) were added.
+        buf.truncate(buf.len() - 10);
+        let body_len = (buf.len() - 4) as u32;
+        buf[..4].copy_from_slice(&body_len.to_le_bytes());
+
+        let old = read_record(&mut buf.as_slice()).unwrap();
+        assert_eq!(fi.sha2_hash, old.sha2_hash);
+        assert_eq!(0, old.width);
+        assert_eq!(None, old.pixels());
+    }
+
+    #[test]
+    fn test_truncated_binary_is_an_error() {
+        let mut buf = Vec::new();
+        write_table(&mut buf, &make_table(), CacheFormat::Binary).unwrap();
+        buf.truncate(buf.len() - 3);
+        assert!(read_table(buf.as_slice()).is_err());
+    }
+
+    #[test]
+    fn test_corrupt_lengths_are_errors() {
+        let mut buf = Vec::new();
+        write_table(&mut buf, &make_table(), CacheFormat::Binary).unwrap();
+
+        // A record count of u64::MAX, just after the magic and the version.
+        let mut huge_count = buf.clone();
+        huge_count[12..20].copy_from_slice(&u64::max_value().to_le_bytes());
+        assert!(read_table(huge_count.as_slice()).is_err());
+
+        // A body length of u32::MAX for the first record.
+        let mut huge_body = buf.clone();
+        huge_body[20..24].copy_from_slice(&u32::max_value().to_le_bytes());
+        assert!(read_table(huge_body.as_slice()).is_err());
+
+        // A path length of u32::MAX inside the first record's body.
+        let mut huge_path = buf;
+        huge_path[24..28].copy_from_slice(&u32::max_value().to_le_bytes());
+        assert!(read_table(huge_path.as_slice()).is_err());
+        match read_record(&mut &huge_path[20..]) {
+            Err(ItoolsError::CorruptCache(_)) => (),
+            other => panic!("expected a corrupt record, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_torn_record_is_eof() {
+        let mut buf = Vec::new();
+        write_record(&mut buf, make_table().values().next().unwrap()).unwrap();
+        buf.truncate(buf.len() / 2);
+        match read_record(&mut buf.as_slice()) {
+            Err(ItoolsError::IO(ref err)) if err.kind() == io::ErrorKind::UnexpectedEof => (),
+            other => panic!("expected UnexpectedEof, got {:?}", other),
+        }
+    }
+}
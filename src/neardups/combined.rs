@@ -0,0 +1,303 @@
+use std::collections::{BTreeSet, HashMap};
+use std::path::PathBuf;
+
+use bk_tree::BKTree;
+
+use super::fileinfo::{FileInfo, HashSpec};
+use super::perceptual::BitHash;
+use super::search::{percentage, HammingDistance, MatchedFile, Matches};
+use super::size_filter::SizeFilter;
+
+// A search by several perceptual hashes at once. Each hash has its own false
+// positives, and they are mostly different ones, so asking more than one of them
+// to agree cuts the false positives without losing many of the true matches.
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct CombinedHash {
+    pub spec: HashSpec,
+    // The most that the hashes of two files can differ by for this hash's vote.
+    pub distance: u8,
+    // This hash's share of the weighted distance.
+    pub weight: f64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CombineRule {
+    // At least this many of the hashes are within their own distances.
+    Votes(usize),
+    // The weighted mean of the hashes' distances, each as a fraction of the hash's
+    // bits, is at most this.
+    Weighted(f64),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct CombinedSearch {
+    pub hashes: Vec<CombinedHash>,
+    pub rule: CombineRule,
+}
+
+impl CombinedSearch {
+    pub fn specs(&self) -> Vec<HashSpec> {
+        self.hashes.iter().map(|hash| hash.spec).collect()
+    }
+
+    // Two files that match are at least this close by one of the hashes, so the
+    // candidates can be found with that hash's BK-tree.
+    fn candidate_distance(&self, hash: &CombinedHash) -> u64 {
+        match self.rule {
+            CombineRule::Votes(_) => u64::from(hash.distance),
+            // A weighted mean is never smaller than all of its terms.
+            CombineRule::Weighted(cutoff) => (cutoff * f64::from(hash.spec.bits())).floor() as u64,
+        }
+    }
+
+    // From 0, for identical hashes, to 1.
+    fn weighted_distance(&self, distances: &[u64]) -> f64 {
+        let total_weight: f64 = self.hashes.iter().map(|hash| hash.weight).sum();
+        let weighted: f64 = self
+            .hashes
+            .iter()
+            .zip(distances)
+            .map(|(hash, d)| hash.weight * *d as f64 / f64::from(hash.spec.bits()))
+            .sum();
+        weighted / total_weight
+    }
+
+    fn accepts(&self, distances: &[u64]) -> bool {
+        match self.rule {
+            CombineRule::Votes(min_votes) => {
+                let votes = self
+                    .hashes
+                    .iter()
+                    .zip(distances)
+                    .filter(|(hash, d)| **d <= u64::from(hash.distance))
+                    .count();
+                votes >= min_votes
+            }
+            CombineRule::Weighted(cutoff) => self.weighted_distance(distances) <= cutoff,
+        }
+    }
+
+    // The files are ordered by their similarity, which is from the weighted distance.
+    pub fn find_dups(
+        &self,
+        files: Vec<PathBuf>,
+        fileinfos: &HashMap<PathBuf, FileInfo>,
+        size_filter: &SizeFilter,
+    ) -> Vec<Matches> {
+        // Entries that were only hashed for other searches don't have all of the
+        // hashes.
+        let (files, hashes): (Vec<PathBuf>, Vec<Vec<BitHash>>) = files
+            .into_iter()
+            .filter_map(|file| {
+                let fi = fileinfos.get(&file).filter(|fi| {
+                    size_filter.accepts(fi) && self.hashes.iter().all(|hash| fi.has_hash(hash.spec))
+                })?;
+                let file_hashes = self
+                    .hashes
+                    .iter()
+                    .map(|hash| BitHash::from_base64(fi.hash(hash.spec)))
+                    .collect::<Option<Vec<_>>>()?;
+                Some((file, file_hashes))
+            })
+            .unzip();
+
+        let mut candidates = BTreeSet::new();
+        for (i, hash) in self.hashes.iter().enumerate() {
+            let mut index: HashMap<&BitHash, Vec<usize>> = HashMap::new();
+            for (file, file_hashes) in hashes.iter().enumerate() {
+                index
+                    .entry(&file_hashes[i])
+                    .or_insert_with(Vec::new)
+                    .push(file);
+            }
+            let mut bk_tree = BKTree::new(HammingDistance {});
+            for key in index.keys() {
+                bk_tree.add((*key).clone());
+            }
+
+            let distance = self.candidate_distance(hash);
+            for (a, file_hashes) in hashes.iter().enumerate() {
+                for (_distance, close) in bk_tree.find(&file_hashes[i], distance) {
+                    for b in &index[close] {
+                        if a < *b {
+                            candidates.insert((a, *b));
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut matched: Vec<Vec<MatchedFile>> = vec![Vec::new(); files.len()];
+        for (a, b) in candidates {
+            let distances: Vec<u64> = hashes[a]
+                .iter()
+                .zip(&hashes[b])
+                .map(|(x, y)| x.distance(y))
+                .collect();
+            if self.accepts(&distances) {
+                let similarity = percentage(1.0 - self.weighted_distance(&distances));
+                matched[a].push(MatchedFile {
+                    path: files[b].clone(),
+                    distances: distances.clone(),
+                    similarity,
+                    ..MatchedFile::default()
+                });
+                matched[b].push(MatchedFile {
+                    path: files[a].clone(),
+                    distances,
+                    similarity,
+                    ..MatchedFile::default()
+                });
+            }
+        }
+
+        let mut vec = Vec::new();
+        for (i, mut matched_files) in matched.into_iter().enumerate() {
+            if matched_files.is_empty() {
+                continue;
+            }
+            matched_files.push(MatchedFile {
+                path: files[i].clone(),
+                distances: vec![0; self.hashes.len()],
+                similarity: 100.0,
+                ..MatchedFile::default()
+            });
+            vec.push(Matches::new(files[i].clone(), matched_files));
+        }
+        vec
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashMap;
+    use std::path::PathBuf;
+
+    use serialize::base64::{ToBase64, STANDARD};
+
+    use super::super::fileinfo::{FileInfo, HashKind, HashSpec};
+    use super::super::size_filter::SizeFilter;
+    use super::{CombineRule, CombinedHash, CombinedSearch};
+
+    // A 64-bit hash with the first `ones` bits set, so that two of them differ by
+    // the difference in their `ones`.
+    fn hash_with_ones(ones: usize) -> String {
+        let bytes: Vec<u8> = (0..8)
+            .map(|byte| {
+                (0..8).fold(0u8, |b, bit| {
+                    if byte * 8 + bit < ones {
+                        b | 0x80 >> bit
+                    } else {
+                        b
+                    }
+                })
+            })
+            .collect();
+        bytes.to_base64(STANDARD)
+    }
+
+    fn fileinfos() -> HashMap<PathBuf, FileInfo> {
+        let mean = HashSpec::new(HashKind::Mean, 8);
+        let dct = HashSpec::new(HashKind::Dct, 8);
+        // b is close to a by the mean hash, but not by the DCT hash. c is close by
+        // both.
+        [("a", 0, 0), ("b", 2, 20), ("c", 2, 2), ("d", 40, 40)]
+            .iter()
+            .map(|(name, mean_ones, dct_ones)| {
+                let mut fi = FileInfo {
+                    filename: PathBuf::from(name),
+                    ..FileInfo::default()
+                };
+                fi.set_hash(mean, hash_with_ones(*mean_ones));
+                fi.set_hash(dct, hash_with_ones(*dct_ones));
+                (fi.filename.clone(), fi)
+            })
+            .collect()
+    }
+
+    fn search(rule: CombineRule, dct_weight: f64) -> CombinedSearch {
+        CombinedSearch {
+            hashes: vec![
+                CombinedHash {
+                    spec: HashSpec::new(HashKind::Mean, 8),
+                    distance: 4,
+                    weight: 1.0,
+                },
+                CombinedHash {
+                    spec: HashSpec::new(HashKind::Dct, 8),
+                    distance: 4,
+                    weight: dct_weight,
+                },
+            ],
+            rule,
+        }
+    }
+
+    fn matches_of(search: &CombinedSearch, name: &str) -> Vec<PathBuf> {
+        let files = ["a", "b", "c", "d"].iter().map(PathBuf::from).collect();
+        search
+            .find_dups(files, &fileinfos(), &SizeFilter::default())
+            .into_iter()
+            .find(|m| m.filename == PathBuf::from(name))
+            .map(|m| m.matched_files.into_iter().map(|mf| mf.path).collect())
+            .unwrap_or_default()
+    }
+
+    #[test]
+    fn test_votes() {
+        let both = search(CombineRule::Votes(2), 1.0);
+        assert_eq!(vec![PathBuf::from("a"), "c".into()], matches_of(&both, "a"));
+        assert!(matches_of(&both, "b").is_empty());
+        assert!(matches_of(&both, "d").is_empty());
+
+        // The closest comes first.
+        let either = search(CombineRule::Votes(1), 1.0);
+        assert_eq!(
+            vec![PathBuf::from("a"), "c".into(), "b".into()],
+            matches_of(&either, "a")
+        );
+    }
+
+    #[test]
+    fn test_weighted() {
+        // b averages (2/64 + 20/64) / 2, and c 2/64.
+        let even = search(CombineRule::Weighted(0.1), 1.0);
+        assert_eq!(vec![PathBuf::from("a"), "c".into()], matches_of(&even, "a"));
+
+        // With the DCT hash counting for less, b is close enough.
+        let light_dct = search(CombineRule::Weighted(0.1), 0.1);
+        assert_eq!(
+            vec![PathBuf::from("a"), "c".into(), "b".into()],
+            matches_of(&light_dct, "a")
+        );
+        assert!(matches_of(&light_dct, "d").is_empty());
+    }
+
+    #[test]
+    fn test_distances_are_per_hash() {
+        let either = search(CombineRule::Votes(1), 1.0);
+        let files = ["a", "b", "c", "d"].iter().map(PathBuf::from).collect();
+        let matches = either.find_dups(files, &fileinfos(), &SizeFilter::default());
+        let a = matches
+            .iter()
+            .find(|m| m.filename == PathBuf::from("a"))
+            .unwrap();
+        let b = &a.matched_files[2];
+        assert_eq!(PathBuf::from("b"), b.path);
+        assert_eq!(None, b.distance);
+        assert_eq!(vec![2, 20], b.distances);
+        assert_eq!(vec![0, 0], a.matched_files[0].distances);
+    }
+
+    #[test]
+    fn test_missing_hashes() {
+        let mut fileinfos = fileinfos();
+        fileinfos.get_mut(&PathBuf::from("c")).unwrap().p_hash = String::new();
+        let files = vec![PathBuf::from("a"), "c".into()];
+        let both = search(CombineRule::Votes(2), 1.0);
+        assert!(both
+            .find_dups(files, &fileinfos, &SizeFilter::default())
+            .is_empty());
+    }
+}
@@ -1,22 +1,54 @@
+use std::collections::HashMap;
 use std::env;
 use std::ffi::{OsStr, OsString};
 use std::path::PathBuf;
 
-use clap::{self, App, Arg};
+use clap::{self, App, AppSettings, Arg, SubCommand};
 
+use super::cache_cmd::{CacheCommand, ExportFormat};
+use super::cache_format::CacheFormat;
+use super::combined::{CombineRule, CombinedHash, CombinedSearch};
+use super::decoder::Decoder;
+use super::fileinfo::{HashKind, HashSpec, DEFAULT_HASH_SIZE};
 use super::output::{
-    new_no_output, new_open_output, new_text_output, new_yaml_output, DynamicOutput,
+    new_json_output, new_no_output, new_open_output, new_text_output, new_yaml_output,
+    DynamicOutput,
 };
+use super::result::ItoolsError;
 use super::search::SearchType;
+use super::size_filter::{parse_size, SizeFilter};
+use super::sniff::ImageType;
+use super::store::StoreKind;
 use super::Result;
 
 #[derive(Default, Debug)]
 pub struct Config {
+    pub by_extension: bool,
     pub cache_file: PathBuf,
+    pub cache_format: Option<CacheFormat>,
+    pub cache_journal: bool,
     pub cache_only: bool,
+    pub cache_store: Option<StoreKind>,
+    pub command: Option<CacheCommand>,
+    pub decoder: Decoder,
+    pub exclude: Vec<String>,
+    pub failures_file: Option<PathBuf>,
     pub files: Vec<OsString>,
+    pub files_from: Option<PathBuf>,
+    pub follow_symlinks: bool,
+    pub include: Vec<String>,
+    pub jobs: usize,
+    pub library_root: Option<PathBuf>,
+    pub max_depth: Option<usize>,
+    pub memory_budget: u64,
+    pub null_separated: bool,
+    pub one_file_system: bool,
     pub output: DynamicOutput,
+    pub retry_failed: bool,
     pub show_progress: bool,
+    pub size_filter: SizeFilter,
+    pub skip_hidden: bool,
+    pub strict: bool,
     pub search: SearchType,
 }
 
@@ -33,12 +65,33 @@ impl Config {
         let matches = build_clap_spec().get_matches_from_safe(itr)?;
 
         Ok(Config {
+            by_extension: by_extension(&matches),
             cache_file: cache_file(&matches),
+            cache_format: cache_format(&matches),
+            cache_journal: cache_journal(&matches),
             cache_only: cache_only(&matches),
+            cache_store: cache_store(&matches),
+            command: cache_command(&matches)?,
+            decoder: decoder(&matches),
+            exclude: exclude_values(&matches),
+            failures_file: failures_file(&matches),
             files: files_values(&matches),
+            files_from: files_from(&matches),
+            follow_symlinks: follow_symlinks(&matches),
+            include: include_values(&matches),
+            jobs: jobs_value(&matches)?,
+            library_root: library_root(&matches),
+            max_depth: max_depth_value(&matches)?,
+            memory_budget: memory_budget_value(&matches)?,
+            null_separated: null_separated(&matches),
+            one_file_system: one_file_system(&matches),
             output: choose_output(&matches),
+            retry_failed: retry_failed(&matches),
             show_progress: show_progress_value(&matches),
-            search: choose_search(&matches),
+            size_filter: size_filter(&matches)?,
+            skip_hidden: skip_hidden(&matches),
+            strict: strict(&matches),
+            search: choose_search(&matches)?,
         })
     }
 }
@@ -49,24 +102,79 @@ const ABOUT: &str = "Finds dup and near-dup image files.";
 const AUTHOR: &str = "George Madrid <gmadrid@gmail.com>";
 const VERSION: &str = "0.1.0";
 
+const ANY_ORIENTATION_ARG_NAME: &str = "any_orientation";
+const BY_EXTENSION_ARG_NAME: &str = "by_extension";
+const CACHE_COMMAND_NAME: &str = "cache";
+const CACHE_EXPORT_COMMAND_NAME: &str = "export";
+const CACHE_EXPORT_FORMAT_ARG_NAME: &str = "format";
+const CACHE_EXPORT_FORMAT_CSV_VALUE_NAME: &str = "csv";
+const CACHE_EXPORT_FORMAT_JSON_VALUE_NAME: &str = "json";
+const CACHE_FAILURES_COMMAND_NAME: &str = "failures";
+const CACHE_PRUNE_COMMAND_NAME: &str = "prune";
+const CACHE_PRUNE_ROOTS_ARG_NAME: &str = "roots";
+const CACHE_RELOCATE_COMMAND_NAME: &str = "relocate";
+const CACHE_RELOCATE_NEW_PREFIX_ARG_NAME: &str = "NEW_PREFIX";
+const CACHE_RELOCATE_OLD_PREFIX_ARG_NAME: &str = "OLD_PREFIX";
+const CACHE_STATS_COMMAND_NAME: &str = "stats";
+const CACHE_VERIFY_COMMAND_NAME: &str = "verify";
+const CACHE_VERIFY_SAMPLE_ARG_NAME: &str = "sample";
+const CACHE_VERIFY_SAMPLE_DEFAULT_VALUE: &str = "20";
 const CACHE_FILE_ARG_NAME: &str = "cache_file";
 const CACHE_FILE_ENV_NAME: &str = "NDUPS_CACHE_FILE";
 const CACHE_FILE_DEFAULT_VALUE: &str = "ndups_cache";
+const CACHE_FORMAT_ARG_NAME: &str = "cache_format";
+const CACHE_FORMAT_BINARY_VALUE_NAME: &str = "binary";
+const CACHE_FORMAT_YAML_VALUE_NAME: &str = "yaml";
+const CACHE_JOURNAL_ARG_NAME: &str = "cache_journal";
 const CACHE_ONLY_ARG_NAME: &str = "cache_only";
+const CACHE_STORE_ARG_NAME: &str = "cache_store";
+const CACHE_STORE_FILE_VALUE_NAME: &str = "file";
+const CACHE_STORE_SQLITE_VALUE_NAME: &str = "sqlite";
+const COMBINE_ARG_NAME: &str = "combine";
+const EXCLUDE_ARG_NAME: &str = "exclude";
+const FAILURES_FILE_ARG_NAME: &str = "failures_file";
 const FILES_ARG_NAME: &str = "files";
+const FILES_FROM_ARG_NAME: &str = "files_from";
+const FOLLOW_SYMLINKS_ARG_NAME: &str = "follow_symlinks";
 const FORMAT_ARG_NAME: &str = "format";
+const FORMAT_JSON_VALUE_NAME: &str = "json";
 const FORMAT_NONE_VALUE_NAME: &str = "none";
 const FORMAT_OPEN_VALUE_NAME: &str = "open";
 const FORMAT_TEXT_VALUE_NAME: &str = "text";
 const FORMAT_YAML_VALUE_NAME: &str = "yaml";
 const HASH_DISTANCE_ARG_NAME: &str = "distance";
+const HASH_SIZE_ARG_NAME: &str = "hash_size";
 const HASH_TYPE_ARG_NAME: &str = "use_hash";
+const HASH_TYPE_BLOCK_VALUE_NAME: &str = "block";
+const HASH_TYPE_COLOR_VALUE_NAME: &str = "color";
 const HASH_TYPE_DCT_VALUE_NAME: &str = "dct";
+const HASH_TYPE_FEATURES_VALUE_NAME: &str = "features";
 const HASH_TYPE_GRAD_VALUE_NAME: &str = "grad";
 const HASH_TYPE_MEAN_VALUE_NAME: &str = "mean";
 const HASH_TYPE_SHA2_VALUE_NAME: &str = "sha2";
+const HASH_TYPE_WAVELET_VALUE_NAME: &str = "wavelet";
+const HASH_WEIGHT_ARG_NAME: &str = "hash_weight";
+const HEIC_CONVERTER_ARG_NAME: &str = "heic_converter";
+const HEIC_CONVERTER_ENV_NAME: &str = "NDUPS_HEIC_CONVERTER";
+const INCLUDE_ARG_NAME: &str = "include";
+const JOBS_ARG_NAME: &str = "jobs";
+const LIBRARY_ROOT_ARG_NAME: &str = "library_root";
+const LIBRARY_ROOT_ENV_NAME: &str = "NDUPS_LIBRARY_ROOT";
+const MAX_DEPTH_ARG_NAME: &str = "max_depth";
+const MAX_SIZE_ARG_NAME: &str = "max_size";
+const MAX_WEIGHTED_DISTANCE_ARG_NAME: &str = "max_weighted_distance";
+const MEMORY_BUDGET_ARG_NAME: &str = "memory_budget";
+const MIN_PIXELS_ARG_NAME: &str = "min_pixels";
+const MIN_SIZE_ARG_NAME: &str = "min_size";
+const MIN_VOTES_ARG_NAME: &str = "min_votes";
 const NO_PROGRESS_ARG_NAME: &str = "no_progress";
+const NULL_SEPARATED_ARG_NAME: &str = "null";
+const ONE_FILE_SYSTEM_ARG_NAME: &str = "one_file_system";
 const QUIET_ARG_NAME: &str = "quiet";
+const RETRY_FAILED_ARG_NAME: &str = "retry_failed";
+const SKIP_FORMAT_ARG_NAME: &str = "skip_format";
+const SKIP_HIDDEN_ARG_NAME: &str = "skip_hidden";
+const STRICT_ARG_NAME: &str = "strict";
 
 fn build_clap_spec<'a, 'b>() -> clap::App<'a, 'b> {
     let no_progress_arg = Arg::with_name(NO_PROGRESS_ARG_NAME).long(NO_PROGRESS_ARG_NAME);
@@ -81,15 +189,87 @@ fn build_clap_spec<'a, 'b>() -> clap::App<'a, 'b> {
         .env(CACHE_FILE_ENV_NAME)
         .takes_value(true)
         .default_value(CACHE_FILE_DEFAULT_VALUE);
+    let cache_format_arg = Arg::with_name(CACHE_FORMAT_ARG_NAME)
+        .long(CACHE_FORMAT_ARG_NAME)
+        .takes_value(true)
+        .possible_values(&[CACHE_FORMAT_BINARY_VALUE_NAME, CACHE_FORMAT_YAML_VALUE_NAME]);
+    let cache_journal_arg = Arg::with_name(CACHE_JOURNAL_ARG_NAME).long(CACHE_JOURNAL_ARG_NAME);
+    let cache_store_arg = Arg::with_name(CACHE_STORE_ARG_NAME)
+        .long(CACHE_STORE_ARG_NAME)
+        .takes_value(true)
+        .possible_values(&[CACHE_STORE_FILE_VALUE_NAME, CACHE_STORE_SQLITE_VALUE_NAME]);
+    let failures_file_arg = Arg::with_name(FAILURES_FILE_ARG_NAME)
+        .long(FAILURES_FILE_ARG_NAME)
+        .takes_value(true);
+    let retry_failed_arg = Arg::with_name(RETRY_FAILED_ARG_NAME).long(RETRY_FAILED_ARG_NAME);
+    let by_extension_arg = Arg::with_name(BY_EXTENSION_ARG_NAME).long(BY_EXTENSION_ARG_NAME);
+    let heic_converter_arg = Arg::with_name(HEIC_CONVERTER_ARG_NAME)
+        .long(HEIC_CONVERTER_ARG_NAME)
+        .env(HEIC_CONVERTER_ENV_NAME)
+        .takes_value(true);
+    let skip_format_names: Vec<&str> = ImageType::ALL.iter().map(|t| t.name()).collect();
+    let skip_format_arg = Arg::with_name(SKIP_FORMAT_ARG_NAME)
+        .long(SKIP_FORMAT_ARG_NAME)
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+        .possible_values(&skip_format_names);
+    let exclude_arg = Arg::with_name(EXCLUDE_ARG_NAME)
+        .long(EXCLUDE_ARG_NAME)
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1);
+    let include_arg = Arg::with_name(INCLUDE_ARG_NAME)
+        .long(INCLUDE_ARG_NAME)
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1);
+    let follow_symlinks_arg =
+        Arg::with_name(FOLLOW_SYMLINKS_ARG_NAME).long(FOLLOW_SYMLINKS_ARG_NAME);
+    let max_depth_arg = Arg::with_name(MAX_DEPTH_ARG_NAME)
+        .long(MAX_DEPTH_ARG_NAME)
+        .takes_value(true);
+    let one_file_system_arg =
+        Arg::with_name(ONE_FILE_SYSTEM_ARG_NAME).long(ONE_FILE_SYSTEM_ARG_NAME);
+    let skip_hidden_arg = Arg::with_name(SKIP_HIDDEN_ARG_NAME).long(SKIP_HIDDEN_ARG_NAME);
+    let strict_arg = Arg::with_name(STRICT_ARG_NAME).long(STRICT_ARG_NAME);
+    let memory_budget_arg = Arg::with_name(MEMORY_BUDGET_ARG_NAME)
+        .long(MEMORY_BUDGET_ARG_NAME)
+        .takes_value(true);
+    let max_size_arg = Arg::with_name(MAX_SIZE_ARG_NAME)
+        .long(MAX_SIZE_ARG_NAME)
+        .takes_value(true);
+    let min_pixels_arg = Arg::with_name(MIN_PIXELS_ARG_NAME)
+        .long(MIN_PIXELS_ARG_NAME)
+        .takes_value(true);
+    let min_size_arg = Arg::with_name(MIN_SIZE_ARG_NAME)
+        .long(MIN_SIZE_ARG_NAME)
+        .takes_value(true);
     let files_arg = Arg::with_name(FILES_ARG_NAME)
         .multiple(true)
         .takes_value(true)
-        .required(true);
+        .required_unless(FILES_FROM_ARG_NAME);
+    let files_from_arg = Arg::with_name(FILES_FROM_ARG_NAME)
+        .long(FILES_FROM_ARG_NAME)
+        .takes_value(true);
+    let null_separated_arg = Arg::with_name(NULL_SEPARATED_ARG_NAME)
+        .long(NULL_SEPARATED_ARG_NAME)
+        .short("0")
+        .requires(FILES_FROM_ARG_NAME);
+    let jobs_arg = Arg::with_name(JOBS_ARG_NAME)
+        .long(JOBS_ARG_NAME)
+        .short("j")
+        .takes_value(true);
+    let library_root_arg = Arg::with_name(LIBRARY_ROOT_ARG_NAME)
+        .long(LIBRARY_ROOT_ARG_NAME)
+        .env(LIBRARY_ROOT_ENV_NAME)
+        .takes_value(true);
     let format_arg = Arg::with_name(FORMAT_ARG_NAME)
         .long(FORMAT_ARG_NAME)
         .short("f")
         .takes_value(true)
         .possible_values(&[
+            FORMAT_JSON_VALUE_NAME,
             FORMAT_NONE_VALUE_NAME,
             FORMAT_OPEN_VALUE_NAME,
             FORMAT_TEXT_VALUE_NAME,
@@ -105,24 +285,148 @@ fn build_clap_spec<'a, 'b>() -> clap::App<'a, 'b> {
         .short("t")
         .takes_value(true)
         .possible_values(&[
+            HASH_TYPE_BLOCK_VALUE_NAME,
+            HASH_TYPE_COLOR_VALUE_NAME,
             HASH_TYPE_DCT_VALUE_NAME,
+            HASH_TYPE_FEATURES_VALUE_NAME,
             HASH_TYPE_MEAN_VALUE_NAME,
             HASH_TYPE_GRAD_VALUE_NAME,
             HASH_TYPE_SHA2_VALUE_NAME,
+            HASH_TYPE_WAVELET_VALUE_NAME,
         ]).default_value(HASH_TYPE_DCT_VALUE_NAME);
+    let any_orientation_arg =
+        Arg::with_name(ANY_ORIENTATION_ARG_NAME).long(ANY_ORIENTATION_ARG_NAME);
+    let hash_size_arg = Arg::with_name(HASH_SIZE_ARG_NAME)
+        .long(HASH_SIZE_ARG_NAME)
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1);
+    let combine_arg = Arg::with_name(COMBINE_ARG_NAME)
+        .long(COMBINE_ARG_NAME)
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1);
+    let min_votes_arg = Arg::with_name(MIN_VOTES_ARG_NAME)
+        .long(MIN_VOTES_ARG_NAME)
+        .takes_value(true)
+        .requires(COMBINE_ARG_NAME);
+    let max_weighted_distance_arg = Arg::with_name(MAX_WEIGHTED_DISTANCE_ARG_NAME)
+        .long(MAX_WEIGHTED_DISTANCE_ARG_NAME)
+        .takes_value(true)
+        .requires(COMBINE_ARG_NAME)
+        .conflicts_with(MIN_VOTES_ARG_NAME);
+    let hash_weight_arg = Arg::with_name(HASH_WEIGHT_ARG_NAME)
+        .long(HASH_WEIGHT_ARG_NAME)
+        .takes_value(true)
+        .multiple(true)
+        .number_of_values(1)
+        .requires(MAX_WEIGHTED_DISTANCE_ARG_NAME);
 
     App::new(APP_NAME)
         .about(ABOUT)
         .author(AUTHOR)
         .version(VERSION)
+        // The cache commands don't take any files.
+        .setting(AppSettings::SubcommandsNegateReqs)
+        .arg(by_extension_arg)
         .arg(cache_file_arg)
+        .arg(cache_format_arg)
+        .arg(cache_journal_arg)
         .arg(cache_only_arg)
+        .arg(cache_store_arg)
+        .arg(exclude_arg)
+        .arg(failures_file_arg)
+        .arg(follow_symlinks_arg)
         .arg(format_arg)
+        .arg(heic_converter_arg)
+        .arg(include_arg)
+        .arg(jobs_arg)
+        .arg(library_root_arg)
+        .arg(max_depth_arg)
+        .arg(max_size_arg)
+        .arg(memory_budget_arg)
+        .arg(min_pixels_arg)
+        .arg(min_size_arg)
         .arg(no_progress_arg)
+        .arg(one_file_system_arg)
         .arg(quiet_arg)
+        .arg(retry_failed_arg)
+        .arg(skip_format_arg)
+        .arg(skip_hidden_arg)
+        .arg(strict_arg)
         .arg(distance_arg)
         .arg(hash_type_arg)
+        .arg(hash_size_arg)
+        .arg(any_orientation_arg)
+        .arg(combine_arg)
+        .arg(min_votes_arg)
+        .arg(max_weighted_distance_arg)
+        .arg(hash_weight_arg)
         .arg(files_arg)
+        .arg(files_from_arg)
+        .arg(null_separated_arg)
+        .subcommand(build_cache_command_spec())
+}
+
+fn build_cache_command_spec<'a, 'b>() -> clap::App<'a, 'b> {
+    let prune_command = SubCommand::with_name(CACHE_PRUNE_COMMAND_NAME)
+        .about("Removes entries for missing files, or for files outside of the roots.")
+        .arg(
+            Arg::with_name(CACHE_PRUNE_ROOTS_ARG_NAME)
+                .multiple(true)
+                .takes_value(true),
+        );
+    let relocate_command = SubCommand::with_name(CACHE_RELOCATE_COMMAND_NAME)
+        .about("Moves every entry under OLD_PREFIX to be under NEW_PREFIX.")
+        .arg(
+            Arg::with_name(CACHE_RELOCATE_OLD_PREFIX_ARG_NAME)
+                .required(true)
+                .index(1),
+        )
+        .arg(
+            Arg::with_name(CACHE_RELOCATE_NEW_PREFIX_ARG_NAME)
+                .required(true)
+                .index(2),
+        );
+    let stats_command = SubCommand::with_name(CACHE_STATS_COMMAND_NAME)
+        .about("Prints the number of entries, their size, and hash coverage.");
+    let verify_command = SubCommand::with_name(CACHE_VERIFY_COMMAND_NAME)
+        .about("Re-hashes a random sample of entries and reports mismatches.")
+        .arg(
+            Arg::with_name(CACHE_VERIFY_SAMPLE_ARG_NAME)
+                .long(CACHE_VERIFY_SAMPLE_ARG_NAME)
+                .short("n")
+                .takes_value(true)
+                .default_value(CACHE_VERIFY_SAMPLE_DEFAULT_VALUE),
+        );
+    let export_command = SubCommand::with_name(CACHE_EXPORT_COMMAND_NAME)
+        .about("Writes all of the entries to stdout.")
+        .arg(
+            Arg::with_name(CACHE_EXPORT_FORMAT_ARG_NAME)
+                .long(CACHE_EXPORT_FORMAT_ARG_NAME)
+                .short("f")
+                .takes_value(true)
+                .possible_values(&[
+                    CACHE_EXPORT_FORMAT_CSV_VALUE_NAME,
+                    CACHE_EXPORT_FORMAT_JSON_VALUE_NAME,
+                ]).default_value(CACHE_EXPORT_FORMAT_CSV_VALUE_NAME),
+        );
+    let failures_command = SubCommand::with_name(CACHE_FAILURES_COMMAND_NAME)
+        .about("Lists the files that couldn't be hashed, and why.");
+
+    SubCommand::with_name(CACHE_COMMAND_NAME)
+        .about("Inspects and maintains the cache file.")
+        .setting(AppSettings::SubcommandRequiredElseHelp)
+        .subcommand(prune_command)
+        .subcommand(relocate_command)
+        .subcommand(stats_command)
+        .subcommand(verify_command)
+        .subcommand(export_command)
+        .subcommand(failures_command)
+}
+
+fn by_extension<'a>(matches: &clap::ArgMatches<'a>) -> bool {
+    matches.is_present(BY_EXTENSION_ARG_NAME)
 }
 
 fn cache_file<'a>(matches: &clap::ArgMatches<'a>) -> PathBuf {
@@ -132,22 +436,247 @@ fn cache_file<'a>(matches: &clap::ArgMatches<'a>) -> PathBuf {
         .into()
 }
 
+// No default here: if unspecified, the cache is kept in whatever format it was
+// loaded in.
+fn cache_format<'a>(matches: &clap::ArgMatches<'a>) -> Option<CacheFormat> {
+    matches
+        .value_of(CACHE_FORMAT_ARG_NAME)
+        .map(|value| match value {
+            CACHE_FORMAT_BINARY_VALUE_NAME => CacheFormat::Binary,
+            CACHE_FORMAT_YAML_VALUE_NAME => CacheFormat::Yaml,
+            _ => {
+                // This should never happen.
+                panic!("Weird unknown cache format value")
+            }
+        })
+}
+
+fn cache_journal<'a>(matches: &clap::ArgMatches<'a>) -> bool {
+    matches.is_present(CACHE_JOURNAL_ARG_NAME)
+}
+
 fn cache_only<'a>(matches: &clap::ArgMatches<'a>) -> bool {
     matches.is_present(CACHE_ONLY_ARG_NAME)
 }
 
+// The cache is keyed by canonical paths, so the roots have to be canonical too.
+// A root that doesn't match the keys would have every entry pruned, so one that
+// can't be canonicalized is an error.
+fn prune_root(root: &OsStr) -> Result<PathBuf> {
+    PathBuf::from(root)
+        .canonicalize()
+        .map_err(|_| ItoolsError::UsageError("prune roots must be existing directories"))
+}
+
+// The prefixes have to look like the cache's canonical keys too. The old files
+// are usually gone by the time they are relocated, so only the part of the path
+// that still exists is canonicalized.
+fn relocate_prefix(prefix: &OsStr) -> Result<PathBuf> {
+    let prefix = env::current_dir()?.join(prefix);
+    for existing in prefix.ancestors() {
+        if let Ok(canonical) = existing.canonicalize() {
+            // unwrap() is safe, since it's one of the prefix's ancestors.
+            return Ok(canonical.join(prefix.strip_prefix(existing).unwrap()));
+        }
+    }
+    Ok(prefix)
+}
+
+// No default here: if unspecified, the store is chosen by the cache file's
+// extension.
+fn cache_store<'a>(matches: &clap::ArgMatches<'a>) -> Option<StoreKind> {
+    matches
+        .value_of(CACHE_STORE_ARG_NAME)
+        .map(|value| match value {
+            CACHE_STORE_FILE_VALUE_NAME => StoreKind::File,
+            CACHE_STORE_SQLITE_VALUE_NAME => StoreKind::Sqlite,
+            _ => {
+                // This should never happen.
+                panic!("Weird unknown cache store value")
+            }
+        })
+}
+
+fn cache_command<'a>(matches: &clap::ArgMatches<'a>) -> Result<Option<CacheCommand>> {
+    let cache_matches = match matches.subcommand_matches(CACHE_COMMAND_NAME) {
+        Some(cache_matches) => cache_matches,
+        None => return Ok(None),
+    };
+    // clap requires one of the subcommands, so this should always match something.
+    let command = match cache_matches.subcommand() {
+        (CACHE_PRUNE_COMMAND_NAME, Some(m)) => Some(CacheCommand::Prune(
+            m.values_of_os(CACHE_PRUNE_ROOTS_ARG_NAME)
+                .map(|roots| roots.map(prune_root).collect::<Result<_>>())
+                .unwrap_or_else(|| Ok(Vec::new()))?,
+        )),
+        (CACHE_RELOCATE_COMMAND_NAME, Some(m)) => {
+            // Both are required by clap.
+            let old_prefix = m.value_of_os(CACHE_RELOCATE_OLD_PREFIX_ARG_NAME).unwrap();
+            let new_prefix = m.value_of_os(CACHE_RELOCATE_NEW_PREFIX_ARG_NAME).unwrap();
+            Some(CacheCommand::Relocate(
+                relocate_prefix(old_prefix)?,
+                relocate_prefix(new_prefix)?,
+            ))
+        }
+        (CACHE_STATS_COMMAND_NAME, _) => Some(CacheCommand::Stats),
+        (CACHE_FAILURES_COMMAND_NAME, _) => Some(CacheCommand::Failures),
+        (CACHE_VERIFY_COMMAND_NAME, Some(m)) => {
+            // Safe, since clap has a default value.
+            let sample = m
+                .value_of(CACHE_VERIFY_SAMPLE_ARG_NAME)
+                .unwrap()
+                .parse::<usize>()
+                .map_err(|_| ItoolsError::UsageError("--sample must be a number"))?;
+            Some(CacheCommand::Verify(sample))
+        }
+        (CACHE_EXPORT_COMMAND_NAME, Some(m)) => {
+            let format = match m.value_of(CACHE_EXPORT_FORMAT_ARG_NAME).unwrap() {
+                CACHE_EXPORT_FORMAT_JSON_VALUE_NAME => ExportFormat::Json,
+                _ => ExportFormat::Csv,
+            };
+            Some(CacheCommand::Export(format))
+        }
+        _ => {
+            // This should never happen.
+            panic!("Weird unknown cache command")
+        }
+    };
+    Ok(command)
+}
+
+fn exclude_values<'a>(matches: &clap::ArgMatches<'a>) -> Vec<String> {
+    string_values(matches, EXCLUDE_ARG_NAME)
+}
+
+fn failures_file<'a>(matches: &clap::ArgMatches<'a>) -> Option<PathBuf> {
+    matches
+        .value_of_os(FAILURES_FILE_ARG_NAME)
+        .map(PathBuf::from)
+}
+
+fn decoder<'a>(matches: &clap::ArgMatches<'a>) -> Decoder {
+    let mut decoder = Decoder::default();
+    if let Some(command) = matches.value_of(HEIC_CONVERTER_ARG_NAME) {
+        decoder.set_heic_converter(command);
+    }
+    if let Some(names) = matches.values_of(SKIP_FORMAT_ARG_NAME) {
+        for name in names {
+            // clap only allows the possible values.
+            decoder.skip(ImageType::from_name(name).unwrap());
+        }
+    }
+    decoder
+}
+
 fn files_values<'a>(matches: &clap::ArgMatches<'a>) -> Vec<OsString> {
+    // clap ensures at least one, unless there is a subcommand.
     matches
         .values_of_os(FILES_ARG_NAME)
-        .unwrap() // Should be safe, since clap ensures at least one.
-        .map(OsStr::to_os_string)
-        .collect()
+        .map(|values| values.map(OsStr::to_os_string).collect())
+        .unwrap_or_default()
+}
+
+// "-" means stdin.
+fn files_from<'a>(matches: &clap::ArgMatches<'a>) -> Option<PathBuf> {
+    matches.value_of_os(FILES_FROM_ARG_NAME).map(PathBuf::from)
+}
+
+fn follow_symlinks<'a>(matches: &clap::ArgMatches<'a>) -> bool {
+    matches.is_present(FOLLOW_SYMLINKS_ARG_NAME)
+}
+
+fn include_values<'a>(matches: &clap::ArgMatches<'a>) -> Vec<String> {
+    string_values(matches, INCLUDE_ARG_NAME)
+}
+
+fn string_values<'a>(matches: &clap::ArgMatches<'a>, name: &str) -> Vec<String> {
+    matches
+        .values_of(name)
+        .map(|values| values.map(String::from).collect())
+        .unwrap_or_default()
+}
+
+// Defaults to one worker per core.
+fn jobs_value<'a>(matches: &clap::ArgMatches<'a>) -> Result<usize> {
+    match matches.value_of(JOBS_ARG_NAME) {
+        Some(value) => match value.parse::<usize>() {
+            Ok(jobs) if jobs > 0 => Ok(jobs),
+            _ => Err(ItoolsError::UsageError("--jobs must be a positive number")),
+        },
+        None => Ok(num_cpus::get()),
+    }
+}
+
+fn library_root<'a>(matches: &clap::ArgMatches<'a>) -> Option<PathBuf> {
+    matches
+        .value_of_os(LIBRARY_ROOT_ARG_NAME)
+        .map(PathBuf::from)
+}
+
+fn max_depth_value<'a>(matches: &clap::ArgMatches<'a>) -> Result<Option<usize>> {
+    match matches.value_of(MAX_DEPTH_ARG_NAME) {
+        Some(value) => value
+            .parse::<usize>()
+            .map(Some)
+            .map_err(|_| ItoolsError::UsageError("--max_depth must be a number")),
+        None => Ok(None),
+    }
+}
+
+// The most file content that the hasher keeps in memory at once, by default.
+const DEFAULT_MEMORY_BUDGET: u64 = 512 * 1024 * 1024;
+
+fn memory_budget_value<'a>(matches: &clap::ArgMatches<'a>) -> Result<u64> {
+    match size_value(matches, MEMORY_BUDGET_ARG_NAME)? {
+        Some(0) => Err(ItoolsError::UsageError(
+            "--memory_budget must be a positive size",
+        )),
+        Some(budget) => Ok(budget),
+        None => Ok(DEFAULT_MEMORY_BUDGET),
+    }
+}
+
+fn size_filter<'a>(matches: &clap::ArgMatches<'a>) -> Result<SizeFilter> {
+    Ok(SizeFilter {
+        min_size: size_value(matches, MIN_SIZE_ARG_NAME)?,
+        max_size: size_value(matches, MAX_SIZE_ARG_NAME)?,
+        min_pixels: size_value(matches, MIN_PIXELS_ARG_NAME)?,
+    })
+}
+
+fn size_value<'a>(matches: &clap::ArgMatches<'a>, name: &str) -> Result<Option<u64>> {
+    match matches.value_of(name) {
+        Some(value) => parse_size(value).map(Some).ok_or(ItoolsError::UsageError(
+            "sizes must be a number, optionally followed by K, M, or G",
+        )),
+        None => Ok(None),
+    }
+}
+
+fn null_separated<'a>(matches: &clap::ArgMatches<'a>) -> bool {
+    matches.is_present(NULL_SEPARATED_ARG_NAME)
+}
+
+fn one_file_system<'a>(matches: &clap::ArgMatches<'a>) -> bool {
+    matches.is_present(ONE_FILE_SYSTEM_ARG_NAME)
 }
 
 fn quiet_value<'a>(matches: &clap::ArgMatches<'a>) -> bool {
     matches.is_present(QUIET_ARG_NAME)
 }
 
+fn retry_failed<'a>(matches: &clap::ArgMatches<'a>) -> bool {
+    matches.is_present(RETRY_FAILED_ARG_NAME)
+}
+
+fn skip_hidden<'a>(matches: &clap::ArgMatches<'a>) -> bool {
+    matches.is_present(SKIP_HIDDEN_ARG_NAME)
+}
+
+fn strict<'a>(matches: &clap::ArgMatches<'a>) -> bool {
+    matches.is_present(STRICT_ARG_NAME)
+}
+
 fn show_progress_value<'a>(matches: &clap::ArgMatches<'a>) -> bool {
     !matches.is_present(NO_PROGRESS_ARG_NAME) && !quiet_value(matches)
 }
@@ -158,6 +687,7 @@ fn choose_output<'a>(matches: &clap::ArgMatches<'a>) -> DynamicOutput {
     } else {
         // spec defines a default value, so it will always be there.
         match matches.value_of(FORMAT_ARG_NAME).unwrap() {
+            FORMAT_JSON_VALUE_NAME => new_json_output(),
             FORMAT_NONE_VALUE_NAME => new_no_output(),
             FORMAT_OPEN_VALUE_NAME => new_open_output(),
             FORMAT_TEXT_VALUE_NAME => new_text_output(),
@@ -170,38 +700,235 @@ fn choose_output<'a>(matches: &clap::ArgMatches<'a>) -> DynamicOutput {
     }
 }
 
-fn choose_search<'a>(matches: &clap::ArgMatches<'a>) -> SearchType {
+fn choose_search<'a>(matches: &clap::ArgMatches<'a>) -> Result<SearchType> {
     // Both of these unwraps should be safe since clap has a default value.
     let distance = matches
         .value_of(HASH_DISTANCE_ARG_NAME)
         .unwrap()
         .parse::<u8>()
         .unwrap();
+    if let Some(combined) = combined_search(matches, distance)? {
+        return Ok(SearchType::COMBINED(combined));
+    }
     let type_value = matches.value_of(HASH_TYPE_ARG_NAME).unwrap();
 
-    match type_value {
-        HASH_TYPE_MEAN_VALUE_NAME => SearchType::MEAN(distance),
-        HASH_TYPE_GRAD_VALUE_NAME => SearchType::GRAD(distance),
-        HASH_TYPE_DCT_VALUE_NAME => SearchType::DCT(distance),
+    let kind = match type_value {
+        HASH_TYPE_BLOCK_VALUE_NAME => HashKind::Block,
+        HASH_TYPE_COLOR_VALUE_NAME => HashKind::Color,
+        HASH_TYPE_MEAN_VALUE_NAME => HashKind::Mean,
+        HASH_TYPE_GRAD_VALUE_NAME => HashKind::Grad,
+        HASH_TYPE_DCT_VALUE_NAME => HashKind::Dct,
+        HASH_TYPE_WAVELET_VALUE_NAME => HashKind::Wavelet,
         HASH_TYPE_SHA2_VALUE_NAME => {
             if distance != 0 {
                 panic!("SHA2 can only have distance == 0.");
             }
-            SearchType::SHA2
+            if matches.is_present(ANY_ORIENTATION_ARG_NAME) {
+                return Err(ItoolsError::UsageError(
+                    "--any_orientation needs a perceptual hash",
+                ));
+            }
+            return Ok(SearchType::SHA2);
+        }
+        // Features are compared by how many match, so there's no distance.
+        HASH_TYPE_FEATURES_VALUE_NAME => {
+            if matches.is_present(ANY_ORIENTATION_ARG_NAME) {
+                return Err(ItoolsError::UsageError(
+                    "--any_orientation needs a perceptual hash",
+                ));
+            }
+            return Ok(SearchType::FEATURES);
         }
         _ => {
             // This should never happen.
             panic!("Weird unknown format value");
         }
+    };
+    let spec = HashSpec::new(kind, hash_sizes(matches)?.size_for(kind));
+    if matches.is_present(ANY_ORIENTATION_ARG_NAME) {
+        Ok(SearchType::DIHEDRAL(spec, distance))
+    } else {
+        Ok(SearchType::PERCEPTUAL(spec, distance))
     }
 }
 
+const MIN_HASH_SIZE: u32 = 2;
+const MAX_HASH_SIZE: u32 = 32;
+
+// The sizes given with --hash_size. "16" is the size for every algorithm, and
+// "dct=16" is the size for just that one.
+#[derive(Debug, Default)]
+struct HashSizes {
+    all: Option<u32>,
+    by_kind: HashMap<HashKind, u32>,
+}
+
+impl HashSizes {
+    fn size_for(&self, kind: HashKind) -> u32 {
+        self.by_kind
+            .get(&kind)
+            .cloned()
+            .or(self.all)
+            .unwrap_or(DEFAULT_HASH_SIZE)
+    }
+}
+
+fn hash_sizes<'a>(matches: &clap::ArgMatches<'a>) -> Result<HashSizes> {
+    let mut sizes = HashSizes::default();
+    for value in matches.values_of(HASH_SIZE_ARG_NAME).into_iter().flatten() {
+        let (kind, size) = match value.find('=') {
+            Some(pos) => (HashKind::from_name(&value[..pos]), &value[pos + 1..]),
+            None => (None, value),
+        };
+        let size = match size.parse::<u32>() {
+            Ok(size) if size >= MIN_HASH_SIZE && size <= MAX_HASH_SIZE => size,
+            _ => return Err(bad_hash_size()),
+        };
+        match kind {
+            Some(HashKind::Sha2) => return Err(bad_hash_size()),
+            Some(kind) => {
+                sizes.by_kind.insert(kind, size);
+            }
+            None if value.contains('=') => return Err(bad_hash_size()),
+            None => sizes.all = Some(size),
+        }
+    }
+    Ok(sizes)
+}
+
+// Each --combine is a perceptual hash, optionally with its own distance, as in
+// dct=10. The others use --distance.
+fn combined_search<'a>(
+    matches: &clap::ArgMatches<'a>,
+    distance: u8,
+) -> Result<Option<CombinedSearch>> {
+    let values = match matches.values_of(COMBINE_ARG_NAME) {
+        Some(values) => values,
+        None => return Ok(None),
+    };
+    // --use_hash always has a (default) value, so only an explicit one conflicts.
+    if matches.occurrences_of(HASH_TYPE_ARG_NAME) > 0 {
+        return Err(ItoolsError::UsageError(
+            "--combine chooses the hashes, so it can't be used with --use_hash",
+        ));
+    }
+    if matches.is_present(ANY_ORIENTATION_ARG_NAME) {
+        return Err(ItoolsError::UsageError(
+            "--any_orientation can't be used with --combine",
+        ));
+    }
+
+    let sizes = hash_sizes(matches)?;
+    let weights = hash_weights(matches)?;
+    let mut hashes: Vec<CombinedHash> = Vec::new();
+    for value in values {
+        let (name, hash_distance) = match value.find('=') {
+            Some(pos) => (
+                &value[..pos],
+                value[pos + 1..].parse::<u8>().map_err(|_| bad_combine())?,
+            ),
+            None => (value, distance),
+        };
+        let kind = match HashKind::from_name(name) {
+            Some(kind) if kind.needs_decoding() => kind,
+            _ => return Err(bad_combine()),
+        };
+        if hashes.iter().any(|hash| hash.spec.kind == kind) {
+            return Err(bad_combine());
+        }
+        hashes.push(CombinedHash {
+            spec: HashSpec::new(kind, sizes.size_for(kind)),
+            distance: hash_distance,
+            weight: weights.get(&kind).cloned().unwrap_or(1.0),
+        });
+    }
+    if weights
+        .keys()
+        .any(|kind| !hashes.iter().any(|hash| hash.spec.kind == *kind))
+    {
+        return Err(ItoolsError::UsageError(
+            "--hash_weight is for a hash that isn't one of the --combine hashes",
+        ));
+    }
+
+    let rule = combine_rule(matches, hashes.len())?;
+    Ok(Some(CombinedSearch { hashes, rule }))
+}
+
+fn bad_combine() -> ItoolsError {
+    ItoolsError::UsageError(
+        "--combine must be a perceptual hash, used once, optionally with a distance, as in dct=10",
+    )
+}
+
+// Without --max_weighted_distance, every one of the hashes has to agree, unless
+// --min_votes asks for fewer.
+fn combine_rule<'a>(matches: &clap::ArgMatches<'a>, num_hashes: usize) -> Result<CombineRule> {
+    if let Some(value) = matches.value_of(MAX_WEIGHTED_DISTANCE_ARG_NAME) {
+        return match value.parse::<f64>() {
+            Ok(cutoff) if cutoff >= 0.0 && cutoff <= 1.0 => Ok(CombineRule::Weighted(cutoff)),
+            _ => Err(ItoolsError::UsageError(
+                "--max_weighted_distance must be from 0 to 1",
+            )),
+        };
+    }
+    match matches.value_of(MIN_VOTES_ARG_NAME) {
+        None => Ok(CombineRule::Votes(num_hashes)),
+        Some(value) => match value.parse::<usize>() {
+            Ok(votes) if votes >= 1 && votes <= num_hashes => Ok(CombineRule::Votes(votes)),
+            _ => Err(ItoolsError::UsageError(
+                "--min_votes must be from 1 to the number of --combine hashes",
+            )),
+        },
+    }
+}
+
+// "dct=2" counts the DCT hash twice as much as the hashes without a weight.
+fn hash_weights<'a>(matches: &clap::ArgMatches<'a>) -> Result<HashMap<HashKind, f64>> {
+    let mut weights = HashMap::new();
+    for value in matches
+        .values_of(HASH_WEIGHT_ARG_NAME)
+        .into_iter()
+        .flatten()
+    {
+        let pos = value.find('=').ok_or_else(bad_hash_weight)?;
+        let kind = HashKind::from_name(&value[..pos]).ok_or_else(bad_hash_weight)?;
+        match value[pos + 1..].parse::<f64>() {
+            Ok(weight) if weight > 0.0 && weight.is_finite() => {
+                weights.insert(kind, weight);
+            }
+            _ => return Err(bad_hash_weight()),
+        }
+    }
+    Ok(weights)
+}
+
+fn bad_hash_weight() -> ItoolsError {
+    ItoolsError::UsageError("--hash_weight must be a hash and a positive weight, as in dct=2")
+}
+
+fn bad_hash_size() -> ItoolsError {
+    ItoolsError::UsageError(
+        "--hash_size must be from 2 to 32, optionally after a perceptual hash, as in dct=16",
+    )
+}
+
 #[cfg(test)]
 mod testing {
     use std::ffi::OsString;
     use std::iter::Iterator;
 
+    use std::path::{Path, PathBuf};
+
+    use super::super::cache_cmd::{CacheCommand, ExportFormat};
+    use super::super::cache_format::CacheFormat;
+    use super::super::combined::{CombineRule, CombinedHash, CombinedSearch};
+    use super::super::fileinfo::{HashKind, HashSpec};
     use super::super::result::ItoolsError;
+    use super::super::search::SearchType;
+    use super::super::size_filter::SizeFilter;
+    use super::super::sniff::ImageType;
+    use super::super::store::StoreKind;
     use super::Config;
 
     pub const CMD_NAME: &str = "CommandNameIgnored";
@@ -253,6 +980,22 @@ mod testing {
         assert_eq!(c_many.unwrap().files, vec!["foo", "bar", "quux"])
     }
 
+    #[test]
+    fn test_files_from() {
+        let c_stdin = Config::new_from(vec![CMD_NAME, "--files_from", "-"]).unwrap();
+        assert_eq!(Some(PathBuf::from("-")), c_stdin.files_from);
+        assert!(c_stdin.files.is_empty());
+        assert_eq!(false, c_stdin.null_separated);
+
+        let c_null = Config::new_from(vec![CMD_NAME, "--files_from", "list", "-0", "foo"]).unwrap();
+        assert_eq!(Some(PathBuf::from("list")), c_null.files_from);
+        assert_eq!(true, c_null.null_separated);
+        assert_eq!(c_null.files, vec!["foo"]);
+
+        let c_default = make_test_config(std::iter::empty::<OsString>());
+        assert_eq!(None, c_default.files_from);
+    }
+
     #[test]
     fn test_show_progress() {
         let c_default = make_test_config(std::iter::empty::<OsString>());
@@ -276,4 +1019,448 @@ mod testing {
         let c_cache_only = make_test_config(vec!["--cache_only"]);
         assert_eq!(true, c_cache_only.cache_only);
     }
+
+    #[test]
+    fn test_by_extension() {
+        let c_default = make_test_config(std::iter::empty::<OsString>());
+        assert_eq!(false, c_default.by_extension);
+
+        let c_by_extension = make_test_config(vec!["--by_extension"]);
+        assert_eq!(true, c_by_extension.by_extension);
+    }
+
+    #[test]
+    fn test_cache_format() {
+        let c_default = make_test_config(std::iter::empty::<OsString>());
+        assert_eq!(None, c_default.cache_format);
+
+        let c_binary = make_test_config(vec!["--cache_format", "binary"]);
+        assert_eq!(Some(CacheFormat::Binary), c_binary.cache_format);
+
+        let c_yaml = make_test_config(vec!["--cache_format", "yaml"]);
+        assert_eq!(Some(CacheFormat::Yaml), c_yaml.cache_format);
+    }
+
+    #[test]
+    fn test_cache_journal() {
+        let c_default = make_test_config(std::iter::empty::<OsString>());
+        assert_eq!(false, c_default.cache_journal);
+
+        let c_journal = make_test_config(vec!["--cache_journal"]);
+        assert_eq!(true, c_journal.cache_journal);
+    }
+
+    #[test]
+    fn test_cache_store() {
+        let c_default = make_test_config(std::iter::empty::<OsString>());
+        assert_eq!(None, c_default.cache_store);
+
+        let c_sqlite = make_test_config(vec!["--cache_store", "sqlite"]);
+        assert_eq!(Some(StoreKind::Sqlite), c_sqlite.cache_store);
+
+        let c_file = make_test_config(vec!["--cache_store", "file"]);
+        assert_eq!(Some(StoreKind::File), c_file.cache_store);
+    }
+
+    #[test]
+    fn test_no_command() {
+        let c = make_test_config(std::iter::empty::<OsString>());
+        assert_eq!(None, c.command);
+    }
+
+    #[test]
+    fn test_cache_commands() {
+        let c_relocate =
+            Config::new_from(vec![CMD_NAME, "cache", "relocate", "/old", "/new"]).unwrap();
+        assert_eq!(
+            Some(CacheCommand::Relocate(
+                PathBuf::from("/old"),
+                PathBuf::from("/new")
+            )),
+            c_relocate.command
+        );
+
+        // A relative prefix that no longer exists.
+        let c_relocate =
+            Config::new_from(vec![CMD_NAME, "cache", "relocate", "src/gone", "/new"]).unwrap();
+        assert_eq!(
+            Some(CacheCommand::Relocate(
+                Path::new("src").canonicalize().unwrap().join("gone"),
+                PathBuf::from("/new")
+            )),
+            c_relocate.command
+        );
+
+        let c_stats = Config::new_from(vec![CMD_NAME, "cache", "stats"]).unwrap();
+        assert_eq!(Some(CacheCommand::Stats), c_stats.command);
+        assert!(c_stats.files.is_empty());
+
+        // Tests run in the crate's directory. The roots are a relative one, and one
+        // that goes the long way round.
+        let c_prune =
+            Config::new_from(vec![CMD_NAME, "cache", "prune", "src", "src/neardups/.."]).unwrap();
+        let src = Path::new("src").canonicalize().unwrap();
+        assert_eq!(
+            Some(CacheCommand::Prune(vec![src.clone(), src])),
+            c_prune.command
+        );
+        // Pruning against a root that isn't there would drop every entry.
+        assert!(Config::new_from(vec![CMD_NAME, "cache", "prune", "/no/such/root"]).is_err());
+
+        let c_verify = Config::new_from(vec![CMD_NAME, "cache", "verify"]).unwrap();
+        assert_eq!(Some(CacheCommand::Verify(20)), c_verify.command);
+
+        let c_verify = Config::new_from(vec![CMD_NAME, "cache", "verify", "-n", "5"]).unwrap();
+        assert_eq!(Some(CacheCommand::Verify(5)), c_verify.command);
+
+        for bad in &["abc", "-1", "2.5"] {
+            let c_bad = Config::new_from(vec![CMD_NAME, "cache", "verify", "-n", bad]);
+            assert!(c_bad.is_err(), "{}", bad);
+        }
+
+        let c_export =
+            Config::new_from(vec![CMD_NAME, "cache", "export", "--format", "json"]).unwrap();
+        assert_eq!(
+            Some(CacheCommand::Export(ExportFormat::Json)),
+            c_export.command
+        );
+
+        let c_export = Config::new_from(vec![CMD_NAME, "cache", "export"]).unwrap();
+        assert_eq!(
+            Some(CacheCommand::Export(ExportFormat::Csv)),
+            c_export.command
+        );
+
+        let c_failures = Config::new_from(vec![CMD_NAME, "cache", "failures"]).unwrap();
+        assert_eq!(Some(CacheCommand::Failures), c_failures.command);
+    }
+
+    #[test]
+    fn test_decoder() {
+        let c_default = make_test_config(std::iter::empty::<OsString>());
+        assert!(c_default.decoder.is_enabled(ImageType::Raw));
+
+        let c_decoder = make_test_config(vec![
+            "--skip_format",
+            "raw",
+            "--skip_format",
+            "webp",
+            "--heic_converter",
+            "magick {} jpg:-",
+        ]);
+        assert!(!c_decoder.decoder.is_enabled(ImageType::Raw));
+        assert!(!c_decoder.decoder.is_enabled(ImageType::Webp));
+        assert!(c_decoder.decoder.is_enabled(ImageType::Jpeg));
+        assert!(c_decoder.decoder.is_enabled(ImageType::Heic));
+    }
+
+    #[test]
+    fn test_include_exclude() {
+        let c_default = make_test_config(std::iter::empty::<OsString>());
+        assert!(c_default.include.is_empty());
+        assert!(c_default.exclude.is_empty());
+
+        let c_filters = make_test_config(vec![
+            "--include",
+            "*.jpg",
+            "--exclude",
+            "@eaDir",
+            "--exclude",
+            ".git",
+        ]);
+        assert_eq!(vec!["*.jpg"], c_filters.include);
+        assert_eq!(vec!["@eaDir", ".git"], c_filters.exclude);
+    }
+
+    #[test]
+    fn test_walk_options() {
+        let c_default = make_test_config(std::iter::empty::<OsString>());
+        assert_eq!(false, c_default.follow_symlinks);
+        assert_eq!(None, c_default.max_depth);
+        assert_eq!(false, c_default.one_file_system);
+        assert_eq!(false, c_default.skip_hidden);
+
+        let c_walk = make_test_config(vec![
+            "--follow_symlinks",
+            "--max_depth",
+            "2",
+            "--one_file_system",
+            "--skip_hidden",
+        ]);
+        assert_eq!(true, c_walk.follow_symlinks);
+        assert_eq!(Some(2), c_walk.max_depth);
+        assert_eq!(true, c_walk.one_file_system);
+        assert_eq!(true, c_walk.skip_hidden);
+
+        let c_bad = Config::new_from(vec![CMD_NAME, "--max_depth", "deep", "foo"]);
+        assert!(c_bad.is_err());
+    }
+
+    #[test]
+    fn test_strict() {
+        let c_default = make_test_config(std::iter::empty::<OsString>());
+        assert_eq!(false, c_default.strict);
+
+        let c_strict = make_test_config(vec!["--strict"]);
+        assert_eq!(true, c_strict.strict);
+    }
+
+    #[test]
+    fn test_search() {
+        let c_default = make_test_config(std::iter::empty::<OsString>());
+        assert_eq!(
+            SearchType::PERCEPTUAL(HashSpec::new(HashKind::Dct, 8), 0),
+            c_default.search
+        );
+
+        let c_sha2 = make_test_config(vec!["--use_hash", "sha2"]);
+        assert_eq!(SearchType::SHA2, c_sha2.search);
+
+        let c_features = make_test_config(vec!["--use_hash", "features"]);
+        assert_eq!(SearchType::FEATURES, c_features.search);
+        assert!(c_features.search.hash_needs().features);
+
+        let c_wavelet = make_test_config(vec!["--use_hash", "wavelet", "-d", "3"]);
+        assert_eq!(
+            SearchType::PERCEPTUAL(HashSpec::new(HashKind::Wavelet, 8), 3),
+            c_wavelet.search
+        );
+    }
+
+    #[test]
+    fn test_any_orientation() {
+        let c_any = make_test_config(vec!["--any_orientation", "-d", "4"]);
+        assert_eq!(
+            SearchType::DIHEDRAL(HashSpec::new(HashKind::Dct, 8), 4),
+            c_any.search
+        );
+
+        let c_sha2 = Config::new_from(vec![
+            CMD_NAME,
+            "--any_orientation",
+            "--use_hash",
+            "sha2",
+            "foo",
+        ]);
+        assert!(c_sha2.is_err());
+
+        let c_features = Config::new_from(vec![
+            CMD_NAME,
+            "--any_orientation",
+            "--use_hash",
+            "features",
+            "foo",
+        ]);
+        assert!(c_features.is_err());
+    }
+
+    #[test]
+    fn test_hash_size() {
+        let c_all = make_test_config(vec!["--use_hash", "block", "--hash_size", "16"]);
+        assert_eq!(
+            SearchType::PERCEPTUAL(HashSpec::new(HashKind::Block, 16), 0),
+            c_all.search
+        );
+
+        let c_each = make_test_config(vec![
+            "--use_hash",
+            "color",
+            "--hash_size",
+            "16",
+            "--hash_size",
+            "color=4",
+            "--hash_size",
+            "dct=32",
+        ]);
+        assert_eq!(
+            SearchType::PERCEPTUAL(HashSpec::new(HashKind::Color, 4), 0),
+            c_each.search
+        );
+
+        for bad in &["1", "33", "big", "sha2=16", "fancy=16"] {
+            let c_bad = Config::new_from(vec![CMD_NAME, "--hash_size", bad, "foo"]);
+            assert!(c_bad.is_err(), "{}", bad);
+        }
+    }
+
+    #[test]
+    fn test_combine() {
+        let c_votes = make_test_config(vec![
+            "-d",
+            "5",
+            "--combine",
+            "mean=6",
+            "--combine",
+            "dct",
+            "--combine",
+            "wavelet=3",
+            "--hash_size",
+            "wavelet=16",
+            "--min_votes",
+            "2",
+        ]);
+        let hash = |kind, size, distance| CombinedHash {
+            spec: HashSpec::new(kind, size),
+            distance,
+            weight: 1.0,
+        };
+        assert_eq!(
+            SearchType::COMBINED(CombinedSearch {
+                hashes: vec![
+                    hash(HashKind::Mean, 8, 6),
+                    hash(HashKind::Dct, 8, 5),
+                    hash(HashKind::Wavelet, 16, 3),
+                ],
+                rule: CombineRule::Votes(2),
+            }),
+            c_votes.search
+        );
+
+        let c_all = make_test_config(vec!["--combine", "mean", "--combine", "grad"]);
+        match c_all.search {
+            SearchType::COMBINED(ref combined) => {
+                assert_eq!(CombineRule::Votes(2), combined.rule)
+            }
+            _ => panic!("Expected a combined search"),
+        }
+
+        let c_weighted = make_test_config(vec![
+            "--combine",
+            "mean",
+            "--combine",
+            "dct",
+            "--max_weighted_distance",
+            "0.1",
+            "--hash_weight",
+            "dct=2",
+        ]);
+        match c_weighted.search {
+            SearchType::COMBINED(ref combined) => {
+                assert_eq!(CombineRule::Weighted(0.1), combined.rule);
+                assert_eq!(1.0, combined.hashes[0].weight);
+                assert_eq!(2.0, combined.hashes[1].weight);
+            }
+            _ => panic!("Expected a combined search"),
+        }
+
+        for bad in &[
+            vec!["--combine", "sha2"],
+            vec!["--combine", "fancy"],
+            vec!["--combine", "dct=far"],
+            vec!["--combine", "dct", "--combine", "dct=4"],
+            vec!["--combine", "dct", "--use_hash", "mean"],
+            vec!["--combine", "dct", "--any_orientation"],
+            vec!["--combine", "dct", "--min_votes", "2"],
+            vec!["--combine", "dct", "--min_votes", "0"],
+            vec!["--min_votes", "1"],
+            vec!["--combine", "dct", "--max_weighted_distance", "2"],
+            vec![
+                "--combine",
+                "dct",
+                "--min_votes",
+                "1",
+                "--max_weighted_distance",
+                "0.1",
+            ],
+            vec!["--combine", "dct", "--hash_weight", "dct=2"],
+            vec![
+                "--combine",
+                "dct",
+                "--max_weighted_distance",
+                "0.1",
+                "--hash_weight",
+                "mean=2",
+            ],
+            vec![
+                "--combine",
+                "dct",
+                "--max_weighted_distance",
+                "0.1",
+                "--hash_weight",
+                "dct=0",
+            ],
+        ] {
+            let mut args = vec![CMD_NAME];
+            args.extend(bad);
+            args.push("foo");
+            assert!(Config::new_from(args).is_err(), "{:?}", bad);
+        }
+    }
+
+    #[test]
+    fn test_size_filter() {
+        let c_default = make_test_config(std::iter::empty::<OsString>());
+        assert_eq!(SizeFilter::default(), c_default.size_filter);
+
+        let c_sizes = make_test_config(vec![
+            "--min_size",
+            "10K",
+            "--max_size",
+            "50M",
+            "--min_pixels",
+            "40000",
+        ]);
+        assert_eq!(
+            SizeFilter {
+                min_size: Some(10 * 1024),
+                max_size: Some(50 * 1024 * 1024),
+                min_pixels: Some(40000),
+            },
+            c_sizes.size_filter
+        );
+
+        let c_bad = Config::new_from(vec![CMD_NAME, "--min_size", "tiny", "foo"]);
+        assert!(c_bad.is_err());
+    }
+
+    #[test]
+    fn test_failures_file() {
+        let c_default = make_test_config(std::iter::empty::<OsString>());
+        assert_eq!(None, c_default.failures_file);
+
+        let c_file = make_test_config(vec!["--failures_file", "failed.tsv"]);
+        assert_eq!(Some(PathBuf::from("failed.tsv")), c_file.failures_file);
+    }
+
+    #[test]
+    fn test_jobs() {
+        let c_default = make_test_config(std::iter::empty::<OsString>());
+        assert!(c_default.jobs > 0);
+
+        let c_jobs = make_test_config(vec!["--jobs", "3"]);
+        assert_eq!(3, c_jobs.jobs);
+
+        let c_bad = Config::new_from(vec![CMD_NAME, "--jobs", "many", "foo"]);
+        assert!(c_bad.is_err());
+
+        let c_zero = Config::new_from(vec![CMD_NAME, "--jobs", "0", "foo"]);
+        assert!(c_zero.is_err());
+    }
+
+    #[test]
+    fn test_memory_budget() {
+        let c_default = make_test_config(std::iter::empty::<OsString>());
+        assert_eq!(super::DEFAULT_MEMORY_BUDGET, c_default.memory_budget);
+
+        let c_budget = make_test_config(vec!["--memory_budget", "2G"]);
+        assert_eq!(2 * 1024 * 1024 * 1024, c_budget.memory_budget);
+
+        let c_zero = Config::new_from(vec![CMD_NAME, "--memory_budget", "0", "foo"]);
+        assert!(c_zero.is_err());
+    }
+
+    #[test]
+    fn test_retry_failed() {
+        let c_default = make_test_config(std::iter::empty::<OsString>());
+        assert_eq!(false, c_default.retry_failed);
+
+        let c_retry = make_test_config(vec!["--retry_failed"]);
+        assert_eq!(true, c_retry.retry_failed);
+    }
+
+    #[test]
+    fn test_library_root() {
+        let c_root = make_test_config(vec!["--library_root", "/photos"]);
+        assert_eq!(Some(PathBuf::from("/photos")), c_root.library_root);
+    }
 }
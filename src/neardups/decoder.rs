@@ -0,0 +1,287 @@
+use std::collections::HashSet;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+use image::{self, DynamicImage};
+
+use super::result::{ItoolsError, Result};
+use super::sniff::ImageType;
+
+// The placeholder for the input file in the external converter's command line.
+const CONVERTER_INPUT_PLACEHOLDER: &str = "{}";
+// How the converter's command line refers to the input file, once it is handed to
+// the shell. The file is passed as an argument, so its name is never parsed.
+const CONVERTER_INPUT_PARAMETER: &str = "\"$1\"";
+
+// Turns the contents of an image file into an image. Most formats are decoded by
+// the image crate. RAW files are decoded from the JPEG preview inside them, and
+// HEIC files are run through an external converter, if one is configured.
+#[derive(Clone, Debug, Default)]
+pub struct Decoder {
+    skipped: HashSet<ImageType>,
+    heic_converter: Option<String>,
+}
+
+impl Decoder {
+    pub fn skip(&mut self, image_type: ImageType) {
+        self.skipped.insert(image_type);
+    }
+
+    // The converter is a shell command line that writes a JPEG or PNG to stdout,
+    // so it can quote its arguments and use pipes. "{}" is replaced by the input
+    // file, which is added at the end if there is no "{}". (e.g., "magick {} jpg:-")
+    pub fn set_heic_converter(&mut self, command: &str) {
+        let command = command.trim();
+        self.heic_converter = if command.is_empty() {
+            None
+        } else {
+            Some(command.to_string())
+        };
+    }
+
+    pub fn is_enabled(&self, image_type: ImageType) -> bool {
+        !self.skipped.contains(&image_type) && !self.needs_converter(image_type)
+    }
+
+    // True for formats that are wanted, but can't be decoded without a converter.
+    pub fn needs_converter(&self, image_type: ImageType) -> bool {
+        image_type == ImageType::Heic
+            && !self.skipped.contains(&image_type)
+            && self.heic_converter.is_none()
+    }
+
+    pub fn decode(&self, path: &Path, buf: &[u8]) -> Result<DynamicImage> {
+        let image_type = ImageType::detect(path, buf).ok_or(ItoolsError::UnsupportedFormat(
+            "not a recognised image format",
+        ))?;
+        if self.needs_converter(image_type) {
+            return Err(ItoolsError::UnsupportedFormat(
+                "HEIC files need a converter (see --heic_converter)",
+            ));
+        }
+        if !self.is_enabled(image_type) {
+            return Err(ItoolsError::UnsupportedFormat("image format is turned off"));
+        }
+
+        match image_type {
+            ImageType::Raw => {
+                let preview = largest_embedded_jpeg(buf).ok_or(ItoolsError::UnsupportedFormat(
+                    "no JPEG preview in RAW file",
+                ))?;
+                Ok(image::load_from_memory(preview)?)
+            }
+            // is_enabled() checked that there is a converter.
+            ImageType::Heic => self.convert(path, self.heic_converter.as_ref().unwrap()),
+            _ => Ok(image::load_from_memory(buf)?),
+        }
+    }
+
+    fn convert(&self, path: &Path, command: &str) -> Result<DynamicImage> {
+        let output = Command::new("sh")
+            .arg("-c")
+            .arg(shell_command(command))
+            // The name that the shell gives $0.
+            .arg("sh")
+            .arg(path)
+            .stdin(Stdio::null())
+            .output()?;
+        if !output.status.success() {
+            return Err(ItoolsError::Converter(format!(
+                "{} failed ({}): {}",
+                command,
+                output.status,
+                String::from_utf8_lossy(&output.stderr).trim()
+            )));
+        }
+        Ok(image::load_from_memory(&output.stdout)?)
+    }
+}
+
+// RAW formats differ a lot, but nearly all of them carry a full-size (or nearly
+// full-size) JPEG preview. Rather than parse each container, look for complete
+// JPEG streams (SOI ... EOI) and use the largest one.
+fn largest_embedded_jpeg(buf: &[u8]) -> Option<&[u8]> {
+    let mut best: Option<&[u8]> = None;
+    let mut pos = 0;
+    while let Some(start) = find(&buf[pos..], b"\xFF\xD8\xFF").map(|i| pos + i) {
+        let end = match jpeg_end(&buf[start..]) {
+            Some(len) => start + len,
+            // Not a JPEG after all, or a truncated one.
+            None => {
+                pos = start + 1;
+                continue;
+            }
+        };
+        let candidate = &buf[start..end];
+        if best.map(|b| candidate.len() > b.len()).unwrap_or(true) {
+            best = Some(candidate);
+        }
+        pos = end;
+    }
+    best
+}
+
+// The length of the JPEG stream at the start of `buf`, up to and including its
+// EOI. The segments are walked by their lengths, since they can hold anything
+// (an EXIF thumbnail in APP1 has an EOI of its own), and markers are only looked
+// for in the entropy-coded data after each SOS.
+fn jpeg_end(buf: &[u8]) -> Option<usize> {
+    let mut pos = 2;
+    loop {
+        if *buf.get(pos)? != 0xFF {
+            return None;
+        }
+        // Any number of 0xFF can pad a marker.
+        while *buf.get(pos + 1)? == 0xFF {
+            pos += 1;
+        }
+        let marker = buf[pos + 1];
+        pos += 2;
+        match marker {
+            // EOI
+            0xD9 => return Some(pos),
+            // TEM and RSTn stand alone.
+            0x01 | 0xD0...0xD7 => continue,
+            _ => (),
+        }
+
+        let len = (usize::from(*buf.get(pos)?) << 8) | usize::from(*buf.get(pos + 1)?);
+        if len < 2 {
+            return None;
+        }
+        pos += len;
+        if marker == 0xDA {
+            // In the scan, a 0xFF is followed by 0 (a stuffed byte) or a RSTn.
+            // Anything else is the next marker.
+            loop {
+                if *buf.get(pos)? == 0xFF {
+                    match *buf.get(pos + 1)? {
+                        0x00 | 0xD0...0xD7 => pos += 2,
+                        _ => break,
+                    }
+                } else {
+                    pos += 1;
+                }
+            }
+        }
+    }
+}
+
+// The converter's command line, with the input file as the shell's first
+// positional parameter.
+fn shell_command(command: &str) -> String {
+    if command.contains(CONVERTER_INPUT_PLACEHOLDER) {
+        command.replace(CONVERTER_INPUT_PLACEHOLDER, CONVERTER_INPUT_PARAMETER)
+    } else {
+        format!("{} {}", command, CONVERTER_INPUT_PARAMETER)
+    }
+}
+
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack
+        .windows(needle.len())
+        .position(|window| window == needle)
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::sniff::ImageType;
+    use super::{largest_embedded_jpeg, shell_command, Decoder};
+
+    fn segment(marker: u8, payload: &[u8]) -> Vec<u8> {
+        let len = payload.len() + 2;
+        let mut segment = vec![0xFF, marker, (len >> 8) as u8, len as u8];
+        segment.extend_from_slice(payload);
+        segment
+    }
+
+    // SOI, an APPn segment holding `app`, a scan of `scan`, and EOI.
+    fn jpeg(app_marker: u8, app: &[u8], scan: &[u8]) -> Vec<u8> {
+        let mut jpeg = vec![0xFF, 0xD8];
+        jpeg.extend(segment(app_marker, app));
+        jpeg.extend(segment(0xDA, b"sos header"));
+        jpeg.extend_from_slice(scan);
+        jpeg.extend_from_slice(b"\xFF\xD9");
+        jpeg
+    }
+
+    #[test]
+    fn test_largest_embedded_jpeg() {
+        let small = jpeg(0xE0, b"JFIF", b"small");
+        let large = jpeg(0xE0, b"JFIF", b"much \xFF\x00larger\xFF\xD0scan");
+        let mut buf = b"MM\x00*header".to_vec();
+        buf.extend_from_slice(&small);
+        buf.extend_from_slice(b"padding");
+        buf.extend_from_slice(&large);
+        buf.extend_from_slice(b"trailer");
+        assert_eq!(Some(&large[..]), largest_embedded_jpeg(&buf));
+
+        assert_eq!(None, largest_embedded_jpeg(b"MM\x00*no preview"));
+        assert_eq!(None, largest_embedded_jpeg(b"\xFF\xD8\xFFtruncated"));
+        assert_eq!(None, largest_embedded_jpeg(&large[..large.len() - 2]));
+    }
+
+    #[test]
+    fn test_preview_with_thumbnail() {
+        // The EXIF thumbnail's EOI doesn't end the preview around it.
+        let mut exif = b"Exif\x00\x00".to_vec();
+        exif.extend(jpeg(0xE0, b"JFIF", b"thumbnail"));
+        let preview = jpeg(0xE1, &exif, b"the full-size preview, which is large");
+        let mut buf = b"II*\x00header".to_vec();
+        buf.extend_from_slice(&preview);
+        assert_eq!(Some(&preview[..]), largest_embedded_jpeg(&buf));
+    }
+
+    #[test]
+    fn test_is_enabled() {
+        let mut decoder = Decoder::default();
+        assert!(decoder.is_enabled(ImageType::Jpeg));
+        assert!(decoder.is_enabled(ImageType::Raw));
+        // HEIC needs a converter.
+        assert!(!decoder.is_enabled(ImageType::Heic));
+        assert!(decoder.needs_converter(ImageType::Heic));
+        assert!(!decoder.needs_converter(ImageType::Jpeg));
+
+        decoder.set_heic_converter("magick {} jpg:-");
+        assert!(decoder.is_enabled(ImageType::Heic));
+        assert!(!decoder.needs_converter(ImageType::Heic));
+
+        decoder.set_heic_converter("  ");
+        decoder.skip(ImageType::Heic);
+        assert!(!decoder.needs_converter(ImageType::Heic));
+
+        decoder.skip(ImageType::Raw);
+        assert!(!decoder.is_enabled(ImageType::Raw));
+    }
+
+    #[test]
+    fn test_shell_command() {
+        assert_eq!("magick \"$1\" jpg:-", shell_command("magick {} jpg:-"));
+        assert_eq!(
+            "heif-convert-stdout --quality 90 \"$1\"",
+            shell_command("heif-convert-stdout --quality 90")
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_converter_is_run_by_the_shell() {
+        use std::env;
+        use std::fs;
+
+        use image::{GenericImage, GrayImage, Luma};
+
+        let dir = env::temp_dir().join("itools_test_converter_is_run_by_the_shell");
+        fs::create_dir_all(&dir).unwrap();
+        // Spaces and quotes in the name don't confuse the converter.
+        let png = dir.join("converted.png");
+        let input = dir.join("it's a \"photo\".heic");
+        GrayImage::from_pixel(3, 2, Luma([7])).save(&png).unwrap();
+        fs::rename(&png, &input).unwrap();
+
+        let image = Decoder::default().convert(&input, "cat {} | cat").unwrap();
+        assert_eq!((3, 2), image.dimensions());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,147 @@
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use super::fileinfo::FileStamp;
+use super::result::{ItoolsError, Result};
+
+// Only this many failures are listed individually in the summary. The rest are
+// just counted.
+const MAX_LISTED_FAILURES: usize = 10;
+
+// A file that couldn't be hashed, and why. The stamp is the file's, if we could
+// get it, and is what lets the failure be remembered.
+#[derive(Debug)]
+pub struct HashFailure {
+    pub path: PathBuf,
+    pub error: ItoolsError,
+    pub stamp: Option<FileStamp>,
+}
+
+impl HashFailure {
+    pub fn new<T>(path: T, error: ItoolsError) -> HashFailure
+    where
+        T: Into<PathBuf>,
+    {
+        HashFailure {
+            path: path.into(),
+            error,
+            stamp: None,
+        }
+    }
+
+    pub fn with_stamp(mut self, stamp: FileStamp) -> HashFailure {
+        self.stamp = Some(stamp);
+        self
+    }
+}
+
+// A failure, as remembered in the cache. The file isn't tried again until it
+// changes (or the user asks for it with --retry_failed).
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct FailedFile {
+    pub path: PathBuf,
+    pub stamp: FileStamp,
+    pub kind: String,
+    pub message: String,
+}
+
+impl FailedFile {
+    // Failures without a stamp (e.g., the file has vanished) aren't worth
+    // remembering.
+    pub fn from_failure(failure: &HashFailure) -> Option<FailedFile> {
+        failure.stamp.map(|stamp| FailedFile {
+            path: failure.path.clone(),
+            stamp,
+            kind: failure.error.kind().to_string(),
+            message: failure.error.to_string(),
+        })
+    }
+}
+
+pub fn print_summary(failures: &[HashFailure]) {
+    if failures.is_empty() {
+        return;
+    }
+
+    eprintln!("{} files could not be hashed:", failures.len());
+    for (kind, count) in count_by_kind(failures) {
+        eprintln!("   {:<8} {}", kind, count);
+    }
+    for failure in failures.iter().take(MAX_LISTED_FAILURES) {
+        eprintln!("{}: {}", failure.path.display(), failure.error);
+    }
+    if failures.len() > MAX_LISTED_FAILURES {
+        eprintln!(
+            "...and {} more. (Use --failures_file to list all of them.)",
+            failures.len() - MAX_LISTED_FAILURES
+        );
+    }
+}
+
+// One failure per line: the kind, the path, and the message, separated by tabs.
+pub fn write_failures(filename: &Path, failures: &[HashFailure]) -> Result<()> {
+    let mut w = BufWriter::new(File::create(filename)?);
+    for failure in failures {
+        writeln!(
+            w,
+            "{}\t{}\t{}",
+            failure.error.kind(),
+            failure.path.display(),
+            failure.error.to_string().replace('\n', " ")
+        )?;
+    }
+    w.flush()?;
+    Ok(())
+}
+
+fn count_by_kind(failures: &[HashFailure]) -> BTreeMap<&'static str, usize> {
+    let mut counts = BTreeMap::new();
+    for failure in failures {
+        *counts.entry(failure.error.kind()).or_insert(0) += 1;
+    }
+    counts
+}
+
+#[cfg(test)]
+mod test {
+    use std::io;
+
+    use super::super::fileinfo::FileStamp;
+    use super::super::result::ItoolsError;
+    use super::{count_by_kind, FailedFile, HashFailure};
+
+    #[test]
+    fn test_failed_file_needs_stamp() {
+        let failure = HashFailure::new("a.jpg", ItoolsError::InvalidState("weird"));
+        assert_eq!(None, FailedFile::from_failure(&failure));
+
+        let stamp = FileStamp {
+            size: 10,
+            ..FileStamp::default()
+        };
+        let failed = FailedFile::from_failure(&failure.with_stamp(stamp)).unwrap();
+        assert_eq!(stamp, failed.stamp);
+        assert_eq!("invalid_state", failed.kind);
+    }
+
+    #[test]
+    fn test_count_by_kind() {
+        let failures = vec![
+            HashFailure::new(
+                "a.jpg",
+                io::Error::new(io::ErrorKind::PermissionDenied, "nope").into(),
+            ),
+            HashFailure::new(
+                "b.jpg",
+                io::Error::new(io::ErrorKind::NotFound, "gone").into(),
+            ),
+            HashFailure::new("c.jpg", ItoolsError::InvalidState("weird")),
+        ];
+        let counts = count_by_kind(&failures);
+        assert_eq!(Some(&2), counts.get("io"));
+        assert_eq!(Some(&1), counts.get("invalid_state"));
+        assert_eq!(2, counts.len());
+    }
+}
@@ -0,0 +1,667 @@
+use std::collections::HashMap;
+
+use image::{imageops, DynamicImage, FilterType, GenericImage, GrayImage};
+use serialize::base64::{FromBase64, ToBase64, STANDARD};
+
+use super::fileinfo::{FileInfo, FEATURES_NAME};
+use super::utils::xorshift;
+
+// Local features, in the style of ORB: FAST corners, described by rotated BRIEF
+// descriptors. Two images that share a part (a crop, a screenshot of a photo, a
+// copy with a border added) share features in the same arrangement, even though
+// their global hashes are nothing alike. Comparing features is much more
+// expensive than comparing hashes, so only candidate pairs that share some
+// similar descriptors are checked.
+
+// Images are analyzed at (at most) this size, and then at smaller scales, so that
+// a crop (which is blown up relative to the original) has features at the same
+// scale as some of the original's.
+const ANALYSIS_SIZE: u32 = 512;
+const LEVEL_SCALE: f32 = 1.4;
+// The most keypoints kept at each scale.
+const LEVEL_QUOTAS: [usize; 3] = [100, 60, 40];
+
+// A corner needs an arc of this many pixels on the circle around it that are all
+// brighter (or all darker) than it by the threshold.
+const FAST_THRESHOLD: i32 = 20;
+const FAST_ARC: usize = 9;
+const FAST_CIRCLE: [(i32, i32); 16] = [
+    (0, -3),
+    (1, -3),
+    (2, -2),
+    (3, -1),
+    (3, 0),
+    (3, 1),
+    (2, 2),
+    (1, 3),
+    (0, 3),
+    (-1, 3),
+    (-2, 2),
+    (-3, 1),
+    (-3, 0),
+    (-3, -1),
+    (-2, -2),
+    (-1, -3),
+];
+
+// The patch used for a keypoint's orientation. The descriptor's points are inside
+// a smaller circle, so that they stay inside the patch when they are rotated.
+const PATCH_RADIUS: i32 = 15;
+const PATTERN_RADIUS: i32 = 13;
+const BORDER: i32 = PATCH_RADIUS + 1;
+const BLUR_RADIUS: i32 = 2;
+
+const DESCRIPTOR_BITS: usize = 256;
+// The pattern is random, but has to be the same in every run, since the
+// descriptors are cached.
+const PATTERN_SEED: u64 = 0x5eed_f0eb_b1e5;
+
+// Two descriptors match if they are close, and clearly closer than the next best.
+const MAX_DESCRIPTOR_DISTANCE: u32 = 64;
+const RATIO: f32 = 0.8;
+
+// The matches have to agree on a single rotation, scale and translation.
+const RANSAC_ITERATIONS: usize = 500;
+const RANSAC_SEED: u64 = 0x0ddb_a11;
+const INLIER_TOLERANCE: f32 = 8.0;
+const MIN_SCALE: f32 = 0.125;
+const MAX_SCALE: f32 = 8.0;
+const MIN_INLIERS: usize = 12;
+
+// Candidates are found with locality-sensitive hashing: descriptors are bucketed
+// by a few of their bits, several times over, and images that share enough
+// buckets are candidates. Keys get more bits as the library grows, so that the
+// average bucket stays about the same size. Buckets much bigger than that (of
+// featureless texture, say) say nothing about which images are alike, and are
+// ignored.
+const LSH_TABLES: usize = 8;
+const MIN_LSH_BITS: usize = 16;
+const MAX_LSH_BITS: usize = 32;
+const AVERAGE_BUCKET_SIZE: u64 = 2;
+const MIN_MAX_BUCKET_SIZE: usize = 64;
+const MAX_BUCKET_SIZE_FACTOR: usize = 32;
+// Unrelated images land in the same bucket now and then. Images that are alike
+// share many buckets in each table, so a pair needs a few votes from one table
+// before it is remembered at all, and enough from all of them to be a candidate.
+const MIN_TABLE_VOTES: u32 = 2;
+const MIN_CANDIDATE_VOTES: u32 = 12;
+
+lazy_static! {
+    // The pairs of points compared for each bit of a descriptor, as [x1, y1, x2, y2].
+    static ref PATTERN: Vec<[i32; 4]> = make_pattern();
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Keypoint {
+    // In the coordinates of the largest analysis image.
+    pub x: f32,
+    pub y: f32,
+    pub descriptor: [u64; 4],
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FeatureMatch {
+    // The matched keypoints that agree on how one image maps onto the other.
+    pub inliers: usize,
+    // The inliers, as a fraction of the keypoints in the image with fewer of them.
+    pub score: f64,
+}
+
+pub fn extract(image: &DynamicImage) -> Vec<Keypoint> {
+    let (width, height) = image.dimensions();
+    let base: GrayImage = if width.max(height) > ANALYSIS_SIZE {
+        image
+            .resize(ANALYSIS_SIZE, ANALYSIS_SIZE, FilterType::Triangle)
+            .to_luma()
+    } else {
+        image.to_luma()
+    };
+
+    let mut keypoints = Vec::new();
+    let mut scale = 1.0f32;
+    for (level, quota) in LEVEL_QUOTAS.iter().enumerate() {
+        let level_image = if level == 0 {
+            base.clone()
+        } else {
+            scale *= LEVEL_SCALE;
+            let level_width = (base.width() as f32 / scale).round() as u32;
+            let level_height = (base.height() as f32 / scale).round() as u32;
+            imageops::resize(&base, level_width, level_height, FilterType::Triangle)
+        };
+        for mut keypoint in extract_level(&level_image, *quota) {
+            keypoint.x *= scale;
+            keypoint.y *= scale;
+            keypoints.push(keypoint);
+        }
+    }
+    keypoints
+}
+
+pub fn features_of(fi: &FileInfo) -> Option<Vec<Keypoint>> {
+    fi.hashes
+        .get(FEATURES_NAME)
+        .and_then(|encoded| decode(encoded))
+}
+
+pub fn set_features(fi: &mut FileInfo, keypoints: &[Keypoint]) {
+    fi.hashes
+        .insert(FEATURES_NAME.to_string(), encode(keypoints));
+}
+
+// Layout (little-endian): count: u16, then for each keypoint, x: u16, y: u16, and
+// the descriptor's four u64s. The count means that an image without any keypoints
+// still has a (non-empty) entry, and isn't analyzed again.
+fn encode(keypoints: &[Keypoint]) -> String {
+    let mut bytes = Vec::with_capacity(2 + keypoints.len() * 36);
+    bytes.extend_from_slice(&(keypoints.len() as u16).to_le_bytes());
+    for keypoint in keypoints {
+        bytes.extend_from_slice(&(keypoint.x.round() as u16).to_le_bytes());
+        bytes.extend_from_slice(&(keypoint.y.round() as u16).to_le_bytes());
+        for word in &keypoint.descriptor {
+            bytes.extend_from_slice(&word.to_le_bytes());
+        }
+    }
+    bytes.to_base64(STANDARD)
+}
+
+fn decode(encoded: &str) -> Option<Vec<Keypoint>> {
+    let bytes = encoded.from_base64().ok()?;
+    if bytes.len() < 2 {
+        return None;
+    }
+    let count = usize::from(u16::from_le_bytes([bytes[0], bytes[1]]));
+    let records = &bytes[2..];
+    if records.len() != count * 36 {
+        return None;
+    }
+
+    let u16_at = |at: usize| f32::from(u16::from_le_bytes([records[at], records[at + 1]]));
+    let u64_at = |at: usize| {
+        let mut word = [0u8; 8];
+        word.copy_from_slice(&records[at..at + 8]);
+        u64::from_le_bytes(word)
+    };
+    Some(
+        (0..count)
+            .map(|i| {
+                let at = i * 36;
+                Keypoint {
+                    x: u16_at(at),
+                    y: u16_at(at + 2),
+                    descriptor: [
+                        u64_at(at + 4),
+                        u64_at(at + 12),
+                        u64_at(at + 20),
+                        u64_at(at + 28),
+                    ],
+                }
+            })
+            .collect(),
+    )
+}
+
+// Pairs of images (by index) that share enough similar descriptors to be worth
+// matching.
+pub fn candidate_pairs(features: &[Vec<Keypoint>]) -> Vec<(usize, usize)> {
+    let keypoint_count: usize = features.iter().map(Vec::len).sum();
+    let lsh_bit_count = lsh_bit_count(keypoint_count);
+    let max_bucket_size = max_bucket_size(keypoint_count, lsh_bit_count);
+    let bits = lsh_bits(lsh_bit_count);
+
+    // One table at a time, so that only one table's keys are held at once.
+    let mut votes: HashMap<(usize, usize), u32> = HashMap::new();
+    for table_bits in bits.chunks(lsh_bit_count) {
+        let mut keys: Vec<(u32, u32)> = Vec::with_capacity(keypoint_count);
+        for (image, keypoints) in features.iter().enumerate() {
+            for keypoint in keypoints {
+                let key = table_bits.iter().enumerate().fold(0u32, |key, (i, bit)| {
+                    key | (descriptor_bit(&keypoint.descriptor, *bit) as u32) << i
+                });
+                keys.push((key, image as u32));
+            }
+        }
+        // Sorted by key, then image, so that each bucket is a run of distinct images.
+        keys.sort_unstable();
+        keys.dedup();
+
+        let mut table_votes: HashMap<(usize, usize), u32> = HashMap::new();
+        let mut start = 0;
+        while start < keys.len() {
+            let key = keys[start].0;
+            let len = keys[start..].iter().take_while(|k| k.0 == key).count();
+            let bucket = &keys[start..start + len];
+            start += len;
+            if bucket.len() > max_bucket_size {
+                continue;
+            }
+            for (i, a) in bucket.iter().enumerate() {
+                for b in &bucket[i + 1..] {
+                    *table_votes.entry((a.1 as usize, b.1 as usize)).or_insert(0) += 1;
+                }
+            }
+        }
+        for (pair, count) in table_votes {
+            if count >= MIN_TABLE_VOTES {
+                *votes.entry(pair).or_insert(0) += count;
+            }
+        }
+    }
+
+    let mut pairs: Vec<(usize, usize)> = votes
+        .into_iter()
+        .filter(|(_, count)| *count >= MIN_CANDIDATE_VOTES)
+        .map(|(pair, _)| pair)
+        .collect();
+    pairs.sort();
+    pairs
+}
+
+// None unless enough of the keypoints match, and agree on how one image maps onto
+// the other (which a scattering of chance matches won't).
+pub fn match_features(a: &[Keypoint], b: &[Keypoint]) -> Option<FeatureMatch> {
+    let pairs = putative_matches(a, b);
+    if pairs.len() < MIN_INLIERS {
+        return None;
+    }
+    let inliers = ransac_inliers(&pairs);
+    if inliers < MIN_INLIERS {
+        return None;
+    }
+    Some(FeatureMatch {
+        inliers,
+        score: (inliers as f64 / a.len().min(b.len()) as f64).min(1.0),
+    })
+}
+
+type Point = (f32, f32);
+
+fn putative_matches(a: &[Keypoint], b: &[Keypoint]) -> Vec<(Point, Point)> {
+    let mut pairs = Vec::new();
+    for ka in a {
+        let mut best = (u32::max_value(), None);
+        let mut second = u32::max_value();
+        for kb in b {
+            let d = descriptor_distance(&ka.descriptor, &kb.descriptor);
+            if d < best.0 {
+                second = best.0;
+                best = (d, Some(kb));
+            } else if d < second {
+                second = d;
+            }
+        }
+        if let (d, Some(kb)) = best {
+            if d <= MAX_DESCRIPTOR_DISTANCE && (d as f32) < RATIO * second as f32 {
+                pairs.push(((ka.x, ka.y), (kb.x, kb.y)));
+            }
+        }
+    }
+    pairs
+}
+
+// Tries similarity transforms (rotation, scale, and translation) through random
+// pairs of matches, and returns the most matches that any of them agree with.
+fn ransac_inliers(pairs: &[(Point, Point)]) -> usize {
+    let mut state = RANSAC_SEED;
+    let mut best = 0;
+    for _ in 0..RANSAC_ITERATIONS {
+        let i = (xorshift(&mut state) % pairs.len() as u64) as usize;
+        let j = (xorshift(&mut state) % pairs.len() as u64) as usize;
+        let ((a1, b1), (a2, b2)) = (pairs[i], pairs[j]);
+
+        // As complex numbers, b = z * a + t.
+        let da = (a2.0 - a1.0, a2.1 - a1.1);
+        let db = (b2.0 - b1.0, b2.1 - b1.1);
+        let norm = da.0 * da.0 + da.1 * da.1;
+        if norm < 1.0 {
+            continue;
+        }
+        let z = (
+            (db.0 * da.0 + db.1 * da.1) / norm,
+            (db.1 * da.0 - db.0 * da.1) / norm,
+        );
+        let scale = (z.0 * z.0 + z.1 * z.1).sqrt();
+        if scale < MIN_SCALE || scale > MAX_SCALE {
+            continue;
+        }
+        let t = (
+            b1.0 - (z.0 * a1.0 - z.1 * a1.1),
+            b1.1 - (z.1 * a1.0 + z.0 * a1.1),
+        );
+
+        let inliers = pairs
+            .iter()
+            .filter(|(a, b)| {
+                let dx = z.0 * a.0 - z.1 * a.1 + t.0 - b.0;
+                let dy = z.1 * a.0 + z.0 * a.1 + t.1 - b.1;
+                dx * dx + dy * dy < INLIER_TOLERANCE * INLIER_TOLERANCE
+            })
+            .count();
+        best = best.max(inliers);
+    }
+    best
+}
+
+fn extract_level(gray: &GrayImage, quota: usize) -> Vec<Keypoint> {
+    let (width, height) = (gray.width() as i32, gray.height() as i32);
+    if width <= 2 * BORDER || height <= 2 * BORDER {
+        return Vec::new();
+    }
+    let pixels: Vec<i32> = gray.pixels().map(|p| i32::from(p.data[0])).collect();
+    let index = |x: i32, y: i32| (y * width + x) as usize;
+
+    let mut scores = vec![0; pixels.len()];
+    for y in BORDER..height - BORDER {
+        for x in BORDER..width - BORDER {
+            scores[index(x, y)] = fast_score(&pixels, width, x, y);
+        }
+    }
+
+    // Only keep the strongest corner in each neighbourhood.
+    let mut corners = Vec::new();
+    for y in BORDER..height - BORDER {
+        for x in BORDER..width - BORDER {
+            let score = scores[index(x, y)];
+            let is_max = score > 0
+                && (-1..2).all(|dy| {
+                    (-1..2).all(|dx| (dx == 0 && dy == 0) || scores[index(x + dx, y + dy)] < score)
+                });
+            if is_max {
+                corners.push((score, x, y));
+            }
+        }
+    }
+    corners.sort_by(|a, b| b.0.cmp(&a.0));
+    corners.truncate(quota);
+
+    let smoothed = box_blur(&pixels, width, height);
+    corners
+        .into_iter()
+        .map(|(_, x, y)| {
+            let angle = orientation(&smoothed, width, x, y);
+            Keypoint {
+                x: x as f32,
+                y: y as f32,
+                descriptor: describe(&smoothed, width, x, y, angle),
+            }
+        })
+        .collect()
+}
+
+// Zero if the pixel isn't a corner. Otherwise, how much the circle around it
+// differs from it, beyond the threshold.
+fn fast_score(pixels: &[i32], width: i32, x: i32, y: i32) -> i32 {
+    let center = pixels[(y * width + x) as usize];
+    let ring: Vec<i32> = FAST_CIRCLE
+        .iter()
+        .map(|(dx, dy)| pixels[((y + dy) * width + x + dx) as usize])
+        .collect();
+    let class = |v: i32| {
+        if v > center + FAST_THRESHOLD {
+            1
+        } else if v < center - FAST_THRESHOLD {
+            -1
+        } else {
+            0
+        }
+    };
+
+    // Go around twice, so that arcs can wrap.
+    let mut run = 0;
+    let mut longest = 0;
+    let mut previous = 0;
+    for i in 0..2 * ring.len() {
+        let c = class(ring[i % ring.len()]);
+        run = if c != 0 && c == previous { run + 1 } else { 1 };
+        if c != 0 {
+            longest = longest.max(run);
+        }
+        previous = c;
+    }
+    if longest < FAST_ARC {
+        return 0;
+    }
+    ring.iter()
+        .map(|v| ((v - center).abs() - FAST_THRESHOLD).max(0))
+        .sum()
+}
+
+fn box_blur(pixels: &[i32], width: i32, height: i32) -> Vec<i32> {
+    let blur = |source: &[i32], horizontal: bool| -> Vec<i32> {
+        let mut out = vec![0; source.len()];
+        for y in 0..height {
+            for x in 0..width {
+                let mut sum = 0;
+                let mut count = 0;
+                for d in -BLUR_RADIUS..=BLUR_RADIUS {
+                    let (sx, sy) = if horizontal { (x + d, y) } else { (x, y + d) };
+                    if sx >= 0 && sx < width && sy >= 0 && sy < height {
+                        sum += source[(sy * width + sx) as usize];
+                        count += 1;
+                    }
+                }
+                out[(y * width + x) as usize] = sum / count;
+            }
+        }
+        out
+    };
+    blur(&blur(pixels, true), false)
+}
+
+// The direction from the keypoint to the patch's intensity centroid.
+fn orientation(smoothed: &[i32], width: i32, x: i32, y: i32) -> f32 {
+    let (mut m10, mut m01) = (0i64, 0i64);
+    for dy in -PATCH_RADIUS..=PATCH_RADIUS {
+        for dx in -PATCH_RADIUS..=PATCH_RADIUS {
+            if dx * dx + dy * dy <= PATCH_RADIUS * PATCH_RADIUS {
+                let v = i64::from(smoothed[((y + dy) * width + x + dx) as usize]);
+                m10 += i64::from(dx) * v;
+                m01 += i64::from(dy) * v;
+            }
+        }
+    }
+    (m01 as f32).atan2(m10 as f32)
+}
+
+// The pattern is rotated to the keypoint's orientation, so that the descriptor
+// doesn't change when the image is rotated.
+fn describe(smoothed: &[i32], width: i32, x: i32, y: i32, angle: f32) -> [u64; 4] {
+    let (sin, cos) = angle.sin_cos();
+    let at = |px: i32, py: i32| {
+        let rx = (cos * px as f32 - sin * py as f32).round() as i32;
+        let ry = (sin * px as f32 + cos * py as f32).round() as i32;
+        smoothed[((y + ry) * width + x + rx) as usize]
+    };
+
+    let mut descriptor = [0u64; 4];
+    for (i, points) in PATTERN.iter().enumerate() {
+        if at(points[0], points[1]) < at(points[2], points[3]) {
+            descriptor[i / 64] |= 1 << (i % 64);
+        }
+    }
+    descriptor
+}
+
+fn descriptor_distance(a: &[u64; 4], b: &[u64; 4]) -> u32 {
+    a.iter().zip(b).map(|(x, y)| (x ^ y).count_ones()).sum()
+}
+
+fn descriptor_bit(descriptor: &[u64; 4], bit: usize) -> bool {
+    (descriptor[bit / 64] & (1 << (bit % 64))) != 0
+}
+
+fn make_pattern() -> Vec<[i32; 4]> {
+    let mut state = PATTERN_SEED;
+    let side = (2 * PATTERN_RADIUS + 1) as u64;
+    let mut point = || loop {
+        let x = (xorshift(&mut state) % side) as i32 - PATTERN_RADIUS;
+        let y = (xorshift(&mut state) % side) as i32 - PATTERN_RADIUS;
+        if x * x + y * y <= PATTERN_RADIUS * PATTERN_RADIUS {
+            return (x, y);
+        }
+    };
+    (0..DESCRIPTOR_BITS)
+        .map(|_| {
+            let (x1, y1) = point();
+            let (x2, y2) = point();
+            [x1, y1, x2, y2]
+        })
+        .collect()
+}
+
+// The number of bits in each LSH table's key, for this many keypoints.
+fn lsh_bit_count(keypoint_count: usize) -> usize {
+    let mut bits = MIN_LSH_BITS;
+    while bits < MAX_LSH_BITS && keypoint_count as u64 > AVERAGE_BUCKET_SIZE << bits {
+        bits += 1;
+    }
+    bits
+}
+
+// Once the keys can't get any longer, the buckets grow with the library, and so
+// does the size at which they stop being useful.
+fn max_bucket_size(keypoint_count: usize, lsh_bit_count: usize) -> usize {
+    let average = (keypoint_count as u64 >> lsh_bit_count) as usize;
+    MIN_MAX_BUCKET_SIZE.max(average * MAX_BUCKET_SIZE_FACTOR)
+}
+
+// The descriptor bits that make up each of the LSH tables' keys.
+fn lsh_bits(lsh_bit_count: usize) -> Vec<usize> {
+    let mut state = PATTERN_SEED ^ RANSAC_SEED;
+    (0..LSH_TABLES * lsh_bit_count)
+        .map(|_| (xorshift(&mut state) % DESCRIPTOR_BITS as u64) as usize)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use image::{DynamicImage, GrayImage, Luma};
+
+    use super::super::fileinfo::FileInfo;
+    use super::super::utils::xorshift;
+    use super::{
+        candidate_pairs, decode, encode, extract, features_of, lsh_bit_count, match_features,
+        max_bucket_size, set_features, Keypoint, AVERAGE_BUCKET_SIZE, MIN_LSH_BITS,
+        MIN_MAX_BUCKET_SIZE,
+    };
+
+    // Random blocks of grey make plenty of corners.
+    fn blocks(width: u32, height: u32, seed: u64) -> GrayImage {
+        let mut state = seed;
+        let cells: Vec<u8> = (0..(width / 8 + 1) * (height / 8 + 1))
+            .map(|_| (xorshift(&mut state) % 256) as u8)
+            .collect();
+        GrayImage::from_fn(width, height, |x, y| {
+            Luma([cells[((y / 8) * (width / 8 + 1) + x / 8) as usize]])
+        })
+    }
+
+    fn crop(image: &GrayImage, x: u32, y: u32, width: u32, height: u32) -> GrayImage {
+        GrayImage::from_fn(width, height, |cx, cy| *image.get_pixel(x + cx, y + cy))
+    }
+
+    #[test]
+    fn test_encoding() {
+        let keypoints = vec![
+            Keypoint {
+                x: 12.0,
+                y: 345.0,
+                descriptor: [1, 2, 3, u64::max_value()],
+            },
+            Keypoint {
+                x: 0.0,
+                y: 511.0,
+                descriptor: [0, 0, 0, 0],
+            },
+        ];
+        assert_eq!(Some(keypoints.clone()), decode(&encode(&keypoints)));
+        assert_eq!(Some(Vec::new()), decode(&encode(&[])));
+        assert!(!encode(&[]).is_empty());
+        assert_eq!(None, decode("AAAA"));
+
+        let mut fi = FileInfo::default();
+        assert!(!fi.has_features());
+        set_features(&mut fi, &keypoints);
+        assert!(fi.has_features());
+        assert_eq!(Some(keypoints), features_of(&fi));
+    }
+
+    #[test]
+    fn test_flat_image_has_no_features() {
+        let flat = DynamicImage::ImageLuma8(GrayImage::from_pixel(200, 200, Luma([128])));
+        assert!(extract(&flat).is_empty());
+    }
+
+    #[test]
+    fn test_crop_matches() {
+        let original = blocks(400, 300, 1);
+        let cropped = crop(&original, 60, 40, 280, 220);
+        let other = blocks(400, 300, 2);
+
+        let features = vec![
+            extract(&DynamicImage::ImageLuma8(original)),
+            extract(&DynamicImage::ImageLuma8(cropped)),
+            extract(&DynamicImage::ImageLuma8(other)),
+        ];
+        assert!(!features[0].is_empty());
+
+        let found = match_features(&features[0], &features[1]).unwrap();
+        assert!(found.score > 0.0 && found.score <= 1.0);
+        assert!(match_features(&features[0], &features[2]).is_none());
+        assert!(match_features(&features[1], &features[2]).is_none());
+
+        let pairs = candidate_pairs(&features);
+        assert!(pairs.contains(&(0, 1)));
+    }
+
+    #[test]
+    fn test_lsh_scales_with_library() {
+        assert_eq!(MIN_LSH_BITS, lsh_bit_count(600));
+        for images in &[1_000u64, 20_000, 1_000_000] {
+            let keypoints = (images * 200) as usize;
+            let bits = lsh_bit_count(keypoints);
+            assert!(keypoints as u64 >> bits <= AVERAGE_BUCKET_SIZE);
+            assert_eq!(MIN_MAX_BUCKET_SIZE, max_bucket_size(keypoints, bits));
+        }
+
+        // Past the longest keys, buckets can be bigger before they're ignored.
+        let huge = usize::max_value() / 2;
+        let bits = lsh_bit_count(huge);
+        assert!(max_bucket_size(huge, bits) > MIN_MAX_BUCKET_SIZE);
+    }
+
+    #[test]
+    fn test_candidate_pairs_in_larger_library() {
+        let mut state = 7;
+        let mut random_keypoint = || Keypoint {
+            x: 0.0,
+            y: 0.0,
+            descriptor: [
+                xorshift(&mut state),
+                xorshift(&mut state),
+                xorshift(&mut state),
+                xorshift(&mut state),
+            ],
+        };
+        let mut features: Vec<Vec<Keypoint>> = (0..2_000)
+            .map(|_| (0..100).map(|_| random_keypoint()).collect())
+            .collect();
+
+        // Image 1 is image 0 with a little noise in its descriptors.
+        features[1] = features[0]
+            .iter()
+            .enumerate()
+            .map(|(i, keypoint)| {
+                let mut descriptor = keypoint.descriptor;
+                descriptor[i % 4] ^= 1 << (i % 64);
+                Keypoint {
+                    descriptor,
+                    ..*keypoint
+                }
+            })
+            .collect();
+        // Images 2 and 3 only happen to share a single keypoint.
+        features[3][0] = features[2][0];
+
+        assert_eq!(vec![(0, 1)], candidate_pairs(&features));
+    }
+}
@@ -0,0 +1,348 @@
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use super::cache_format::{read_table, write_table, CacheFormat};
+use super::failures::FailedFile;
+use super::fileinfo::FileInfo;
+use super::journal::Journal;
+use super::pcache::HashTable;
+use super::result::Result;
+use super::spinner_reader::SpinnerReader;
+use super::store::{CacheStore, LibraryRoot};
+use super::utils::{path_with_suffix, remove_file_if_exists};
+
+// In journal mode, the journal is compacted into the cache file once it holds this
+// many records (and at the end of the run).
+const COMPACT_AFTER_RECORDS: usize = 10_000;
+
+// Otherwise, the whole cache file is rewritten at most this often.
+const SAVE_INTERVAL_SECS: u64 = 15;
+
+// Keeps the cache in a single YAML or binary file, optionally with a journal of
+// recent changes next to it.
+#[derive(Debug)]
+pub struct FileStore {
+    filename: PathBuf,
+    format: CacheFormat,
+    requested_format: Option<CacheFormat>,
+    journaled: bool,
+    root: LibraryRoot,
+
+    journal: Option<Journal>,
+    dirty: bool,
+    last_save_time: Instant,
+}
+
+impl FileStore {
+    pub fn new<T>(filename: T) -> FileStore
+    where
+        T: Into<PathBuf>,
+    {
+        FileStore {
+            filename: filename.into(),
+            format: CacheFormat::default(),
+            requested_format: None,
+            journaled: false,
+            root: LibraryRoot::default(),
+            journal: None,
+            dirty: false,
+            last_save_time: Instant::now(),
+        }
+    }
+
+    // Save in this format. An existing cache in another format is converted when
+    // it is loaded. If this is never called, the cache keeps whatever format it
+    // was loaded in.
+    pub fn set_format(&mut self, format: CacheFormat) {
+        self.requested_format = Some(format);
+    }
+
+    // In journal mode, new entries are appended to a journal as they arrive
+    // instead of periodically rewriting the whole cache file.
+    pub fn set_journaled(&mut self, journaled: bool) {
+        self.journaled = journaled;
+    }
+
+    pub fn set_root(&mut self, root: LibraryRoot) {
+        self.root = root;
+    }
+
+    // The previous generation of the cache file is kept here, and is used if the
+    // cache file itself can't be read.
+    pub fn backup_path(filename: &Path) -> PathBuf {
+        path_with_suffix(filename, ".bak")
+    }
+
+    // Failures are rare, and are rewritten all at once, so they live in a small
+    // YAML file next to the cache file.
+    pub fn failures_path(filename: &Path) -> PathBuf {
+        path_with_suffix(filename, ".failures")
+    }
+
+    fn read_file(filename: &Path) -> Result<(HashTable, CacheFormat)> {
+        let file = File::open(filename)?;
+        let r = SpinnerReader::new(file, "Loading cache file...");
+        read_table(r)
+    }
+
+    // Returns None if there is no cache yet.
+    fn read_with_backup(&self) -> Result<Option<(HashTable, CacheFormat)>> {
+        let backup_filename = Self::backup_path(&self.filename);
+
+        // If we fail to load the cached file, report an error to avoid overwriting
+        // data. If the file doesn't exist, then go ahead and create a brand new one.
+        if !self.filename.exists() && !backup_filename.exists() {
+            return Ok(None);
+        }
+
+        match Self::read_file(&self.filename) {
+            Ok(contents) => Ok(Some(contents)),
+            Err(err) => {
+                if !backup_filename.exists() {
+                    return Err(err);
+                }
+                eprintln!(
+                    "Unable to read cache file {}: {:?}\nUsing backup {}",
+                    self.filename.display(),
+                    err,
+                    backup_filename.display()
+                );
+                let contents = Self::read_file(&backup_filename)?;
+
+                // Move the broken file aside. Otherwise, the next save would make it
+                // the backup, and we would lose the good copy.
+                if self.filename.exists() {
+                    fs::rename(&self.filename, path_with_suffix(&self.filename, ".corrupt"))?;
+                }
+                Ok(Some(contents))
+            }
+        }
+    }
+
+    // Write the cache so that a crash at any point leaves a complete cache file
+    // behind: write to a temp file in the same directory, fsync it, then rename it
+    // over the old file. The old file is kept as the backup.
+    fn save(&mut self, table: &HashTable) -> Result<()> {
+        let temp_filename = path_with_suffix(&self.filename, ".tmp");
+        {
+            let mut w = BufWriter::new(File::create(&temp_filename)?);
+            write_table(&mut w, &self.root.relativize_table(table), self.format)?;
+            w.flush()?;
+            w.get_ref().sync_all()?;
+        }
+
+        if self.filename.exists() {
+            let backup_filename = Self::backup_path(&self.filename);
+            remove_file_if_exists(&backup_filename)?;
+            // Linking means that there is never a moment without a cache file at
+            // `filename`. Not every filesystem can do that, though.
+            if fs::hard_link(&self.filename, &backup_filename).is_err() {
+                fs::copy(&self.filename, &backup_filename)?;
+            }
+        }
+
+        fs::rename(&temp_filename, &self.filename)?;
+        sync_parent_dir(&self.filename)?;
+
+        // Everything in the journal is in the cache file now.
+        if let Some(ref mut journal) = self.journal {
+            journal.reset()?;
+        }
+        self.dirty = false;
+        self.last_save_time = Instant::now();
+        Ok(())
+    }
+}
+
+impl CacheStore for FileStore {
+    fn load(&mut self) -> Result<HashTable> {
+        let (mut table, loaded_format, existed) = match self.read_with_backup()? {
+            Some((table, format)) => (table, format, true),
+            None => (HashTable::new(), CacheFormat::default(), false),
+        };
+        self.format = self.requested_format.unwrap_or(loaded_format);
+
+        // Anything left in the journal by an earlier run is newer than the file.
+        let journal_filename = Journal::path_for(&self.filename);
+        let replayed = if journal_filename.exists() {
+            Journal::replay(&journal_filename, &mut table)?
+        } else {
+            0
+        };
+
+        let table = self.root.absolutize_table(table);
+
+        // Fold the replayed records into the cache file (converting it, if that
+        // was asked for) before starting a new journal, since that throws the old
+        // one away.
+        let converting = existed && self.format != loaded_format;
+        if converting {
+            eprintln!("Converting cache file to {:?} format...", self.format);
+        }
+        if replayed > 0 || converting {
+            self.save(&table)?;
+        }
+
+        self.journal = if self.journaled {
+            Some(Journal::create(&journal_filename)?)
+        } else {
+            remove_file_if_exists(&journal_filename)?;
+            None
+        };
+
+        Ok(table)
+    }
+
+    // Each record goes straight to the journal (if there is one). Otherwise, it
+    // waits for the next periodic save.
+    fn insert(&mut self, fi: &FileInfo) -> Result<()> {
+        self.dirty = true;
+        if let Some(ref mut journal) = self.journal {
+            journal.append(&self.root.relativize(fi))?;
+        }
+        Ok(())
+    }
+
+    // The journal can't record removals, so they are saved at the next checkpoint.
+    fn remove(&mut self, _path: &Path) -> Result<()> {
+        self.dirty = true;
+        Ok(())
+    }
+
+    fn load_failures(&mut self) -> Result<Vec<FailedFile>> {
+        let failures_filename = Self::failures_path(&self.filename);
+        if !failures_filename.exists() {
+            return Ok(Vec::new());
+        }
+        let failures: Vec<FailedFile> = serde_yaml::from_reader(File::open(failures_filename)?)?;
+        Ok(failures
+            .into_iter()
+            .map(|failed| self.root.absolutize_failure(failed))
+            .collect())
+    }
+
+    fn save_failures(&mut self, failures: &[FailedFile]) -> Result<()> {
+        let failures_filename = Self::failures_path(&self.filename);
+        if failures.is_empty() {
+            remove_file_if_exists(&failures_filename)?;
+            return Ok(());
+        }
+
+        let relative: Vec<FailedFile> = failures
+            .iter()
+            .map(|failed| self.root.relativize_failure(failed))
+            .collect();
+        let temp_filename = path_with_suffix(&failures_filename, ".tmp");
+        {
+            let mut w = BufWriter::new(File::create(&temp_filename)?);
+            serde_yaml::to_writer(&mut w, &relative)?;
+            w.flush()?;
+        }
+        fs::rename(&temp_filename, &failures_filename)?;
+        Ok(())
+    }
+
+    fn checkpoint(&mut self, table: &HashTable, finished: bool) -> Result<()> {
+        if !self.dirty {
+            return Ok(());
+        }
+
+        let due = match self.journal {
+            Some(ref journal) => journal.len() >= COMPACT_AFTER_RECORDS,
+            None => self.last_save_time.elapsed().as_secs() >= SAVE_INTERVAL_SECS,
+        };
+        if finished || due {
+            self.save(table)?;
+        }
+        Ok(())
+    }
+}
+
+// The rename isn't durable until the directory containing it has been synced.
+#[cfg(unix)]
+fn sync_parent_dir(path: &Path) -> io::Result<()> {
+    let parent = match path.parent() {
+        Some(p) if !p.as_os_str().is_empty() => p,
+        _ => Path::new("."),
+    };
+    File::open(parent)?.sync_all()
+}
+
+#[cfg(not(unix))]
+fn sync_parent_dir(_path: &Path) -> io::Result<()> {
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+
+    use super::super::fileinfo::FileInfo;
+    use super::super::pcache::HashTable;
+    use super::super::store::CacheStore;
+    use super::super::utils::path_with_suffix;
+    use super::FileStore;
+
+    fn table(names: &[&str]) -> HashTable {
+        names
+            .iter()
+            .map(|name| {
+                let fi = FileInfo {
+                    filename: Path::new("/photos").join(name),
+                    sha2_hash: name.to_string(),
+                    ..FileInfo::default()
+                };
+                (fi.filename.clone(), fi)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_save_keeps_backup() {
+        let dir = env::temp_dir().join("itools_test_save_keeps_backup");
+        fs::create_dir_all(&dir).unwrap();
+        let filename = dir.join("cache");
+
+        let mut store = FileStore::new(&filename);
+        store.save(&table(&["a.jpg"])).unwrap();
+        store.save(&table(&["a.jpg", "b.jpg"])).unwrap();
+        assert!(!path_with_suffix(&filename, ".tmp").exists());
+
+        assert_eq!(2, FileStore::new(&filename).load().unwrap().len());
+        let backup = FileStore::backup_path(&filename);
+        assert_eq!(1, FileStore::new(&backup).load().unwrap().len());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_corrupt_file_falls_back_to_backup() {
+        let dir = env::temp_dir().join("itools_test_corrupt_file_falls_back_to_backup");
+        fs::create_dir_all(&dir).unwrap();
+        let filename = dir.join("cache");
+
+        let mut store = FileStore::new(&filename);
+        store.save(&table(&["a.jpg"])).unwrap();
+        store.save(&table(&["a.jpg", "b.jpg"])).unwrap();
+        fs::write(&filename, "{{{ not a cache").unwrap();
+
+        let loaded = FileStore::new(&filename).load().unwrap();
+        assert_eq!(1, loaded.len());
+        assert_eq!("a.jpg", loaded[Path::new("/photos/a.jpg")].sha2_hash);
+        // The broken file is kept, out of the way of the next save.
+        let corrupt = path_with_suffix(&filename, ".corrupt");
+        assert_eq!("{{{ not a cache", fs::read_to_string(&corrupt).unwrap());
+        assert!(!filename.exists());
+
+        // Without a backup, the error is reported rather than starting over.
+        fs::remove_file(FileStore::backup_path(&filename)).unwrap();
+        fs::rename(&corrupt, &filename).unwrap();
+        assert!(FileStore::new(&filename).load().is_err());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
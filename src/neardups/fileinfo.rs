@@ -1,91 +1,521 @@
-use std::path::PathBuf;
+use std::collections::BTreeMap;
+use std::fs::{self, Metadata};
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
 
-#[derive(Default, Debug, Serialize, Deserialize)]
+#[derive(Clone, Default, Debug, Serialize, Deserialize)]
 pub struct FileInfo {
     pub filename: PathBuf,
     pub a_hash: String,
     pub d_hash: String,
     pub p_hash: String,
     pub sha2_hash: String,
+    // Entries written before stamps existed get the default stamp, which never
+    // matches a real file, so they will be re-hashed on the next run.
+    #[serde(default)]
+    pub stamp: FileStamp,
+    // The image's dimensions, when it was decoded. Entries written before these
+    // were recorded have zeros, which the filters treat as unknown.
+    #[serde(default)]
+    pub width: u32,
+    #[serde(default)]
+    pub height: u32,
+    // Hashes that don't have a field of their own (other algorithms, and other
+    // sizes), by `HashSpec::name`.
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub hashes: BTreeMap<String, String>,
 }
 
-#[derive(Default, Debug)]
-pub struct FileInfoIncomplete {
-    pub filename: PathBuf,
-    pub a_hash: Option<String>,
-    pub d_hash: Option<String>,
-    pub p_hash: Option<String>,
-    pub sha2_hash: Option<String>,
-}
-
-impl From<FileInfoIncomplete> for FileInfo {
-    fn from(fic: FileInfoIncomplete) -> FileInfo {
-        // Trying to make a FileInfo from an incomplete FileInfoIncomplete is a
-        // programmer error. All of these unwraps should work (or panic!).
-        FileInfo {
-            filename: fic.filename,
-            a_hash: fic.a_hash.unwrap(),
-            d_hash: fic.d_hash.unwrap(),
-            p_hash: fic.p_hash.unwrap(),
-            sha2_hash: fic.sha2_hash.unwrap(),
+// The kinds of hash that are kept for each file.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum HashKind {
+    Mean,
+    Grad,
+    Dct,
+    Block,
+    Wavelet,
+    Color,
+    Sha2,
+}
+
+impl HashKind {
+    pub const ALL: [HashKind; 7] = [
+        HashKind::Mean,
+        HashKind::Grad,
+        HashKind::Dct,
+        HashKind::Block,
+        HashKind::Wavelet,
+        HashKind::Color,
+        HashKind::Sha2,
+    ];
+
+    // Matches the names used on the command line.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            HashKind::Mean => "mean",
+            HashKind::Grad => "grad",
+            HashKind::Dct => "dct",
+            HashKind::Block => "block",
+            HashKind::Wavelet => "wavelet",
+            HashKind::Color => "color",
+            HashKind::Sha2 => "sha2",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<HashKind> {
+        HashKind::ALL
+            .iter()
+            .cloned()
+            .find(|kind| kind.name() == name)
+    }
+
+    // Everything but the SHA-256 is computed from the decoded image.
+    pub fn needs_decoding(&self) -> bool {
+        *self != HashKind::Sha2
+    }
+}
+
+// The size of the perceptual hashes that have always been kept.
+pub const DEFAULT_HASH_SIZE: u32 = 8;
+
+// The local features (see `features.rs`) are kept with the other hashes, under
+// this name, so that every store keeps them.
+pub const FEATURES_NAME: &str = "features";
+
+// A kind of hash, at a particular size. A perceptual hash of size n has n * n bits
+// (three times that for the colour hash). A SHA-256 has no size, and always has 0.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub struct HashSpec {
+    pub kind: HashKind,
+    pub size: u32,
+}
+
+impl HashSpec {
+    pub fn new(kind: HashKind, size: u32) -> HashSpec {
+        let size = if kind.needs_decoding() { size } else { 0 };
+        HashSpec { kind, size }
+    }
+
+    pub fn sha2() -> HashSpec {
+        HashSpec::new(HashKind::Sha2, 0)
+    }
+
+    // "dct" for the default size, and "dct16" for the others.
+    pub fn name(&self) -> String {
+        if self.size == DEFAULT_HASH_SIZE || !self.kind.needs_decoding() {
+            self.kind.name().to_string()
+        } else {
+            format!("{}{}", self.kind.name(), self.size)
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<HashSpec> {
+        // "sha2" has a digit of its own.
+        if let Some(kind) = HashKind::from_name(name) {
+            return Some(HashSpec::new(kind, DEFAULT_HASH_SIZE));
+        }
+        let digits = name.find(|c: char| c.is_ascii_digit())?;
+        let kind = HashKind::from_name(&name[..digits])?;
+        let size = name[digits..].parse().ok()?;
+        Some(HashSpec::new(kind, size))
+    }
+
+    pub fn needs_decoding(&self) -> bool {
+        self.kind.needs_decoding()
+    }
+
+    // The most that two of these hashes can differ by.
+    pub fn bits(&self) -> u32 {
+        match self.kind {
+            HashKind::Sha2 => 256,
+            HashKind::Color => 3 * self.size * self.size,
+            _ => self.size * self.size,
         }
     }
 }
 
-impl FileInfoIncomplete {
-    pub fn with_name<T>(name: T) -> FileInfoIncomplete
+// The eight ways to rotate and flip an image (the dihedral group of the square).
+// An image that was rotated or mirrored by an editor has the hashes of one of the
+// transforms of the original. So does one whose EXIF orientation was applied (or
+// dropped), since the eight EXIF orientations are these same transforms.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Transform {
+    Identity,
+    Rotate90,
+    Rotate180,
+    Rotate270,
+    FlipHorizontal,
+    FlipVertical,
+    // Mirrored across the main diagonal, and across the other one.
+    Transpose,
+    Transverse,
+}
+
+impl Transform {
+    pub const ALL: [Transform; 8] = [
+        Transform::Identity,
+        Transform::Rotate90,
+        Transform::Rotate180,
+        Transform::Rotate270,
+        Transform::FlipHorizontal,
+        Transform::FlipVertical,
+        Transform::Transpose,
+        Transform::Transverse,
+    ];
+
+    pub fn name(&self) -> &'static str {
+        match *self {
+            Transform::Identity => "identity",
+            Transform::Rotate90 => "rot90",
+            Transform::Rotate180 => "rot180",
+            Transform::Rotate270 => "rot270",
+            Transform::FlipHorizontal => "fliph",
+            Transform::FlipVertical => "flipv",
+            Transform::Transpose => "transpose",
+            Transform::Transverse => "transverse",
+        }
+    }
+}
+
+// The hashes that a run needs for each file.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct HashNeeds {
+    pub specs: Vec<HashSpec>,
+    // Also the hashes of every transform of the image, for the perceptual specs.
+    pub transforms: bool,
+    // Also the image's local features.
+    pub features: bool,
+    // Also the image's width and height (for --min_pixels), which searches that
+    // don't decode the image otherwise leave out.
+    pub dimensions: bool,
+}
+
+impl HashNeeds {
+    pub fn new(specs: Vec<HashSpec>) -> HashNeeds {
+        HashNeeds {
+            specs,
+            transforms: false,
+            features: false,
+            dimensions: false,
+        }
+    }
+
+    pub fn needs_decoding(&self) -> bool {
+        self.features || self.dimensions || self.specs.iter().any(|spec| spec.needs_decoding())
+    }
+
+    // The hashes that `fi` doesn't have yet.
+    pub fn missing(&self, fi: &FileInfo) -> Vec<(HashSpec, Transform)> {
+        let mut missing = Vec::new();
+        for spec in &self.specs {
+            let transforms: &[Transform] = if self.transforms && spec.needs_decoding() {
+                &Transform::ALL
+            } else {
+                &[Transform::Identity]
+            };
+            for transform in transforms {
+                if fi.transformed_hash(*spec, *transform).is_empty() {
+                    missing.push((*spec, *transform));
+                }
+            }
+        }
+        missing
+    }
+
+    pub fn are_met_by(&self, fi: &FileInfo) -> bool {
+        self.missing(fi).is_empty()
+            && (!self.features || fi.has_features())
+            && (!self.dimensions || fi.pixels().is_some())
+    }
+}
+
+impl FileInfo {
+    // None if the dimensions weren't recorded.
+    pub fn pixels(&self) -> Option<u64> {
+        if self.width == 0 || self.height == 0 {
+            None
+        } else {
+            Some(u64::from(self.width) * u64::from(self.height))
+        }
+    }
+
+    pub fn hash(&self, spec: HashSpec) -> &str {
+        match (spec.kind, spec.size) {
+            (HashKind::Mean, DEFAULT_HASH_SIZE) => &self.a_hash,
+            (HashKind::Grad, DEFAULT_HASH_SIZE) => &self.d_hash,
+            (HashKind::Dct, DEFAULT_HASH_SIZE) => &self.p_hash,
+            (HashKind::Sha2, _) => &self.sha2_hash,
+            _ => self
+                .hashes
+                .get(&spec.name())
+                .map(|hash| hash.as_str())
+                .unwrap_or(""),
+        }
+    }
+
+    pub fn set_hash(&mut self, spec: HashSpec, hash: String) {
+        match (spec.kind, spec.size) {
+            (HashKind::Mean, DEFAULT_HASH_SIZE) => self.a_hash = hash,
+            (HashKind::Grad, DEFAULT_HASH_SIZE) => self.d_hash = hash,
+            (HashKind::Dct, DEFAULT_HASH_SIZE) => self.p_hash = hash,
+            (HashKind::Sha2, _) => self.sha2_hash = hash,
+            _ => {
+                self.hashes.insert(spec.name(), hash);
+            }
+        }
+    }
+
+    // Only the hashes that a search needs are computed, so the others may be
+    // empty. They are filled in when a later search needs them.
+    pub fn has_hash(&self, spec: HashSpec) -> bool {
+        !self.hash(spec).is_empty()
+    }
+
+    // The hashes of the transformed images are kept with the other hashes, as
+    // "dct/rot90" and so on.
+    pub fn transformed_hash(&self, spec: HashSpec, transform: Transform) -> &str {
+        if transform == Transform::Identity {
+            return self.hash(spec);
+        }
+        self.hashes
+            .get(&transformed_name(spec, transform))
+            .map(|hash| hash.as_str())
+            .unwrap_or("")
+    }
+
+    pub fn set_transformed_hash(&mut self, spec: HashSpec, transform: Transform, hash: String) {
+        if transform == Transform::Identity {
+            self.set_hash(spec, hash);
+        } else {
+            self.hashes.insert(transformed_name(spec, transform), hash);
+        }
+    }
+
+    pub fn has_features(&self) -> bool {
+        self.hashes.contains_key(FEATURES_NAME)
+    }
+
+    // Every hash that has been computed for the file.
+    // (The features aren't a hash, and aren't included.)
+    pub fn hash_specs(&self) -> Vec<HashSpec> {
+        let mut specs: Vec<HashSpec> = [HashKind::Mean, HashKind::Grad, HashKind::Dct]
+            .iter()
+            .map(|kind| HashSpec::new(*kind, DEFAULT_HASH_SIZE))
+            .chain(
+                self.hashes
+                    .keys()
+                    .filter_map(|name| HashSpec::from_name(name)),
+            )
+            .chain(Some(HashSpec::sha2()))
+            .filter(|spec| self.has_hash(*spec))
+            .collect();
+        specs.sort();
+        specs
+    }
+}
+
+fn transformed_name(spec: HashSpec, transform: Transform) -> String {
+    format!("{}/{}", spec.name(), transform.name())
+}
+
+/// The on-disk identity of a file at the time that it was hashed. If any of these
+/// change, then the file has been edited or replaced, and its hashes are stale.
+#[derive(Clone, Copy, Default, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileStamp {
+    pub size: u64,
+    pub mtime_secs: u64,
+    pub mtime_nanos: u32,
+    pub device: Option<u64>,
+    pub inode: Option<u64>,
+}
+
+impl FileStamp {
+    pub fn from_path<T>(path: T) -> io::Result<FileStamp>
     where
-        T: Into<PathBuf>,
+        T: AsRef<Path>,
     {
-        FileInfoIncomplete {
-            filename: name.into(),
-            ..FileInfoIncomplete::default()
-        }
+        fs::metadata(path).map(|md| FileStamp::from_metadata(&md))
     }
 
-    pub fn is_complete(&self) -> bool {
-        self.a_hash.is_some()
-            && self.d_hash.is_some()
-            && self.p_hash.is_some()
-            && self.sha2_hash.is_some()
+    pub fn from_metadata(md: &Metadata) -> FileStamp {
+        // If the platform can't tell us the mtime, fall back to the epoch. The size
+        // (and inode, where we have it) will still catch most replacements.
+        let mtime = md
+            .modified()
+            .ok()
+            .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+            .unwrap_or_default();
+        let (device, inode) = device_and_inode(md);
+
+        FileStamp {
+            size: md.len(),
+            mtime_secs: mtime.as_secs(),
+            mtime_nanos: mtime.subsec_nanos(),
+            device,
+            inode,
+        }
     }
 }
 
+#[cfg(unix)]
+fn device_and_inode(md: &Metadata) -> (Option<u64>, Option<u64>) {
+    use std::os::unix::fs::MetadataExt;
+    (Some(md.dev()), Some(md.ino()))
+}
+
+#[cfg(not(unix))]
+fn device_and_inode(_md: &Metadata) -> (Option<u64>, Option<u64>) {
+    (None, None)
+}
+
 #[cfg(test)]
 mod test {
-    use std::path::PathBuf;
+    use std::env;
+    use std::fs;
 
-    use super::FileInfo;
+    use super::{FileInfo, FileStamp, HashKind, HashNeeds, HashSpec, Transform, FEATURES_NAME};
 
     #[test]
-    fn test_is_complete() {
-        let fi = FileInfo::default();
-        assert_eq!(false, fi.is_complete());
+    fn test_partial_hashes() {
+        let sha2 = HashSpec::sha2();
+        let dct = HashSpec::new(HashKind::Dct, 8);
+        let mut fi = FileInfo {
+            sha2_hash: "xxxxx".into(),
+            ..FileInfo::default()
+        };
+        assert!(HashNeeds::new(vec![sha2]).are_met_by(&fi));
+        assert!(!HashNeeds::new(vec![sha2, dct]).are_met_by(&fi));
+        assert_eq!(vec![sha2], fi.hash_specs());
+
+        fi.set_hash(dct, "blah".into());
+        assert!(HashNeeds::new(vec![sha2, dct]).are_met_by(&fi));
+        assert_eq!("blah", fi.p_hash);
+        assert_eq!(vec![dct, sha2], fi.hash_specs());
+    }
 
-        let fi = FileInfo {
-            a_hash: Some("sisisi".into()),
-            ..fi
+    #[test]
+    fn test_other_sizes_and_kinds() {
+        let dct16 = HashSpec::new(HashKind::Dct, 16);
+        let wavelet = HashSpec::new(HashKind::Wavelet, 8);
+        let mut fi = FileInfo {
+            p_hash: "dct8".into(),
+            ..FileInfo::default()
         };
-        assert_eq!(false, fi.is_complete());
+        fi.set_hash(dct16, "dct16".into());
+        fi.set_hash(wavelet, "wavelet8".into());
+
+        assert_eq!("dct8", fi.hash(HashSpec::new(HashKind::Dct, 8)));
+        assert_eq!("dct16", fi.hash(dct16));
+        assert_eq!("wavelet8", fi.hashes["wavelet"]);
+        assert_eq!("", fi.hash(HashSpec::new(HashKind::Color, 8)));
+        assert_eq!(
+            vec![HashSpec::new(HashKind::Dct, 8), dct16, wavelet],
+            fi.hash_specs()
+        );
+    }
 
-        let fi = FileInfo {
-            d_hash: Some("foobar".into()),
-            p_hash: Some("blah".into()),
-            sha2_hash: Some("xxxxx".into()),
-            ..fi
+    #[test]
+    fn test_transformed_hashes() {
+        let dct = HashSpec::new(HashKind::Dct, 8);
+        let needs = HashNeeds {
+            specs: vec![dct, HashSpec::sha2()],
+            transforms: true,
+            features: false,
+            dimensions: false,
         };
-        assert_eq!(true, fi.is_complete());
+        let mut fi = FileInfo {
+            p_hash: "dct".into(),
+            sha2_hash: "xxxxx".into(),
+            ..FileInfo::default()
+        };
+        assert_eq!(7, needs.missing(&fi).len());
+        assert!(!needs.missing(&fi).contains(&(dct, Transform::Identity)));
+
+        for transform in Transform::ALL.iter().skip(1) {
+            fi.set_transformed_hash(dct, *transform, transform.name().into());
+        }
+        assert!(needs.are_met_by(&fi));
+        assert_eq!("dct", fi.transformed_hash(dct, Transform::Identity));
+        assert_eq!("rot90", fi.hashes["dct/rot90"]);
+        // They aren't hashes in their own right.
+        assert_eq!(vec![dct, HashSpec::sha2()], fi.hash_specs());
     }
 
     #[test]
-    fn test_with_name() {
-        let name = "/foobar/baz";
-        let fi = FileInfo::with_name(name);
-        assert_eq!(PathBuf::from(name), fi.filename);
-        assert_eq!(None, fi.a_hash);
-        assert_eq!(None, fi.d_hash);
-        assert_eq!(None, fi.p_hash);
-        assert_eq!(None, fi.sha2_hash);
+    fn test_feature_needs() {
+        let needs = HashNeeds {
+            features: true,
+            ..HashNeeds::new(vec![HashSpec::sha2()])
+        };
+        assert!(needs.needs_decoding());
+
+        let mut fi = FileInfo {
+            sha2_hash: "xxxxx".into(),
+            ..FileInfo::default()
+        };
+        assert!(!needs.are_met_by(&fi));
+        fi.hashes.insert(FEATURES_NAME.into(), "AAA=".into());
+        assert!(needs.are_met_by(&fi));
+        assert_eq!(vec![HashSpec::sha2()], fi.hash_specs());
     }
 
+    #[test]
+    fn test_dimension_needs() {
+        let needs = HashNeeds {
+            dimensions: true,
+            ..HashNeeds::new(vec![HashSpec::sha2()])
+        };
+        assert!(needs.needs_decoding());
+        assert!(!HashNeeds::new(vec![HashSpec::sha2()]).needs_decoding());
+
+        let mut fi = FileInfo {
+            sha2_hash: "xxxxx".into(),
+            ..FileInfo::default()
+        };
+        assert!(!needs.are_met_by(&fi));
+        fi.width = 640;
+        fi.height = 480;
+        assert!(needs.are_met_by(&fi));
+    }
+
+    #[test]
+    fn test_spec_names() {
+        for spec in &[
+            HashSpec::new(HashKind::Mean, 8),
+            HashSpec::new(HashKind::Block, 16),
+            HashSpec::new(HashKind::Color, 4),
+            HashSpec::sha2(),
+        ] {
+            assert_eq!(Some(*spec), HashSpec::from_name(&spec.name()));
+        }
+        assert_eq!("dct", HashSpec::new(HashKind::Dct, 8).name());
+        assert_eq!("dct16", HashSpec::new(HashKind::Dct, 16).name());
+        assert_eq!("sha2", HashSpec::new(HashKind::Sha2, 16).name());
+        assert_eq!(None, HashSpec::from_name("fancy"));
+        assert_eq!(None, HashSpec::from_name("dct16x"));
+    }
+
+    #[test]
+    fn test_stamp_tracks_file_changes() {
+        let path = env::temp_dir().join("itools_test_stamp_tracks_file_changes");
+        fs::write(&path, b"one").unwrap();
+        let before = FileStamp::from_path(&path).unwrap();
+        assert_eq!(before, FileStamp::from_path(&path).unwrap());
+
+        fs::write(&path, b"three").unwrap();
+        let after = FileStamp::from_path(&path).unwrap();
+        assert_ne!(before, after);
+        assert_eq!(5, after.size);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_default_stamp_never_matches() {
+        let path = env::temp_dir().join("itools_test_default_stamp_never_matches");
+        fs::write(&path, b"").unwrap();
+        assert_ne!(FileStamp::default(), FileStamp::from_path(&path).unwrap());
+        fs::remove_file(&path).unwrap();
+    }
 }
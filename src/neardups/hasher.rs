@@ -1,228 +1,292 @@
-use std::fs;
-use std::path::PathBuf;
+use std::fs::File;
+use std::io::Read;
+use std::path::{Path, PathBuf};
 use std::sync::mpsc::{sync_channel, Receiver, SyncSender};
-use std::sync::{Arc, RwLock};
-use std::thread::{self, JoinHandle};
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
 
-use img_hash::{HashType, ImageHash};
+use image::GenericImage;
 use serialize::base64::{ToBase64, STANDARD};
 use sha2::{Digest, Sha256};
 
-use super::fileinfo::{FileInfo, FileInfoIncomplete};
-use super::utils::{spawn_with_name, SafeSend};
+use super::decoder::Decoder;
+use super::failures::HashFailure;
+use super::features::{extract, set_features};
+use super::fileinfo::{FileInfo, FileStamp, HashNeeds, Transform};
+use super::memory_budget::{MemoryBudget, Reservation};
+use super::pcache::Sha2Index;
+use super::perceptual::{hash_image, transform_image};
+use super::result::Result;
+use super::utils::{bool_to_option, spawn_with_name, SafeSend};
 
 #[derive(Debug)]
 pub struct Hasher {
     file_reader_handle: JoinHandle<()>,
-    sha2_hasher_handle: JoinHandle<()>,
-    image_creator_handle: JoinHandle<()>,
-    ahash_handle: JoinHandle<()>,
-    dhash_handle: JoinHandle<()>,
-    phash_handle: JoinHandle<()>,
-    aggregator_handle: JoinHandle<()>,
+    worker_handles: Vec<JoinHandle<()>>,
+    failures: FailuresHandle,
 }
 
+type FailuresHandle = Arc<Mutex<Vec<HashFailure>>>;
+
+// Decoding dominates the cost of hashing, so each worker takes a whole file from
+// the shared queue and does all of the work on it. The queues are bounded to keep
+// only a few files per worker in memory, and the memory budget bounds how big
+// those files can add up to.
+const QUEUE_SLOTS_PER_WORKER: usize = 2;
+
+const READ_CHUNK_SIZE: usize = 64 * 1024;
+
 impl Hasher {
-    pub fn run(files: Vec<PathBuf>) -> (Hasher, Receiver<FileInfo>) {
-        let (sha_hasher_rx, image_creator_rx, file_reader_handle) = make_file_reader(files);
-        let (aggregator_tx, aggregator_rx, sha2_hasher_handle) = make_sha2_hasher(sha_hasher_rx);
-        let (ahash_rx, dhash_rx, phash_rx, image_creator_handle) =
-            make_image_creator(image_creator_rx);
+    // Only the hashes in `needs` are computed. The SHA-256 always is, since it is
+    // cheap, and is needed to recognize content that has been seen before.
+    // `memory_budget` is the most file content (in bytes) held in memory at once.
+    pub fn run(
+        files: Vec<PathBuf>,
+        needs: HashNeeds,
+        known: Sha2Index,
+        jobs: usize,
+        decoder: Decoder,
+        memory_budget: u64,
+    ) -> (Hasher, Receiver<FileInfo>) {
+        let jobs = jobs.max(1);
+        let failures = FailuresHandle::default();
+        // The contents are only kept around if they need to be decoded.
+        let budget = bool_to_option(needs.needs_decoding(), || MemoryBudget::new(memory_budget));
+        let (work_rx, file_reader_handle) = make_file_reader(
+            files,
+            jobs * QUEUE_SLOTS_PER_WORKER,
+            budget,
+            Arc::clone(&failures),
+        );
 
-        let ahash_handle = make_ahasher(ahash_rx, aggregator_tx.clone());
-        let dhash_handle = make_dhasher(dhash_rx, aggregator_tx.clone());
-        let phash_handle = make_phasher(phash_rx, aggregator_tx);
-        let (aggregator_rx, aggregator_handle) = make_aggregator(aggregator_rx);
+        let (tx, rx) = sync_channel(jobs * QUEUE_SLOTS_PER_WORKER);
+        let work_rx = Arc::new(Mutex::new(work_rx));
+        let needs = Arc::new(needs);
+        let known = Arc::new(known);
+        let decoder = Arc::new(decoder);
+        let worker_handles = (0..jobs)
+            .map(|i| {
+                make_worker(
+                    i,
+                    Arc::clone(&work_rx),
+                    Arc::clone(&needs),
+                    Arc::clone(&known),
+                    Arc::clone(&decoder),
+                    tx.clone(),
+                    Arc::clone(&failures),
+                )
+            })
+            .collect();
 
         (
             Hasher {
                 file_reader_handle,
-                sha2_hasher_handle,
-                image_creator_handle,
-                ahash_handle,
-                dhash_handle,
-                phash_handle,
-                aggregator_handle,
+                worker_handles,
+                failures,
             },
-            aggregator_rx,
+            rx,
         )
     }
 
-    pub fn join(self) {
+    // Returns the files that couldn't be hashed. Every other file was sent to the
+    // receiver.
+    pub fn join(self) -> Vec<HashFailure> {
         self.file_reader_handle.join().unwrap();
-        self.sha2_hasher_handle.join().unwrap();
-        self.image_creator_handle.join().unwrap();
-        self.ahash_handle.join().unwrap();
-        self.dhash_handle.join().unwrap();
-        self.phash_handle.join().unwrap();
-        self.aggregator_handle.join().unwrap();
+        for handle in self.worker_handles {
+            handle.join().unwrap();
+        }
+        Arc::try_unwrap(self.failures)
+            .unwrap()
+            .into_inner()
+            .unwrap()
     }
 }
 
-type FileInfoHandle = Arc<RwLock<FileInfoIncomplete>>;
-type VecHandle<T> = Arc<Vec<T>>;
-type ImageHandle = Arc<image::DynamicImage>;
-
-fn make_file_reader(
-    files: Vec<PathBuf>,
-) -> (
-    Receiver<(FileInfoHandle, VecHandle<u8>)>,
-    Receiver<(FileInfoHandle, VecHandle<u8>)>,
-    JoinHandle<()>,
-) {
-    let (tx0, rx0) = sync_channel(0);
-    let (tx1, rx1) = sync_channel(1);
-
-    let handle = thread::Builder::new()
-        .name("file_reader".into())
-        .spawn(move || {
-            for file in files {
-                let buf = fs::read(&file).unwrap();
-                let fi = FileInfoIncomplete::with_name(file);
-                let fi_handle = Arc::new(RwLock::new(fi));
-                let buf_handle = Arc::new(buf);
-                tx0.safe_send((Arc::clone(&fi_handle), Arc::clone(&buf_handle)));
-                tx1.safe_send((Arc::clone(&fi_handle), Arc::clone(&buf_handle)));
-            }
-        }).unwrap();
-
-    (rx0, rx1, handle)
+// Hash a single file on the current thread. This is much slower than running the
+// pipeline, and is meant for spot checks.
+pub fn hash_file(path: &Path, needs: &HashNeeds, decoder: &Decoder) -> Result<FileInfo> {
+    let budget = MemoryBudget::new(u64::max_value());
+    let contents = read_file(path, Some(&budget))?;
+    hash_contents(
+        path.to_owned(),
+        contents,
+        needs,
+        &Sha2Index::default(),
+        decoder,
+    )
 }
 
-fn make_sha2_hasher(
-    fi_receiver: Receiver<(FileInfoHandle, VecHandle<u8>)>,
-) -> (
-    SyncSender<FileInfoHandle>,
-    Receiver<FileInfoHandle>,
-    JoinHandle<()>,
-) {
-    let (tx, rx) = sync_channel(0);
-
-    let tx_local = tx.clone();
-    let handle = thread::Builder::new()
-        .name("sha_hasher".into())
-        .spawn(move || {
-            let mut hasher = Sha256::new();
-            for (mut fi, buf) in fi_receiver {
-                hasher.input(buf.as_ref());
-                {
-                    let mut w = fi.write().unwrap();
-                    w.sha2_hash = Some(hasher.result_reset().to_vec().to_base64(STANDARD));
-                }
-                tx_local.safe_send(fi);
-            }
-        }).unwrap();
-
-    (tx, rx, handle)
+// What the reader learns about a file. The contents are empty unless they were
+// kept for decoding, in which case they hold a reservation on the memory budget.
+#[derive(Debug)]
+struct FileContents {
+    stamp: FileStamp,
+    sha2_hash: String,
+    buf: Vec<u8>,
+    _reservation: Option<Reservation>,
 }
 
-fn make_image_creator(
-    fi_receiver: Receiver<(FileInfoHandle, VecHandle<u8>)>,
-) -> (
-    Receiver<(FileInfoHandle, ImageHandle)>,
-    Receiver<(FileInfoHandle, ImageHandle)>,
-    Receiver<(FileInfoHandle, ImageHandle)>,
-    JoinHandle<()>,
-) {
-    let (tx0, rx0) = sync_channel(0);
-    let (tx1, rx1) = sync_channel(0);
-    let (tx2, rx2) = sync_channel(0);
-
-    let handle = spawn_with_name("image_creator", move || {
-        for (fi, image_buf) in fi_receiver {
-            match image::load_from_memory(&image_buf) {
-                Ok(im) => {
-                    let im_handle = Arc::new(im);
-                    tx0.safe_send((Arc::clone(&fi), Arc::clone(&im_handle)));
-                    tx1.safe_send((Arc::clone(&fi), Arc::clone(&im_handle)));
-                    tx2.safe_send((fi, im_handle));
-                }
-                Err(err) => {
-                    println!(
-                        "Error reading image for: {:?}\n{:?}",
-                        fi.read().unwrap().filename,
-                        err
-                    );
-                }
-            }
+// The SHA-256 is computed as the file is read, a chunk at a time. The contents are
+// only kept if there is a `budget` to hold them in.
+//
+// Take the stamp from the open file before reading it, so that if the file changes
+// underneath us, the next run will see it as stale.
+fn read_file(path: &Path, budget: Option<&MemoryBudget>) -> Result<FileContents> {
+    let mut f = File::open(path)?;
+    let stamp = FileStamp::from_metadata(&f.metadata()?);
+    let reservation = budget.map(|budget| budget.reserve(stamp.size));
+
+    let mut hasher = Sha256::new();
+    let mut contents = Vec::new();
+    if reservation.is_some() {
+        contents.reserve_exact(stamp.size as usize);
+    }
+    let mut chunk = vec![0u8; READ_CHUNK_SIZE];
+    loop {
+        let len = f.read(&mut chunk)?;
+        if len == 0 {
+            break;
         }
-    });
+        hasher.input(&chunk[..len]);
+        if reservation.is_some() {
+            contents.extend_from_slice(&chunk[..len]);
+        }
+    }
 
-    (rx0, rx1, rx2, handle)
+    Ok(FileContents {
+        stamp,
+        sha2_hash: hasher.result().to_vec().to_base64(STANDARD),
+        buf: contents,
+        _reservation: reservation,
+    })
 }
 
-fn make_ahasher(
-    rx: Receiver<(FileInfoHandle, ImageHandle)>,
-    tx: SyncSender<FileInfoHandle>,
-) -> JoinHandle<()> {
-    let handle = spawn_with_name("ahasher", move || {
-        for (fi, image) in rx {
-            let ahash = ImageHash::hash(image.as_ref(), 8, HashType::Mean);
-            {
-                let mut w = fi.write().unwrap();
-                w.a_hash = Some(ahash.to_base64());
-            }
-            tx.safe_send(fi);
-        }
-    });
+// Content that is already in `known` (under another name) keeps the image hashes
+// that were computed for it, and is only decoded if some of `needs` are missing.
+fn hash_contents(
+    filename: PathBuf,
+    contents: FileContents,
+    needs: &HashNeeds,
+    known: &Sha2Index,
+    decoder: &Decoder,
+) -> Result<FileInfo> {
+    let FileContents {
+        stamp,
+        sha2_hash,
+        buf,
+        ..
+    } = contents;
+    let mut fi = match known.get(&sha2_hash) {
+        Some(existing) => FileInfo {
+            filename,
+            sha2_hash,
+            stamp,
+            ..existing
+        },
+        None => FileInfo {
+            filename,
+            sha2_hash,
+            stamp,
+            ..FileInfo::default()
+        },
+    };
 
-    handle
-}
+    let missing: Vec<_> = needs
+        .missing(&fi)
+        .into_iter()
+        .filter(|(spec, _)| spec.needs_decoding())
+        .collect();
+    let needs_features = needs.features && !fi.has_features();
+    let needs_dimensions = needs.dimensions && fi.pixels().is_none();
+    if missing.is_empty() && !needs_features && !needs_dimensions {
+        return Ok(fi);
+    }
 
-fn make_dhasher(
-    rx: Receiver<(FileInfoHandle, ImageHandle)>,
-    tx: SyncSender<FileInfoHandle>,
-) -> JoinHandle<()> {
-    let handle = spawn_with_name("dhasher", move || {
-        for (fi, image) in rx {
-            let dhash = ImageHash::hash(image.as_ref(), 8, HashType::Gradient);
-            {
-                let mut w = fi.write().unwrap();
-                w.d_hash = Some(dhash.to_base64());
+    let image = decoder.decode(&fi.filename, &buf)?;
+    let (width, height) = image.dimensions();
+    fi.width = width;
+    fi.height = height;
+    // Each transform of the image is only made once, for all of the hashes.
+    for transform in Transform::ALL.iter() {
+        let specs: Vec<_> = missing
+            .iter()
+            .filter(|(_, t)| t == transform)
+            .map(|(spec, _)| *spec)
+            .collect();
+        if specs.is_empty() {
+            continue;
+        }
+        let transformed;
+        let image = if *transform == Transform::Identity {
+            &image
+        } else {
+            transformed = transform_image(&image, *transform);
+            &transformed
+        };
+        for spec in specs {
+            if let Some(hash) = hash_image(image, spec) {
+                fi.set_transformed_hash(spec, *transform, hash);
             }
-            tx.safe_send(fi);
         }
-    });
-
-    handle
+    }
+    if needs_features {
+        set_features(&mut fi, &extract(&image));
+    }
+    Ok(fi)
 }
 
-fn make_phasher(
-    rx: Receiver<(FileInfoHandle, ImageHandle)>,
-    tx: SyncSender<FileInfoHandle>,
-) -> JoinHandle<()> {
-    let handle = spawn_with_name("phasher", move || {
-        for (fi, image) in rx {
-            let phash = ImageHash::hash(image.as_ref(), 8, HashType::DCT);
-            {
-                let mut w = fi.write().unwrap();
-                w.p_hash = Some(phash.to_base64());
-            }
-            tx.safe_send(fi);
-        }
-    });
+type WorkItem = (PathBuf, FileContents);
 
-    handle
-}
+// Reading stays on one thread, since the disk doesn't get any faster with more
+// readers. It waits for room in the budget before reading each file.
+fn make_file_reader(
+    files: Vec<PathBuf>,
+    queue_size: usize,
+    budget: Option<MemoryBudget>,
+    failures: FailuresHandle,
+) -> (Receiver<WorkItem>, JoinHandle<()>) {
+    let (tx, rx) = sync_channel(queue_size);
 
-fn make_aggregator(fi_rx: Receiver<FileInfoHandle>) -> (Receiver<FileInfo>, JoinHandle<()>) {
-    let (tx, rx) = sync_channel(0);
-
-    let handle = spawn_with_name("aggregator", move || {
-        for fi in fi_rx {
-            let fi_complete = fi.read().map(|f| f.is_complete()).unwrap_or(false);
-            if fi_complete {
-                // try_unwrap may fail if all of the senders populated the FileInfo,
-                // but the Arc hasn't yet been dropped. Because the Arc is dropped on
-                // calling send(), this means that more messages will be arriving for
-                // this FileInfo, so we can safely do nothing and move on.
-                let _ = Arc::try_unwrap(fi).map(|rw_lock| {
-                    let fic = rw_lock.into_inner().unwrap();
-                    tx.safe_send(fic.into());
-                });
+    let handle = spawn_with_name("file_reader", move || {
+        for file in files {
+            match read_file(&file, budget.as_ref()) {
+                Ok(contents) => tx.safe_send((file, contents)),
+                Err(err) => {
+                    let mut failure = HashFailure::new(file, err);
+                    failure.stamp = FileStamp::from_path(&failure.path).ok();
+                    failures.lock().unwrap().push(failure);
+                }
             }
         }
     });
 
     (rx, handle)
 }
+
+fn make_worker(
+    index: usize,
+    work_rx: Arc<Mutex<Receiver<WorkItem>>>,
+    needs: Arc<HashNeeds>,
+    known: Arc<Sha2Index>,
+    decoder: Arc<Decoder>,
+    tx: SyncSender<FileInfo>,
+    failures: FailuresHandle,
+) -> JoinHandle<()> {
+    spawn_with_name(format!("hasher_{}", index), move || loop {
+        // Only hold the lock while waiting for the next file, so that the other
+        // workers can pick up files while this one is hashing.
+        let item = work_rx.lock().unwrap().recv();
+        let (filename, contents) = match item {
+            Ok(item) => item,
+            Err(_) => break,
+        };
+        let stamp = contents.stamp;
+        match hash_contents(filename.clone(), contents, &needs, &known, &decoder) {
+            Ok(fi) => tx.safe_send(fi),
+            Err(err) => failures
+                .lock()
+                .unwrap()
+                .push(HashFailure::new(filename, err).with_stamp(stamp)),
+        }
+    })
+}
@@ -0,0 +1,163 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use super::cache_format::{read_record, write_record};
+use super::fileinfo::FileInfo;
+use super::pcache::HashTable;
+use super::result::{ItoolsError, Result};
+use super::utils::path_with_suffix;
+
+// An append-only log of the FileInfos that have been added to the cache since
+// the cache file was last written. Records use the binary cache record encoding,
+// whatever format the cache file itself is in.
+const MAGIC: &[u8; 8] = b"NDUPSJNL";
+
+#[derive(Debug)]
+pub struct Journal {
+    file: File,
+    records: usize,
+}
+
+impl Journal {
+    // Journals live next to the cache file that they belong to.
+    pub fn path_for(cache_file: &Path) -> PathBuf {
+        path_with_suffix(cache_file, ".journal")
+    }
+
+    // Create an empty journal, throwing away anything that was already there.
+    // Only do this once the existing records have been replayed and saved.
+    pub fn create(path: &Path) -> Result<Journal> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)?;
+        let mut journal = Journal { file, records: 0 };
+        journal.file.write_all(MAGIC)?;
+        Ok(journal)
+    }
+
+    pub fn len(&self) -> usize {
+        self.records
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records == 0
+    }
+
+    // Each record goes straight to the OS (no buffering), so if the process dies,
+    // at most the record being written is lost.
+    pub fn append(&mut self, fi: &FileInfo) -> Result<()> {
+        let mut buf = Vec::new();
+        write_record(&mut buf, fi)?;
+        self.file.write_all(&buf)?;
+        self.records += 1;
+        Ok(())
+    }
+
+    // Drop all of the records. Call this after they have been compacted into the
+    // cache file.
+    pub fn reset(&mut self) -> Result<()> {
+        self.file.set_len(MAGIC.len() as u64)?;
+        self.file.seek(SeekFrom::End(0))?;
+        self.records = 0;
+        Ok(())
+    }
+
+    // Apply the records in the journal at `path` to the table, returning how many
+    // were applied. A record that was cut off part way through (because we died
+    // while writing it) is ignored.
+    pub fn replay(path: &Path, table: &mut HashTable) -> Result<usize> {
+        let mut reader = BufReader::new(File::open(path)?);
+
+        let mut magic = [0u8; 8];
+        match reader.read_exact(&mut magic) {
+            // Died before the header made it out. There's nothing to replay.
+            Err(ref err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(0),
+            other => other?,
+        }
+        if &magic != MAGIC {
+            return Err(ItoolsError::CorruptCache("not a cache journal"));
+        }
+
+        let mut count = 0;
+        loop {
+            match read_record(&mut reader) {
+                Ok(fi) => {
+                    table.insert(fi.filename.clone(), fi);
+                    count += 1;
+                }
+                Err(ItoolsError::IO(ref err)) if err.kind() == io::ErrorKind::UnexpectedEof => {
+                    break
+                }
+                Err(err) => return Err(err),
+            }
+        }
+        Ok(count)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs::{self, OpenOptions};
+    use std::io::Write;
+    use std::path::PathBuf;
+
+    use super::super::fileinfo::FileInfo;
+    use super::super::pcache::HashTable;
+    use super::Journal;
+
+    fn make_fileinfo(name: &str) -> FileInfo {
+        FileInfo {
+            filename: PathBuf::from(name),
+            a_hash: "AAECAwQFBgc=".into(),
+            d_hash: "CAkKCwwNDg8=".into(),
+            p_hash: "EBESExQVFhc=".into(),
+            sha2_hash: "47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=".into(),
+            ..FileInfo::default()
+        }
+    }
+
+    #[test]
+    fn test_replay_ignores_partial_record() {
+        let path = env::temp_dir().join("itools_test_replay_ignores_partial_record");
+        {
+            let mut journal = Journal::create(&path).unwrap();
+            journal.append(&make_fileinfo("one.jpg")).unwrap();
+            journal.append(&make_fileinfo("two.jpg")).unwrap();
+            assert_eq!(2, journal.len());
+        }
+        // Simulate dying part way through the third record.
+        OpenOptions::new()
+            .append(true)
+            .open(&path)
+            .unwrap()
+            .write_all(&[40, 0, 0, 0, 1, 2])
+            .unwrap();
+
+        let mut table = HashTable::new();
+        assert_eq!(2, Journal::replay(&path, &mut table).unwrap());
+        assert!(table.contains_key(&PathBuf::from("one.jpg")));
+        assert!(table.contains_key(&PathBuf::from("two.jpg")));
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_reset() {
+        let path = env::temp_dir().join("itools_test_journal_reset");
+        let mut journal = Journal::create(&path).unwrap();
+        journal.append(&make_fileinfo("one.jpg")).unwrap();
+        journal.reset().unwrap();
+        assert!(journal.is_empty());
+        journal.append(&make_fileinfo("two.jpg")).unwrap();
+
+        let mut table = HashTable::new();
+        assert_eq!(1, Journal::replay(&path, &mut table).unwrap());
+        assert!(table.contains_key(&PathBuf::from("two.jpg")));
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,101 @@
+use std::sync::{Arc, Condvar, Mutex};
+
+// Limits the total size of the file contents held in memory by the hasher. The
+// reader reserves space for each file before reading it, and the space is given
+// back when the file's reservation is dropped, after it has been hashed.
+#[derive(Clone, Debug)]
+pub struct MemoryBudget {
+    limit: u64,
+    in_use: Arc<(Mutex<u64>, Condvar)>,
+}
+
+#[derive(Debug)]
+pub struct Reservation {
+    bytes: u64,
+    in_use: Arc<(Mutex<u64>, Condvar)>,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: u64) -> MemoryBudget {
+        MemoryBudget {
+            limit,
+            in_use: Arc::default(),
+        }
+    }
+
+    // Blocks until `bytes` fit in the budget. A file that is bigger than the whole
+    // budget is let through once nothing else is in memory, so that it still gets
+    // hashed.
+    pub fn reserve(&self, bytes: u64) -> Reservation {
+        let (ref lock, ref cvar) = *self.in_use;
+        let mut in_use = lock.lock().unwrap();
+        while *in_use > 0 && *in_use + bytes > self.limit {
+            in_use = cvar.wait(in_use).unwrap();
+        }
+        *in_use += bytes;
+        Reservation {
+            bytes,
+            in_use: Arc::clone(&self.in_use),
+        }
+    }
+
+    pub fn in_use(&self) -> u64 {
+        *self.in_use.0.lock().unwrap()
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        let (ref lock, ref cvar) = *self.in_use;
+        *lock.lock().unwrap() -= self.bytes;
+        cvar.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::mpsc::channel;
+    use std::thread;
+    use std::time::Duration;
+
+    use super::MemoryBudget;
+
+    #[test]
+    fn test_reservations() {
+        let budget = MemoryBudget::new(100);
+        let first = budget.reserve(40);
+        let second = budget.reserve(60);
+        assert_eq!(100, budget.in_use());
+
+        drop(first);
+        assert_eq!(60, budget.in_use());
+        drop(second);
+        assert_eq!(0, budget.in_use());
+
+        // Too big for the budget, but nothing else is in use.
+        let huge = budget.reserve(1000);
+        assert_eq!(1000, budget.in_use());
+        drop(huge);
+    }
+
+    #[test]
+    fn test_reserve_waits_for_space() {
+        let budget = MemoryBudget::new(100);
+        let first = budget.reserve(60);
+
+        let (tx, rx) = channel();
+        let other = budget.clone();
+        let handle = thread::spawn(move || {
+            let _second = other.reserve(60);
+            tx.send(()).unwrap();
+        });
+
+        thread::sleep(Duration::from_millis(50));
+        assert!(rx.try_recv().is_err());
+
+        drop(first);
+        rx.recv().unwrap();
+        handle.join().unwrap();
+        assert_eq!(0, budget.in_use());
+    }
+}
@@ -1,23 +1,49 @@
+mod cache_cmd;
+mod cache_format;
+mod combined;
 mod config;
+mod decoder;
+mod failures;
+mod features;
+mod file_store;
 mod fileinfo;
 mod hasher;
+mod journal;
+mod memory_budget;
 pub mod output;
 mod pcache;
+mod perceptual;
 mod progress;
 mod result;
 mod search;
+mod size_filter;
+mod sniff;
 mod spinner_reader;
+mod sqlite_store;
+mod store;
 mod utils;
+mod walk_filter;
 mod walker;
 
+pub use self::cache_cmd::{CacheCommand, ExportFormat};
+pub use self::cache_format::CacheFormat;
 pub use self::config::Config;
+pub use self::decoder::Decoder;
+pub use self::failures::{print_summary, write_failures, HashFailure};
+pub use self::file_store::FileStore;
 
 // pub use fileinfo::FileInfo;
+pub use self::fileinfo::{HashKind, HashNeeds, HashSpec};
 pub use self::hasher::Hasher;
 pub use self::output::Output;
 pub use self::pcache::PersistedCache;
 pub use self::progress::new_counter;
 pub use self::result::{ItoolsError, Result};
+pub use self::size_filter::SizeFilter;
 pub use self::spinner_reader::SpinnerReader;
+pub use self::sqlite_store::SqliteStore;
+pub use self::store::{CacheStore, LibraryRoot, StoreKind};
 pub use self::utils::bool_to_option;
-pub use self::walker::expand_file_list;
+pub use self::walker::{
+    expand_file_list, print_walk_errors, read_file_list, FileList, WalkOptions,
+};
@@ -1,6 +1,12 @@
+use std::path::PathBuf;
+
 use subprocess::{Popen, PopenConfig};
 
-use super::search::Matches;
+use super::search::{MatchedFile, Matches};
+
+pub fn new_json_output() -> DynamicOutput {
+    DynamicOutput::Json(JsonOutput::default())
+}
 
 pub fn new_no_output() -> DynamicOutput {
     DynamicOutput::None(NoOutput::default())
@@ -18,12 +24,22 @@ pub fn new_yaml_output() -> DynamicOutput {
     DynamicOutput::Yaml(YamlOutput::default())
 }
 
+// Everything that a run has to say. The structured outputs write all of it, so that
+// scripts can tell when some of their inputs weren't there.
+#[derive(Debug, Default, Serialize)]
+pub struct Report {
+    pub matches: Vec<Matches>,
+    // Input paths that don't exist.
+    pub missing: Vec<PathBuf>,
+}
+
 pub trait Output {
-    fn output(&self, matches: Vec<Matches>);
+    fn output(&self, report: Report);
 }
 
 #[derive(Debug)]
 pub enum DynamicOutput {
+    Json(JsonOutput),
     None(NoOutput),
     Open(OpenOutput),
     Text(TextOutput),
@@ -31,13 +47,14 @@ pub enum DynamicOutput {
 }
 
 impl Output for DynamicOutput {
-    fn output(&self, matches: Vec<Matches>) {
+    fn output(&self, report: Report) {
         use self::DynamicOutput::*;
         match self {
-            None(no) => no.output(matches),
-            Open(oo) => oo.output(matches),
-            Text(to) => to.output(matches),
-            Yaml(yo) => yo.output(matches),
+            Json(jo) => jo.output(report),
+            None(no) => no.output(report),
+            Open(oo) => oo.output(report),
+            Text(to) => to.output(report),
+            Yaml(yo) => yo.output(report),
         };
     }
 }
@@ -51,41 +68,60 @@ impl Default for DynamicOutput {
 #[derive(Debug, Default)]
 pub struct TextOutput();
 
+// Missing files were already reported on stderr, so they aren't repeated here.
 impl Output for TextOutput {
-    fn output(&self, matches: Vec<Matches>) {
-        for mtch in matches {
+    fn output(&self, report: Report) {
+        for mtch in report.matches {
             let filename = mtch.filename;
             println!("{}", filename.to_string_lossy());
 
             let matched_files = mtch
                 .matched_files
                 .into_iter()
-                .filter(|fnm| *fnm != filename);
+                .filter(|mf| mf.path != filename);
             for mf in matched_files {
-                println!("   {}", mf.to_string_lossy());
+                println!(
+                    "   {} ({}{}% similar)",
+                    mf.path.to_string_lossy(),
+                    distance_text(&mf),
+                    mf.similarity
+                );
             }
         }
     }
 }
 
+// "distance 3, " for a single distance, and "distances 2/5, " for the combined
+// hashes.
+fn distance_text(mf: &MatchedFile) -> String {
+    match mf.distance {
+        Some(distance) => format!("distance {}, ", distance),
+        None if !mf.distances.is_empty() => {
+            let distances: Vec<String> = mf.distances.iter().map(u64::to_string).collect();
+            format!("distances {}, ", distances.join("/"))
+        }
+        None => String::new(),
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct NoOutput();
 
 impl Output for NoOutput {
-    fn output(&self, _matches: Vec<Matches>) {}
+    fn output(&self, _report: Report) {}
 }
 
 #[derive(Debug, Default)]
 pub struct OpenOutput();
 
 impl Output for OpenOutput {
-    fn output(&self, matches: Vec<Matches>) {
-        for mtch in matches {
+    fn output(&self, report: Report) {
+        for mtch in report.matches {
             // TODO: see if you can shorten this ridiculous line.
             let mut filenames = mtch
                 .matched_files
                 .into_iter()
-                .map(|f| f.to_string_lossy().into_owned().to_string())
+                .map(|f| f.path.to_string_lossy().into_owned().to_string())
                 .collect();
             let mut args = vec!["open".to_string()];
             args.append(&mut filenames);
@@ -98,7 +134,17 @@ impl Output for OpenOutput {
 pub struct YamlOutput();
 
 impl Output for YamlOutput {
-    fn output(&self, matches: Vec<Matches>) {
-        serde_yaml::to_writer(std::io::stdout(), &matches).unwrap();
+    fn output(&self, report: Report) {
+        serde_yaml::to_writer(std::io::stdout(), &report).unwrap();
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct JsonOutput();
+
+impl Output for JsonOutput {
+    fn output(&self, report: Report) {
+        serde_json::to_writer_pretty(std::io::stdout(), &report).unwrap();
+        println!();
     }
 }
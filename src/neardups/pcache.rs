@@ -1,123 +1,422 @@
-use std::collections::HashMap;
-use std::fs::File;
-use std::io::Read;
-use std::path::{Path, PathBuf};
-use std::sync::mpsc::{channel, Receiver};
-use std::sync::{Arc, RwLock};
+use std::collections::{HashMap, HashSet};
+use std::path::{Component, Path, PathBuf};
+use std::sync::mpsc::Receiver;
+use std::sync::{Arc, Mutex, RwLock};
 use std::thread::JoinHandle;
-use std::time::Instant;
 
 use indicatif::ProgressBar;
 
-use super::fileinfo::FileInfo;
+use super::failures::{FailedFile, HashFailure};
+use super::fileinfo::{FileInfo, FileStamp, HashNeeds};
 use super::progress::Progress;
 use super::result::Result;
-use super::utils::{spawn_with_name, SafeSend};
+use super::store::CacheStore;
+use super::utils::spawn_with_name;
 
-type HashTable = HashMap<PathBuf, FileInfo>;
+pub type HashTable = HashMap<PathBuf, FileInfo>;
 type HashHandle = Arc<RwLock<HashTable>>;
+type StoreHandle = Arc<Mutex<Box<CacheStore>>>;
 
 // TODO: This file is in desperate need of some cleanup and error handling.
 // (Too many unwraps().)
 
-#[derive(Debug, Default)]
+#[derive(Debug)]
 pub struct PersistedCache {
     cache: HashHandle,
+    store: StoreHandle,
+    failures: HashMap<PathBuf, FailedFile>,
 
     listen_handle: Option<JoinHandle<()>>,
-    save_handle: Option<JoinHandle<()>>,
 }
 
 impl PersistedCache {
-    // Create an empty PersistedCache.
-    pub fn new() -> PersistedCache {
-        PersistedCache::default()
-    }
-
-    pub fn from_reader<T>(reader: T) -> Result<PersistedCache>
-    where
-        T: Read,
-    {
-        let hash = Self::read_hash(reader)?;
+    // Load a PersistedCache from the store. All changes to the cache are passed on
+    // to the store.
+    pub fn load(mut store: Box<CacheStore>) -> Result<PersistedCache> {
+        let loaded = store.load()?;
+        let hash = canonicalize_keys(loaded, store.as_mut())?;
+        let failures = store
+            .load_failures()?
+            .into_iter()
+            .map(|failed| (failed.path.clone(), failed))
+            .collect();
         Ok(PersistedCache {
             cache: Arc::new(RwLock::new(hash)),
-            ..PersistedCache::default()
+            store: Arc::new(Mutex::new(store)),
+            failures,
+            listen_handle: None,
         })
     }
 
-    // Load a PersistedCache from the specified filename.
-    pub fn load(filename: &Path) -> Result<PersistedCache> {
-        let file = File::open(filename)?;
-        PersistedCache::from_reader(&file)
-    }
-
-    pub fn run<T>(&mut self, filename: T, rx: Receiver<FileInfo>, pb: Option<ProgressBar>)
-    where
-        T: Into<PathBuf>,
-    {
+    pub fn run(&mut self, rx: Receiver<FileInfo>, pb: Option<ProgressBar>) {
         let cache = Arc::clone(&self.cache);
-        let cache2 = Arc::clone(&self.cache);
-
-        // Send true to indicate some change that was made.
-        let (ltx, lrx) = channel::<bool>();
+        let store = Arc::clone(&self.store);
 
         let handle = spawn_with_name("pcache_adder", move || {
+            // A store that can't be written to (a full disk, say) fails at every
+            // checkpoint, so only the first failure is reported.
+            let mut checkpoint_failed = false;
+            // Only looked for once there is something to adopt them.
+            let mut orphans = None;
             for fi in rx {
+                let orphans = orphans.get_or_insert_with(|| find_orphans(&cache.read().unwrap()));
+
+                // Lock the store first, so that it can't checkpoint between the
+                // entry going into the table and it being passed to the store.
+                let mut store = store.lock().unwrap();
+                let adopted = orphans.get_mut(&fi.sha2_hash).and_then(Vec::pop);
+                if let Some(old_filename) = adopted {
+                    cache.write().unwrap().remove(&old_filename);
+                    if let Err(err) = store.remove(&old_filename) {
+                        eprintln!("Error removing {:?} from cache: {:?}", old_filename, err);
+                    }
+                }
+                if let Err(err) = store.insert(&fi) {
+                    eprintln!("Error saving {:?} to cache: {:?}", fi.filename, err);
+                }
+
                 let key = fi.filename.clone();
                 cache.write().unwrap().insert(key, fi);
-                ltx.safe_send(true);
-            }
-        });
-
-        let owned_filename: PathBuf = filename.into();
-        let save_handle = spawn_with_name("pcache_saver", move || {
-            let mut last_save_time = Instant::now();
-            for _ in lrx {
-                pb.inc();
-                // Every 15 seconds.
-                let elapsed = last_save_time.elapsed();
-                if elapsed.as_secs() >= 15 {
-                    // TODO: You need to write this atomically.
-                    Self::write_hash_to_file(&owned_filename, &cache2).unwrap();
-                    last_save_time = Instant::now();
+                // Checkpointing can mean writing out the whole table, so only hold a
+                // read lock while doing it.
+                if let Err(err) = store.checkpoint(&cache.read().unwrap(), false) {
+                    if !checkpoint_failed {
+                        eprintln!("Error saving cache: {:?}", err);
+                    }
+                    checkpoint_failed = true;
                 }
+                pb.inc();
+            }
+            let result = store
+                .lock()
+                .unwrap()
+                .checkpoint(&cache.read().unwrap(), true);
+            if let Err(err) = result {
+                eprintln!("Error saving cache: {:?}", err);
             }
-            Self::write_hash_to_file(&owned_filename, &cache2).unwrap();
         });
 
         self.listen_handle = Some(handle);
-        self.save_handle = Some(save_handle);
     }
 
     pub fn contains_file(&self, path: &Path) -> bool {
         self.cache.read().unwrap().contains_key(path)
     }
 
-    fn read_hash<T>(rdr: T) -> Result<HashTable>
-    where
-        T: Read,
-    {
-        let hash_table = serde_yaml::from_reader(rdr)?;
-        Ok(hash_table)
+    // True if there is an entry for the path with all of the hashes in `needs`, and
+    // the file on disk still has the same size, mtime, and (on Unix) device/inode
+    // that it had when it was hashed. Files that have been edited or replaced in
+    // place need to be re-hashed.
+    pub fn contains_current_file(&self, path: &Path, needs: &HashNeeds) -> bool {
+        match self.cache.read().unwrap().get(path) {
+            Some(fi) => {
+                needs.are_met_by(fi)
+                    && FileStamp::from_path(path)
+                        .map(|stamp| stamp == fi.stamp)
+                        .unwrap_or(false)
+            }
+            None => false,
+        }
+    }
+
+    // Files can only have identical contents if they are the same size, so a file
+    // whose size doesn't match any other file (or cache entry) doesn't need to be
+    // hashed for an exact search. Returns the files that do, and the number that
+    // were dropped.
+    pub fn files_with_shared_sizes(&self, files: Vec<PathBuf>) -> (Vec<PathBuf>, usize) {
+        let mut by_size: HashMap<u64, HashSet<PathBuf>> = HashMap::new();
+        for fi in self.cache.read().unwrap().values() {
+            by_size
+                .entry(fi.stamp.size)
+                .or_insert_with(HashSet::new)
+                .insert(fi.filename.clone());
+        }
+        let sizes: Vec<Option<u64>> = files
+            .iter()
+            .map(|f| FileStamp::from_path(f).ok().map(|stamp| stamp.size))
+            .collect();
+        for (file, size) in files.iter().zip(&sizes) {
+            if let Some(size) = *size {
+                by_size
+                    .entry(size)
+                    .or_insert_with(HashSet::new)
+                    .insert(file.clone());
+            }
+        }
+
+        let before = files.len();
+        let remaining: Vec<PathBuf> = files
+            .into_iter()
+            .zip(sizes)
+            .filter(|(_, size)| match *size {
+                Some(size) => by_size[&size].len() > 1,
+                // Let the hasher report the problem.
+                None => true,
+            })
+            .map(|(file, _)| file)
+            .collect();
+        let dropped = before - remaining.len();
+        (remaining, dropped)
+    }
+
+    // True if the file failed to hash before, and hasn't changed since.
+    pub fn contains_failed_file(&self, path: &Path) -> bool {
+        match self.failures.get(path) {
+            Some(failed) => FileStamp::from_path(path)
+                .map(|stamp| stamp == failed.stamp)
+                .unwrap_or(false),
+            None => false,
+        }
     }
 
-    fn write_hash_to_file<T>(filename: T, handle: &HashHandle) -> Result<()>
-    where
-        T: AsRef<Path>,
-    {
-        // This fails if handle is poisoned. That would be a programmer error, so
-        // we want to panic.
-        let hashmap = &*handle.read().unwrap();
+    // Forget the old failures for every file that was attempted, and remember the
+    // new ones.
+    pub fn record_failures(
+        &mut self,
+        attempted: &[PathBuf],
+        failures: &[HashFailure],
+    ) -> Result<()> {
+        let mut changed = !failures.is_empty();
+        for path in attempted {
+            changed |= self.failures.remove(path).is_some();
+        }
+        if !changed {
+            return Ok(());
+        }
+
+        for failed in failures.iter().filter_map(FailedFile::from_failure) {
+            self.failures.insert(failed.path.clone(), failed);
+        }
+        let mut failed: Vec<FailedFile> = self.failures.values().cloned().collect();
+        failed.sort_by(|a, b| a.path.cmp(&b.path));
+        self.store.lock().unwrap().save_failures(&failed)
+    }
 
-        let f = File::create(filename)?;
-        serde_yaml::to_writer(f, hashmap).unwrap();
-        Ok(())
+    // All of the entries, by SHA-256, so that the hasher can skip decoding files
+    // whose content we have already seen (under another name).
+    pub fn sha2_index(&self) -> Sha2Index {
+        let paths = self
+            .cache
+            .read()
+            .unwrap()
+            .values()
+            .filter(|fi| !fi.sha2_hash.is_empty())
+            .map(|fi| (fi.sha2_hash.clone(), fi.filename.clone()))
+            .collect();
+        Sha2Index {
+            paths,
+            cache: Arc::clone(&self.cache),
+        }
     }
 
-    pub fn join(self) -> HashMap<PathBuf, FileInfo> {
+    // Returns the entries, along with the store (which may be able to answer
+    // queries about them).
+    pub fn join(self) -> (HashTable, Box<CacheStore>) {
         self.listen_handle.map(|lh| lh.join());
-        self.save_handle.map(|sh| sh.join());
 
-        Arc::try_unwrap(self.cache).unwrap().into_inner().unwrap()
+        let table = Arc::try_unwrap(self.cache).unwrap().into_inner().unwrap();
+        let store = Arc::try_unwrap(self.store).unwrap().into_inner().unwrap();
+        (table, store)
+    }
+}
+
+// Finds cached entries by SHA-256. Entries can be big (features, and the hashes
+// of every transform), so only their names are indexed, and the entries
+// themselves are looked up in the cache's table when they are needed.
+#[derive(Clone, Debug, Default)]
+pub struct Sha2Index {
+    paths: HashMap<String, PathBuf>,
+    cache: HashHandle,
+}
+
+impl Sha2Index {
+    // None if the entry has been dropped from the cache since the index was made
+    // (when its file was adopted under a new name, say).
+    pub fn get(&self, sha2_hash: &str) -> Option<FileInfo> {
+        let path = self.paths.get(sha2_hash)?;
+        self.cache.read().unwrap().get(path).cloned()
+    }
+}
+
+// Files that have been moved or renamed since they were hashed don't match their
+// old entries by name. The hasher reuses an old entry's hashes for a file with the
+// same SHA-256 (without decoding it), and the entry whose file is gone is dropped
+// when the file under its new name arrives. Returns the entries whose files are
+// gone, by SHA-256. Copies of the same file can be moved together, so each file
+// that arrives adopts one of them.
+fn find_orphans(table: &HashTable) -> HashMap<String, Vec<PathBuf>> {
+    let mut orphans = HashMap::new();
+    for fi in table.values() {
+        if !fi.sha2_hash.is_empty() && !fi.filename.exists() {
+            orphans
+                .entry(fi.sha2_hash.clone())
+                .or_insert_with(Vec::new)
+                .push(fi.filename.clone());
+        }
+    }
+    orphans
+}
+
+// Inputs are canonicalized, but caches written before they were hold keys as they
+// were given on the command line (some of them relative). Those keys would never
+// match again, and would show up next to the canonical entries in the search, so
+// move them to their canonical names. If there is already an entry under that
+// name, it is the newer one, and the old entry is dropped. Entries for missing
+// files are left alone, to be adopted when their files turn up elsewhere (see
+// find_orphans), or dropped by `cache prune`.
+//
+// Only the keys that can't be canonical are looked at, so that loading a cache
+// doesn't touch every file in it.
+fn canonicalize_keys(table: HashTable, store: &mut CacheStore) -> Result<HashTable> {
+    let mut canonical = HashTable::with_capacity(table.len());
+    let mut moved = Vec::new();
+    for (key, fi) in table {
+        if is_plainly_canonical(&key) {
+            canonical.insert(key, fi);
+            continue;
+        }
+        match key.canonicalize() {
+            Ok(path) if path != key => moved.push((key, path, fi)),
+            _ => {
+                canonical.insert(key, fi);
+            }
+        }
+    }
+    if moved.is_empty() {
+        return Ok(canonical);
+    }
+
+    for (old_key, path, mut fi) in moved {
+        store.remove(&old_key)?;
+        if canonical.contains_key(&path) {
+            continue;
+        }
+        fi.filename = path.clone();
+        store.insert(&fi)?;
+        canonical.insert(path, fi);
+    }
+    store.checkpoint(&canonical, false)?;
+    Ok(canonical)
+}
+
+// Absolute, without any "." or ".." in it. (A key that goes through a symlink
+// isn't canonical either, but there is no telling without asking the filesystem.)
+fn is_plainly_canonical(path: &Path) -> bool {
+    path.is_absolute()
+        && path.components().all(|c| match c {
+            Component::CurDir | Component::ParentDir => false,
+            _ => true,
+        })
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+
+    use super::super::fileinfo::FileInfo;
+    use super::super::sqlite_store::SqliteStore;
+    use super::super::store::CacheStore;
+    use super::{find_orphans, is_plainly_canonical, HashTable, PersistedCache};
+
+    fn entry(path: &Path, sha2: &str) -> FileInfo {
+        FileInfo {
+            filename: path.to_path_buf(),
+            sha2_hash: sha2.into(),
+            ..FileInfo::default()
+        }
+    }
+
+    #[test]
+    fn test_keys_are_canonicalized() {
+        let dir = env::temp_dir().join("itools_test_keys_are_canonicalized");
+        fs::create_dir_all(&dir).unwrap();
+        let dir = dir.canonicalize().unwrap();
+        // Tests run in the crate's directory, so this is a relative key for a file
+        // that exists.
+        let a = Path::new("src/lib.rs").canonicalize().unwrap();
+        let b = dir.join("b.jpg");
+        fs::write(&b, "b").unwrap();
+        fs::create_dir_all(dir.join("x")).unwrap();
+        let missing = dir.join("sub").join("..").join("missing.jpg");
+
+        let mut store = SqliteStore::open(Path::new(":memory:")).unwrap();
+        // An old entry for a, which only needs a new key.
+        store
+            .insert(&entry(Path::new("src/lib.rs"), "old a"))
+            .unwrap();
+        // An old entry for b, and a newer one under the canonical name.
+        store
+            .insert(&entry(&dir.join("x").join("..").join("b.jpg"), "old b"))
+            .unwrap();
+        store.insert(&entry(&b, "new b")).unwrap();
+        store.insert(&entry(&missing, "missing")).unwrap();
+
+        let cache = PersistedCache::load(Box::new(store)).unwrap();
+        let (table, mut store) = cache.join();
+        assert_eq!(3, table.len());
+        assert_eq!("old a", table[&a].sha2_hash);
+        assert_eq!(a, table[&a].filename);
+        assert_eq!("new b", table[&b].sha2_hash);
+        assert!(table.contains_key(&missing));
+
+        // The store was changed to match.
+        let reloaded = store.load().unwrap();
+        assert_eq!(3, reloaded.len());
+        assert_eq!("old a", reloaded[&a].sha2_hash);
+        assert_eq!("new b", reloaded[&b].sha2_hash);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_sha2_index() {
+        let mut store = SqliteStore::open(Path::new(":memory:")).unwrap();
+        let mut fi = entry(Path::new("/photos/a.jpg"), "a");
+        fi.hashes.insert("wavelet16".into(), "AAECAwQFBgc=".into());
+        store.insert(&fi).unwrap();
+        store
+            .insert(&entry(Path::new("/photos/b.jpg"), ""))
+            .unwrap();
+
+        let cache = PersistedCache::load(Box::new(store)).unwrap();
+        let index = cache.sha2_index();
+        assert_eq!(fi.hashes, index.get("a").unwrap().hashes);
+        assert!(index.get("").is_none());
+
+        // Entries that leave the cache leave the index too.
+        cache.cache.write().unwrap().clear();
+        assert!(index.get("a").is_none());
+    }
+
+    #[test]
+    fn test_is_plainly_canonical() {
+        assert!(is_plainly_canonical(Path::new("/photos/a.jpg")));
+        assert!(!is_plainly_canonical(Path::new("photos/a.jpg")));
+        assert!(!is_plainly_canonical(Path::new("/photos/../a.jpg")));
+        assert!(!is_plainly_canonical(Path::new("./a.jpg")));
+    }
+
+    #[test]
+    fn test_find_orphans() {
+        let dir = env::temp_dir().join("itools_test_find_orphans");
+        fs::create_dir_all(&dir).unwrap();
+        let present = dir.join("present.jpg");
+        let gone = dir.join("gone.jpg");
+        let copy = dir.join("copy of gone.jpg");
+        fs::write(&present, "present").unwrap();
+
+        let table: HashTable = vec![entry(&present, "p"), entry(&gone, "g"), entry(&copy, "g")]
+            .into_iter()
+            .map(|fi| (fi.filename.clone(), fi))
+            .collect();
+        let orphans = find_orphans(&table);
+        assert_eq!(1, orphans.len());
+        let mut same_content = orphans["g"].clone();
+        same_content.sort();
+        assert_eq!(vec![copy, gone], same_content);
+
+        fs::remove_dir_all(&dir).unwrap();
     }
 }
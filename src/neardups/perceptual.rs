@@ -0,0 +1,298 @@
+use image::{DynamicImage, FilterType, GenericImage};
+use img_hash::{HashType, ImageHash};
+use serialize::base64::{FromBase64, ToBase64, STANDARD};
+
+use super::fileinfo::{HashKind, HashSpec, Transform};
+
+// The hashes that img_hash doesn't provide are computed here. All of them are
+// stored the way img_hash stores its own: as the base64 of the bits, packed most
+// significant bit first.
+
+// Returns None for the SHA-256, which isn't computed from the image.
+pub fn hash_image(image: &DynamicImage, spec: HashSpec) -> Option<String> {
+    let hash_type = match spec.kind {
+        HashKind::Mean => HashType::Mean,
+        HashKind::Grad => HashType::Gradient,
+        HashKind::Dct => HashType::DCT,
+        HashKind::Block => return Some(pack_bits(&block_bits(image, spec.size))),
+        HashKind::Wavelet => return Some(pack_bits(&wavelet_bits(image, spec.size))),
+        HashKind::Color => return Some(pack_bits(&color_bits(image, spec.size))),
+        HashKind::Sha2 => return None,
+    };
+    Some(ImageHash::hash(image, spec.size, hash_type).to_base64())
+}
+
+pub fn transform_image(image: &DynamicImage, transform: Transform) -> DynamicImage {
+    match transform {
+        Transform::Identity => image.clone(),
+        Transform::Rotate90 => image.rotate90(),
+        Transform::Rotate180 => image.rotate180(),
+        Transform::Rotate270 => image.rotate270(),
+        Transform::FlipHorizontal => image.fliph(),
+        Transform::FlipVertical => image.flipv(),
+        Transform::Transpose => image.rotate90().fliph(),
+        Transform::Transverse => image.rotate270().fliph(),
+    }
+}
+
+// A perceptual hash, for measuring Hamming distances.
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct BitHash(Vec<u8>);
+
+impl BitHash {
+    pub fn from_base64(encoded: &str) -> Option<BitHash> {
+        encoded.from_base64().ok().map(BitHash)
+    }
+
+    pub fn to_base64(&self) -> String {
+        self.0.to_base64(STANDARD)
+    }
+
+    // Hashes of different lengths shouldn't be compared, but if they are, the
+    // extra bits all count as different.
+    pub fn distance(&self, other: &BitHash) -> u64 {
+        let common: u64 = self
+            .0
+            .iter()
+            .zip(&other.0)
+            .map(|(a, b)| u64::from((a ^ b).count_ones()))
+            .sum();
+        let extra = (self.0.len() as i64 - other.0.len() as i64).abs() as u64;
+        common + extra * 8
+    }
+}
+
+// The mean brightness of each block in a size x size grid over the whole image,
+// compared to the median of the blocks (as in blockhash.io). Working from the full
+// image, instead of a thumbnail, makes it less sensitive to how it was scaled.
+fn block_bits(image: &DynamicImage, size: u32) -> Vec<bool> {
+    let (width, height) = image.dimensions();
+    let gray = if width < size || height < size {
+        image
+            .resize_exact(size, size, FilterType::Triangle)
+            .to_luma()
+    } else {
+        image.to_luma()
+    };
+    let (width, height) = gray.dimensions();
+
+    let mut means = Vec::with_capacity((size * size) as usize);
+    for by in 0..size {
+        let (y0, y1) = (by * height / size, (by + 1) * height / size);
+        for bx in 0..size {
+            let (x0, x1) = (bx * width / size, (bx + 1) * width / size);
+            let mut sum = 0u64;
+            for y in y0..y1 {
+                for x in x0..x1 {
+                    sum += u64::from(gray.get_pixel(x, y).data[0]);
+                }
+            }
+            means.push(sum as f64 / f64::from((x1 - x0) * (y1 - y0)));
+        }
+    }
+    above_median(&means)
+}
+
+// Number of Haar levels between the resized image and the hash.
+const WAVELET_LEVELS: u32 = 2;
+
+// The low-frequency (LL) band of a Haar wavelet transform of the image, compared
+// to its median (as in wHash). The mean is taken out first, which zeroes the very
+// lowest frequency, so that overall brightness doesn't matter.
+fn wavelet_bits(image: &DynamicImage, size: u32) -> Vec<bool> {
+    let mut side = size << WAVELET_LEVELS;
+    let gray = image
+        .resize_exact(side, side, FilterType::Triangle)
+        .to_luma();
+    let mut values: Vec<f64> = gray.pixels().map(|p| f64::from(p.data[0])).collect();
+    let mean = values.iter().sum::<f64>() / values.len() as f64;
+    for v in &mut values {
+        *v -= mean;
+    }
+
+    // The Haar approximation coefficients are the (scaled) sums of 2x2 blocks.
+    for _ in 0..WAVELET_LEVELS {
+        let half = side / 2;
+        let mut ll = Vec::with_capacity((half * half) as usize);
+        for y in 0..half {
+            for x in 0..half {
+                let at = |dx: u32, dy: u32| values[((2 * y + dy) * side + 2 * x + dx) as usize];
+                ll.push((at(0, 0) + at(1, 0) + at(0, 1) + at(1, 1)) / 2.0);
+            }
+        }
+        values = ll;
+        side = half;
+    }
+    above_median(&values)
+}
+
+// A mean hash of each of the luma and chroma (Y, Cb, Cr) channels of a size x size
+// thumbnail. Unlike the other hashes, images that only differ in their colours
+// don't match.
+fn color_bits(image: &DynamicImage, size: u32) -> Vec<bool> {
+    let rgb = image
+        .resize_exact(size, size, FilterType::Triangle)
+        .to_rgb();
+    let mut channels = vec![Vec::new(), Vec::new(), Vec::new()];
+    for pixel in rgb.pixels() {
+        let r = f64::from(pixel.data[0]);
+        let g = f64::from(pixel.data[1]);
+        let b = f64::from(pixel.data[2]);
+        channels[0].push(0.299 * r + 0.587 * g + 0.114 * b);
+        channels[1].push(-0.168_736 * r - 0.331_264 * g + 0.5 * b);
+        channels[2].push(0.5 * r - 0.418_688 * g - 0.081_312 * b);
+    }
+    channels
+        .iter()
+        .flat_map(|channel| above_median(channel))
+        .collect()
+}
+
+fn above_median(values: &[f64]) -> Vec<bool> {
+    let mut sorted = values.to_vec();
+    sorted.sort_by(|a, b| a.partial_cmp(b).unwrap());
+    let mid = sorted.len() / 2;
+    let median = if sorted.len() % 2 == 0 {
+        (sorted[mid - 1] + sorted[mid]) / 2.0
+    } else {
+        sorted[mid]
+    };
+    values.iter().map(|v| *v > median).collect()
+}
+
+fn pack_bits(bits: &[bool]) -> String {
+    let mut bytes = vec![0u8; (bits.len() + 7) / 8];
+    for (i, bit) in bits.iter().enumerate() {
+        if *bit {
+            bytes[i / 8] |= 0x80 >> (i % 8);
+        }
+    }
+    bytes.to_base64(STANDARD)
+}
+
+#[cfg(test)]
+mod test {
+    use image::{DynamicImage, Rgb, RgbImage};
+
+    use super::super::fileinfo::{HashKind, HashSpec, Transform};
+    use super::{
+        block_bits, color_bits, hash_image, pack_bits, transform_image, wavelet_bits, BitHash,
+    };
+
+    // Dark on the left, bright on the right.
+    fn split_image(left: [u8; 3], right: [u8; 3]) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(64, 48, |x, _| {
+            if x < 32 {
+                Rgb(left)
+            } else {
+                Rgb(right)
+            }
+        }))
+    }
+
+    fn left_dark_bits(size: usize) -> Vec<bool> {
+        (0..size * size).map(|i| i % size >= size / 2).collect()
+    }
+
+    #[test]
+    fn test_pack_bits() {
+        let bits = [true, false, false, false, false, false, false, true, true];
+        assert_eq!(pack_bits(&[]), "");
+        assert_eq!(
+            BitHash::from_base64(&pack_bits(&bits)).unwrap().0,
+            vec![0x81, 0x80]
+        );
+    }
+
+    #[test]
+    fn test_distance() {
+        let a = BitHash(vec![0b1010_1010, 0xff]);
+        let b = BitHash(vec![0b1010_1001, 0xff]);
+        assert_eq!(0, a.distance(&a));
+        assert_eq!(2, a.distance(&b));
+        assert_eq!(8, a.distance(&BitHash(vec![0b1010_1010, 0xff, 0x00])));
+    }
+
+    #[test]
+    fn test_block_and_wavelet() {
+        let image = split_image([10, 10, 10], [200, 200, 200]);
+        assert_eq!(left_dark_bits(8), block_bits(&image, 8));
+        assert_eq!(left_dark_bits(8), wavelet_bits(&image, 8));
+        assert_eq!(left_dark_bits(4), wavelet_bits(&image, 4));
+    }
+
+    #[test]
+    fn test_color_sees_colors() {
+        // Roughly the same brightness, so only the chroma differs.
+        let red_blue = split_image([200, 0, 0], [0, 0, 255]);
+        let blue_red = split_image([0, 0, 255], [200, 0, 0]);
+        let bits = color_bits(&red_blue, 8);
+        assert_eq!(3 * 64, bits.len());
+        assert_ne!(bits[64..], color_bits(&blue_red, 8)[64..]);
+    }
+
+    #[test]
+    fn test_transforms() {
+        // A 3x2 image with a different value in every pixel.
+        let image = DynamicImage::ImageRgb8(RgbImage::from_fn(3, 2, |x, y| {
+            Rgb([(y * 3 + x) as u8, 0, 0])
+        }));
+        let rows = |t| {
+            let rgb = transform_image(&image, t).to_rgb();
+            (0..rgb.height())
+                .map(|y| {
+                    (0..rgb.width())
+                        .map(|x| rgb.get_pixel(x, y).data[0])
+                        .collect::<Vec<u8>>()
+                })
+                .collect::<Vec<Vec<u8>>>()
+        };
+        assert_eq!(
+            vec![vec![0, 1, 2], vec![3, 4, 5]],
+            rows(Transform::Identity)
+        );
+        assert_eq!(
+            vec![vec![3, 0], vec![4, 1], vec![5, 2]],
+            rows(Transform::Rotate90)
+        );
+        assert_eq!(
+            vec![vec![5, 4, 3], vec![2, 1, 0]],
+            rows(Transform::Rotate180)
+        );
+        assert_eq!(
+            vec![vec![2, 5], vec![1, 4], vec![0, 3]],
+            rows(Transform::Rotate270)
+        );
+        assert_eq!(
+            vec![vec![2, 1, 0], vec![5, 4, 3]],
+            rows(Transform::FlipHorizontal)
+        );
+        assert_eq!(
+            vec![vec![3, 4, 5], vec![0, 1, 2]],
+            rows(Transform::FlipVertical)
+        );
+        assert_eq!(
+            vec![vec![0, 3], vec![1, 4], vec![2, 5]],
+            rows(Transform::Transpose)
+        );
+        assert_eq!(
+            vec![vec![5, 2], vec![4, 1], vec![3, 0]],
+            rows(Transform::Transverse)
+        );
+    }
+
+    #[test]
+    fn test_hash_sizes() {
+        let image = split_image([10, 10, 10], [200, 200, 200]);
+        let len = |kind, size| {
+            BitHash::from_base64(&hash_image(&image, HashSpec::new(kind, size)).unwrap())
+                .unwrap()
+                .0
+                .len()
+        };
+        assert_eq!(8, len(HashKind::Block, 8));
+        assert_eq!(32, len(HashKind::Wavelet, 16));
+        assert_eq!(24, len(HashKind::Color, 8));
+        assert_eq!(None, hash_image(&image, HashSpec::sha2()));
+    }
+}
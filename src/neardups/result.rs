@@ -1,30 +1,92 @@
+use std::fmt;
 use std::io;
 use std::result;
 
 use clap;
+use globset;
 use image;
+use rusqlite;
 use walkdir;
 
 pub type Result<T> = result::Result<T, ItoolsError>;
 
 #[derive(Debug)]
 pub enum ItoolsError {
+    CorruptCache(&'static str),
     InvalidState(&'static str),
+    MissingInputs(usize),
+    UnsupportedFormat(&'static str),
     UsageError(&'static str),
 
+    Converter(String),
+
     Clap(clap::Error),
+    Glob(globset::Error),
     Image(image::ImageError),
     IO(io::Error),
+    Json(serde_json::Error),
     Serde(serde_yaml::Error),
+    Sqlite(rusqlite::Error),
     WalkDir(walkdir::Error),
 }
 
+impl ItoolsError {
+    // A short, stable name for the kind of error, for reports.
+    pub fn kind(&self) -> &'static str {
+        use self::ItoolsError::*;
+        match *self {
+            CorruptCache(_) => "corrupt_cache",
+            InvalidState(_) => "invalid_state",
+            MissingInputs(_) => "missing",
+            UnsupportedFormat(_) => "unsupported",
+            UsageError(_) => "usage",
+            Converter(_) => "converter",
+            Clap(_) => "usage",
+            Glob(_) => "usage",
+            Image(_) => "decode",
+            IO(_) => "io",
+            Json(_) => "json",
+            Serde(_) => "yaml",
+            Sqlite(_) => "sqlite",
+            WalkDir(_) => "walk",
+        }
+    }
+}
+
+impl fmt::Display for ItoolsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ItoolsError::*;
+        match *self {
+            CorruptCache(msg) => write!(f, "corrupt cache: {}", msg),
+            InvalidState(msg) => write!(f, "invalid state: {}", msg),
+            MissingInputs(count) => write!(f, "{} input paths are missing", count),
+            UnsupportedFormat(msg) => write!(f, "{}", msg),
+            UsageError(msg) => write!(f, "{}", msg),
+            Converter(ref msg) => write!(f, "{}", msg),
+            Clap(ref err) => write!(f, "{}", err),
+            Glob(ref err) => write!(f, "{}", err),
+            Image(ref err) => write!(f, "{}", err),
+            IO(ref err) => write!(f, "{}", err),
+            Json(ref err) => write!(f, "{}", err),
+            Serde(ref err) => write!(f, "{}", err),
+            Sqlite(ref err) => write!(f, "{}", err),
+            WalkDir(ref err) => write!(f, "{}", err),
+        }
+    }
+}
+
 impl From<clap::Error> for ItoolsError {
     fn from(err: clap::Error) -> ItoolsError {
         ItoolsError::Clap(err)
     }
 }
 
+impl From<globset::Error> for ItoolsError {
+    fn from(err: globset::Error) -> ItoolsError {
+        ItoolsError::Glob(err)
+    }
+}
+
 impl From<image::ImageError> for ItoolsError {
     fn from(err: image::ImageError) -> ItoolsError {
         ItoolsError::Image(err)
@@ -37,12 +99,24 @@ impl From<io::Error> for ItoolsError {
     }
 }
 
+impl From<serde_json::Error> for ItoolsError {
+    fn from(err: serde_json::Error) -> ItoolsError {
+        ItoolsError::Json(err)
+    }
+}
+
 impl From<serde_yaml::Error> for ItoolsError {
     fn from(err: serde_yaml::Error) -> ItoolsError {
         ItoolsError::Serde(err)
     }
 }
 
+impl From<rusqlite::Error> for ItoolsError {
+    fn from(err: rusqlite::Error) -> ItoolsError {
+        ItoolsError::Sqlite(err)
+    }
+}
+
 impl From<walkdir::Error> for ItoolsError {
     fn from(err: walkdir::Error) -> ItoolsError {
         ItoolsError::WalkDir(err)
@@ -1,17 +1,29 @@
+use std::cmp::Ordering;
 use std::collections::HashMap;
 use std::path::PathBuf;
 
 use bk_tree::{BKTree, Metric};
-use img_hash::ImageHash;
 
-use super::fileinfo::FileInfo;
+use super::combined::CombinedSearch;
+use super::features::{candidate_pairs, features_of, match_features};
+use super::fileinfo::{FileInfo, HashNeeds, HashSpec, Transform};
+use super::perceptual::BitHash;
+use super::size_filter::SizeFilter;
+use super::store::CacheStore;
 
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum SearchType {
     SHA2,
-    MEAN(u8),
-    GRAD(u8),
-    DCT(u8),
+    // Files whose perceptual hashes are within a Hamming distance of each other.
+    PERCEPTUAL(HashSpec, u8),
+    // Like PERCEPTUAL, but a file also matches the rotated and flipped copies of
+    // itself.
+    DIHEDRAL(HashSpec, u8),
+    // Files that share enough local features, in the same arrangement. This finds
+    // crops, and copies with borders, that the hashes miss.
+    FEATURES,
+    // Files that are close by several perceptual hashes at once.
+    COMBINED(CombinedSearch),
 }
 
 impl Default for SearchType {
@@ -30,33 +42,120 @@ impl SearchType {
     fn distance(&self) -> u64 {
         use self::SearchType::*;
         match *self {
-            MEAN(d) => d as u64,
-            GRAD(d) => d as u64,
-            DCT(d) => d as u64,
-            SHA2 => 0u64,
+            PERCEPTUAL(_, d) => d as u64,
+            DIHEDRAL(_, d) => d as u64,
+            SHA2 | FEATURES | COMBINED(_) => 0u64,
         }
     }
 
-    fn get_hash<'a>(&self, fi: &'a FileInfo) -> &'a str {
+    fn hash_spec(&self) -> HashSpec {
         use self::SearchType::*;
         match *self {
-            SHA2 => &fi.sha2_hash,
-            MEAN(_) => &fi.a_hash,
-            GRAD(_) => &fi.d_hash,
-            DCT(_) => &fi.p_hash,
+            SHA2 | FEATURES | COMBINED(_) => HashSpec::sha2(),
+            PERCEPTUAL(spec, _) => spec,
+            DIHEDRAL(spec, _) => spec,
+        }
+    }
+
+    fn uses_transforms(&self) -> bool {
+        match *self {
+            SearchType::DIHEDRAL(..) => true,
+            _ => false,
+        }
+    }
+
+    // The hashes that need to be computed for the search. The SHA-256 is always
+    // included.
+    pub fn hash_needs(&self) -> HashNeeds {
+        let specs = match *self {
+            SearchType::SHA2 | SearchType::FEATURES => vec![HashSpec::sha2()],
+            SearchType::COMBINED(ref combined) => {
+                let mut specs = combined.specs();
+                specs.push(HashSpec::sha2());
+                specs
+            }
+            _ => vec![self.hash_spec(), HashSpec::sha2()],
+        };
+        HashNeeds {
+            specs,
+            transforms: self.uses_transforms(),
+            features: *self == SearchType::FEATURES,
+            dimensions: false,
+        }
+    }
+
+    // True if only files with identical contents can match.
+    pub fn compares_bytes(&self) -> bool {
+        match *self {
+            SearchType::SHA2 => true,
+            _ => false,
         }
     }
 
+    fn get_hash<'a>(&self, fi: &'a FileInfo) -> &'a str {
+        fi.hash(self.hash_spec())
+    }
+
+    // The hashes to look for when searching for matches to a file. Other files are
+    // only indexed by their own hash, so a rotated copy of a file is found by the
+    // hash of the file's own rotated image.
+    fn query_hashes<'a>(&self, fi: &'a FileInfo) -> Vec<&'a str> {
+        if !self.uses_transforms() {
+            return vec![self.get_hash(fi)];
+        }
+        Transform::ALL
+            .iter()
+            .map(|transform| fi.transformed_hash(self.hash_spec(), *transform))
+            .filter(|hash| !hash.is_empty())
+            .collect()
+    }
+
     pub fn find_dups(
         &self,
         files: Vec<PathBuf>,
         fileinfos: HashMap<PathBuf, FileInfo>,
+        store: &CacheStore,
+        size_filter: &SizeFilter,
     ) -> Vec<Matches> {
-        let index = self.build_reverse_index(&mut fileinfos.values());
-
+        if *self == SearchType::FEATURES {
+            return find_feature_matches(files, &fileinfos, size_filter);
+        }
+        if let SearchType::COMBINED(ref combined) = *self {
+            return combined.find_dups(files, &fileinfos, size_filter);
+        }
         let distance = self.distance();
-        if distance == 0 {
-            self.find_exact_distance(files, index, fileinfos)
+
+        // Entries that were only hashed for other searches don't have this hash.
+        let spec = self.hash_spec();
+        let fileinfos: HashMap<PathBuf, FileInfo> = fileinfos
+            .into_iter()
+            .filter(|(_, fi)| fi.has_hash(spec) && size_filter.accepts(fi))
+            .collect();
+        let files: Vec<PathBuf> = files
+            .into_iter()
+            .filter(|f| fileinfos.contains_key(f))
+            .collect();
+
+        // If the store has its own index, then exact matches can be looked up
+        // directly, without building one.
+        let exact = distance == 0 && !self.uses_transforms();
+        if exact && store.has_hash_index() {
+            return self.find_exact_distance(files, &fileinfos, |hash| {
+                store
+                    .find_exact(spec, hash)
+                    .map_err(|err| eprintln!("Error looking up {}: {:?}", hash, err))
+                    .ok()
+                    .map(|mut paths| {
+                        // The store doesn't know about the filters.
+                        paths.retain(|p| fileinfos.contains_key(p));
+                        paths
+                    })
+            });
+        }
+
+        let index = self.build_reverse_index(&mut fileinfos.values());
+        if exact {
+            self.find_exact_distance(files, &fileinfos, |hash| index.get(hash).cloned())
         } else {
             self.find_close_matches(distance, &files, &index, &fileinfos)
         }
@@ -64,24 +163,26 @@ impl SearchType {
 
     fn collect_matches<'a, I>(
         &self,
-        filename: &PathBuf,
         close_hashes: I,
         index: &HashMap<String, Vec<PathBuf>>,
-    ) -> Matches
+    ) -> Vec<MatchedFile>
     where
-        I: Iterator<Item = (u64, &'a ImageHash)>,
+        I: Iterator<Item = (u64, &'a BitHash)>,
     {
-        let mut fns = Vec::new();
-        for (_distance, hash) in close_hashes {
+        let bits = self.hash_spec().bits();
+        let mut matched = Vec::new();
+        for (distance, hash) in close_hashes {
             let hash_str = hash.to_base64();
-            let paths = &index[&hash_str];
-            fns.extend(paths);
-        }
-
-        Matches {
-            filename: filename.clone(),
-            matched_files: fns.into_iter().map(|f| f.to_owned()).collect(),
+            for path in &index[&hash_str] {
+                matched.push(MatchedFile {
+                    path: path.to_owned(),
+                    distance: Some(distance),
+                    similarity: similarity(distance, bits),
+                    ..MatchedFile::default()
+                });
+            }
         }
+        matched
     }
 
     fn find_close_matches(
@@ -95,12 +196,24 @@ impl SearchType {
         let mut vec = Vec::new();
         for file in files {
             if let Some(fi_to_find) = fileinfos.get(file) {
-                let hash_to_find = self.get_hash(fi_to_find);
-                let key_to_find = ImageHash::from_base64(hash_to_find).unwrap();
-                let close = bk_tree.find(&key_to_find, distance);
-                let matches = self.collect_matches(&file, close, index);
-                if matches.matched_files.len() > 1 {
-                    vec.push(matches);
+                let mut matched_files: Vec<MatchedFile> = Vec::new();
+                for hash_to_find in self.query_hashes(fi_to_find) {
+                    let key_to_find = BitHash::from_base64(hash_to_find).unwrap();
+                    let close = bk_tree.find(&key_to_find, distance);
+                    // A symmetrical image can find the same files more than once, and
+                    // they are as close as the closest of its transforms.
+                    for matched in self.collect_matches(close, index) {
+                        match matched_files.iter().position(|m| m.path == matched.path) {
+                            Some(i) if matched.distance < matched_files[i].distance => {
+                                matched_files[i] = matched
+                            }
+                            Some(_) => (),
+                            None => matched_files.push(matched),
+                        }
+                    }
+                }
+                if matched_files.len() > 1 {
+                    vec.push(Matches::new(file.clone(), matched_files));
                 }
             }
         }
@@ -110,32 +223,42 @@ impl SearchType {
     fn build_bk_tree(
         &self,
         hashes: &HashMap<String, Vec<PathBuf>>,
-    ) -> BKTree<ImageHash, HammingDistance> {
+    ) -> BKTree<BitHash, HammingDistance> {
         let mut bk_tree = BKTree::new(HammingDistance {});
         for key in hashes.keys() {
-            let hash = ImageHash::from_base64(key).unwrap();
+            let hash = BitHash::from_base64(key).unwrap();
             bk_tree.add(hash);
         }
         bk_tree
     }
 
-    fn find_exact_distance(
+    // `lookup` finds all of the files with exactly the given hash.
+    fn find_exact_distance<F>(
         &self,
         files: Vec<PathBuf>,
-        index: HashMap<String, Vec<PathBuf>>,
-        fileinfos: HashMap<PathBuf, FileInfo>,
-    ) -> Vec<Matches> {
+        fileinfos: &HashMap<PathBuf, FileInfo>,
+        lookup: F,
+    ) -> Vec<Matches>
+    where
+        F: Fn(&str) -> Option<Vec<PathBuf>>,
+    {
         files.iter().fold(Vec::default(), |mut matches, filename| {
             if let Some(fi) = fileinfos.get(filename) {
                 let hash = self.get_hash(fi);
 
-                if let Some(matched_files) = index.get(hash) {
+                if let Some(matched_files) = lookup(hash) {
                     // TODO: Remove the filename from the matched files.
                     if matched_files.len() > 1 {
-                        matches.push(Matches {
-                            filename: filename.to_owned(),
-                            matched_files: matched_files.to_owned(),
-                        });
+                        let matched_files = matched_files
+                            .into_iter()
+                            .map(|path| MatchedFile {
+                                path,
+                                distance: Some(0),
+                                similarity: 100.0,
+                                ..MatchedFile::default()
+                            })
+                            .collect();
+                        matches.push(Matches::new(filename.to_owned(), matched_files));
                     }
                 }
             }
@@ -167,13 +290,161 @@ impl SearchType {
 #[derive(Debug, Default, Deserialize, Serialize)]
 pub struct Matches {
     pub filename: PathBuf,
-    pub matched_files: Vec<PathBuf>,
+    pub matched_files: Vec<MatchedFile>,
+}
+
+impl Matches {
+    // The file itself comes first in its matches, and the others follow, closest
+    // first.
+    pub fn new(filename: PathBuf, mut matched_files: Vec<MatchedFile>) -> Matches {
+        matched_files.sort_by(|a, b| {
+            (a.path != filename)
+                .cmp(&(b.path != filename))
+                .then_with(|| {
+                    b.similarity
+                        .partial_cmp(&a.similarity)
+                        .unwrap_or(Ordering::Equal)
+                })
+                .then_with(|| a.distance.cmp(&b.distance))
+        });
+        Matches {
+            filename,
+            matched_files,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq, Serialize)]
+pub struct MatchedFile {
+    pub path: PathBuf,
+    // How far the file is from the one it matched. For a single hash, this is the
+    // Hamming distance between the hashes, and for features, the keypoints that
+    // didn't match. The combined hashes can differ in size, so their distances
+    // can't be added up, and are given one by one (in the order of --combine)
+    // instead.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub distance: Option<u64>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub distances: Vec<u64>,
+    // As a percentage, from 100 for identical hashes to 0 for opposite ones.
+    pub similarity: f64,
+}
+
+// The similarity of two hashes of `bits` bits, `distance` apart.
+pub fn similarity(distance: u64, bits: u32) -> f64 {
+    percentage(1.0 - distance as f64 / f64::from(bits.max(1)))
+}
+
+// Rounded to a tenth of a percent, which is plenty for eyeballing.
+pub fn percentage(fraction: f64) -> f64 {
+    (fraction.max(0.0) * 1000.0).round() / 10.0
+}
+
+// Comparing features is slow, so only the pairs of files that share some similar
+// features are compared.
+fn find_feature_matches(
+    files: Vec<PathBuf>,
+    fileinfos: &HashMap<PathBuf, FileInfo>,
+    size_filter: &SizeFilter,
+) -> Vec<Matches> {
+    let (files, features): (Vec<PathBuf>, Vec<_>) = files
+        .into_iter()
+        .filter_map(|file| {
+            let keypoints = fileinfos
+                .get(&file)
+                .filter(|fi| size_filter.accepts(fi))
+                .and_then(features_of)?;
+            Some((file, keypoints))
+        })
+        .unzip();
+
+    let mut matched: Vec<Vec<MatchedFile>> = vec![Vec::new(); files.len()];
+    for (a, b) in candidate_pairs(&features) {
+        if let Some(found) = match_features(&features[a], &features[b]) {
+            let unmatched = features[a]
+                .len()
+                .min(features[b].len())
+                .saturating_sub(found.inliers);
+            let similarity = percentage(found.score);
+            matched[a].push(MatchedFile {
+                path: files[b].clone(),
+                distance: Some(unmatched as u64),
+                similarity,
+                ..MatchedFile::default()
+            });
+            matched[b].push(MatchedFile {
+                path: files[a].clone(),
+                distance: Some(unmatched as u64),
+                similarity,
+                ..MatchedFile::default()
+            });
+        }
+    }
+
+    let mut vec = Vec::new();
+    for (i, mut matched_files) in matched.into_iter().enumerate() {
+        if matched_files.is_empty() {
+            continue;
+        }
+        matched_files.push(MatchedFile {
+            path: files[i].clone(),
+            distance: Some(0),
+            similarity: 100.0,
+            ..MatchedFile::default()
+        });
+        vec.push(Matches::new(files[i].clone(), matched_files));
+    }
+    vec
 }
 
-struct HammingDistance;
+pub struct HammingDistance;
+
+impl Metric<BitHash> for HammingDistance {
+    fn distance(&self, a: &BitHash, b: &BitHash) -> u64 {
+        a.distance(b)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+
+    use super::{percentage, similarity, MatchedFile, Matches};
+
+    fn matched(path: &str, distance: u64, similarity: f64) -> MatchedFile {
+        MatchedFile {
+            path: PathBuf::from(path),
+            distance: Some(distance),
+            similarity,
+            ..MatchedFile::default()
+        }
+    }
+
+    #[test]
+    fn test_similarity() {
+        assert_eq!(100.0, similarity(0, 64));
+        assert_eq!(90.6, similarity(6, 64));
+        assert_eq!(0.0, similarity(64, 64));
+        assert_eq!(0.0, similarity(70, 64));
+        assert_eq!(33.3, percentage(1.0 / 3.0));
+    }
 
-impl Metric<ImageHash> for HammingDistance {
-    fn distance(&self, a: &ImageHash, b: &ImageHash) -> u64 {
-        a.dist(b) as u64
+    #[test]
+    fn test_matches_order() {
+        let matches = Matches::new(
+            PathBuf::from("b"),
+            vec![
+                matched("c", 5, 92.2),
+                matched("a", 0, 100.0),
+                matched("d", 1, 98.4),
+                matched("b", 0, 100.0),
+            ],
+        );
+        let paths: Vec<_> = matches
+            .matched_files
+            .iter()
+            .map(|mf| mf.path.to_str().unwrap())
+            .collect();
+        assert_eq!(vec!["b", "a", "d", "c"], paths);
     }
 }
@@ -0,0 +1,108 @@
+use super::fileinfo::FileInfo;
+
+// Tiny images (icons, tracking pixels, thumbnails) have hashes that collide with
+// each other all the time, so they are best left out.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SizeFilter {
+    // In bytes.
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    // Width * height.
+    pub min_pixels: Option<u64>,
+}
+
+impl SizeFilter {
+    pub fn is_active(&self) -> bool {
+        self.min_size.is_some() || self.max_size.is_some() || self.min_pixels.is_some()
+    }
+
+    // The file sizes are known before hashing, so these can be skipped early.
+    pub fn accepts_file_size(&self, size: u64) -> bool {
+        self.min_size.map(|min| size >= min).unwrap_or(true)
+            && self.max_size.map(|max| size <= max).unwrap_or(true)
+    }
+
+    // The pixel check needs the dimensions, so with --min_pixels the files are
+    // decoded (see HashNeeds::dimensions) even for searches that don't otherwise.
+    // Entries whose dimensions still aren't known don't pass it.
+    pub fn accepts(&self, fi: &FileInfo) -> bool {
+        self.accepts_file_size(fi.stamp.size)
+            && match (self.min_pixels, fi.pixels()) {
+                (Some(min), Some(pixels)) => pixels >= min,
+                (Some(_), None) => false,
+                (None, _) => true,
+            }
+    }
+}
+
+// "1500", "64K", "2M", "1G". The suffixes are powers of 1024, and are case
+// insensitive.
+pub fn parse_size(s: &str) -> Option<u64> {
+    let s = s.trim();
+    let (digits, multiplier) = match s.chars().last().map(|c| c.to_ascii_uppercase()) {
+        Some('K') => (&s[..s.len() - 1], 1u64 << 10),
+        Some('M') => (&s[..s.len() - 1], 1u64 << 20),
+        Some('G') => (&s[..s.len() - 1], 1u64 << 30),
+        _ => (s, 1),
+    };
+    digits
+        .parse::<u64>()
+        .ok()
+        .and_then(|n| n.checked_mul(multiplier))
+}
+
+#[cfg(test)]
+mod test {
+    use super::super::fileinfo::{FileInfo, FileStamp};
+    use super::{parse_size, SizeFilter};
+
+    #[test]
+    fn test_parse_size() {
+        assert_eq!(Some(1500), parse_size("1500"));
+        assert_eq!(Some(64 * 1024), parse_size("64K"));
+        assert_eq!(Some(2 * 1024 * 1024), parse_size("2m"));
+        assert_eq!(Some(1 << 30), parse_size("1G"));
+        assert_eq!(None, parse_size("big"));
+        assert_eq!(None, parse_size("K"));
+        assert_eq!(None, parse_size("-1"));
+    }
+
+    #[test]
+    fn test_accepts() {
+        let filter = SizeFilter {
+            min_size: Some(1000),
+            max_size: Some(10_000),
+            min_pixels: Some(100 * 100),
+        };
+        let fi = FileInfo {
+            stamp: FileStamp {
+                size: 5000,
+                ..FileStamp::default()
+            },
+            width: 640,
+            height: 480,
+            ..FileInfo::default()
+        };
+        assert!(filter.accepts(&fi));
+
+        let icon = FileInfo {
+            width: 16,
+            height: 16,
+            ..fi.clone()
+        };
+        assert!(!filter.accepts(&icon));
+
+        let unknown = FileInfo {
+            width: 0,
+            height: 0,
+            ..fi.clone()
+        };
+        assert!(!filter.accepts(&unknown));
+        assert!(SizeFilter::default().accepts(&unknown));
+
+        assert!(!filter.accepts_file_size(999));
+        assert!(!filter.accepts_file_size(10_001));
+        assert!(SizeFilter::default().accepts(&icon));
+        assert!(!SizeFilter::default().is_active());
+    }
+}
@@ -0,0 +1,248 @@
+use std::ffi::OsStr;
+use std::fs::File;
+use std::io::{self, Read};
+use std::path::Path;
+
+// Enough of the file to recognise any of the formats below.
+const MAGIC_LEN: usize = 16;
+
+// The kinds of image file that we know how to hash.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ImageType {
+    Bmp,
+    Gif,
+    Heic,
+    Jpeg,
+    Png,
+    // Camera RAW files. We only use the JPEG preview that is embedded in them.
+    Raw,
+    Tiff,
+    Webp,
+}
+
+// Most RAW formats are TIFF files inside, so they can only be told apart by
+// their extensions.
+const RAW_EXTENSIONS: &[&str] = &[
+    "arw", "cr2", "cr3", "dng", "nef", "nrw", "orf", "pef", "raf", "rw2", "sr2", "srw",
+];
+
+// ISO base media files (HEIC, and Canon's CR3) say what they are in their "ftyp"
+// box, right at the start of the file.
+const HEIC_BRANDS: &[&[u8]] = &[b"heic", b"heix", b"hevc", b"hevx", b"mif1", b"msf1"];
+const CR3_BRAND: &[u8] = b"crx ";
+
+impl ImageType {
+    pub const ALL: [ImageType; 8] = [
+        ImageType::Bmp,
+        ImageType::Gif,
+        ImageType::Heic,
+        ImageType::Jpeg,
+        ImageType::Png,
+        ImageType::Raw,
+        ImageType::Tiff,
+        ImageType::Webp,
+    ];
+
+    // Matches the names used on the command line.
+    pub fn name(&self) -> &'static str {
+        match *self {
+            ImageType::Bmp => "bmp",
+            ImageType::Gif => "gif",
+            ImageType::Heic => "heic",
+            ImageType::Jpeg => "jpeg",
+            ImageType::Png => "png",
+            ImageType::Raw => "raw",
+            ImageType::Tiff => "tiff",
+            ImageType::Webp => "webp",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<ImageType> {
+        ImageType::ALL.iter().cloned().find(|t| t.name() == name)
+    }
+
+    // Recognise an image by the first few bytes of the file, and (since RAW files
+    // look like TIFFs) its extension.
+    pub fn detect(path: &Path, bytes: &[u8]) -> Option<ImageType> {
+        let is_raw_extension = path
+            .extension()
+            .map(|ext| is_raw_extension(ext))
+            .unwrap_or(false);
+        match ImageType::from_magic(bytes) {
+            Some(ImageType::Tiff) if is_raw_extension => Some(ImageType::Raw),
+            other => other,
+        }
+    }
+
+    pub fn from_magic(bytes: &[u8]) -> Option<ImageType> {
+        if bytes.starts_with(b"\xFF\xD8\xFF") {
+            Some(ImageType::Jpeg)
+        } else if bytes.starts_with(b"\x89PNG\r\n\x1A\n") {
+            Some(ImageType::Png)
+        } else if bytes.starts_with(b"GIF87a") || bytes.starts_with(b"GIF89a") {
+            Some(ImageType::Gif)
+        } else if bytes.starts_with(b"RIFF") && bytes.len() >= 12 && &bytes[8..12] == b"WEBP" {
+            Some(ImageType::Webp)
+        } else if bytes.starts_with(b"II*\0") || bytes.starts_with(b"MM\0*") {
+            Some(ImageType::Tiff)
+        } else if bytes.starts_with(b"IIRO")
+            || bytes.starts_with(b"IIRS")
+            || bytes.starts_with(b"IIU\0")
+            || bytes.starts_with(b"FUJIFILMCCD-RAW")
+        {
+            // Olympus, Panasonic, and Fuji RAW files.
+            Some(ImageType::Raw)
+        } else if bytes.starts_with(b"BM") && bytes.len() >= 14 {
+            Some(ImageType::Bmp)
+        } else if bytes.len() >= 12 && &bytes[4..8] == b"ftyp" {
+            let brand = &bytes[8..12];
+            if HEIC_BRANDS.contains(&brand) {
+                Some(ImageType::Heic)
+            } else if brand == CR3_BRAND {
+                Some(ImageType::Raw)
+            } else {
+                None
+            }
+        } else {
+            None
+        }
+    }
+
+    // Recognise an image by its extension, ignoring case. This is faster, since
+    // it doesn't read the file, but it misses files with unusual (or no)
+    // extensions.
+    pub fn from_extension(ext: &OsStr) -> Option<ImageType> {
+        if is_raw_extension(ext) {
+            return Some(ImageType::Raw);
+        }
+        match ext.to_string_lossy().to_lowercase().as_str() {
+            "bmp" | "dib" => Some(ImageType::Bmp),
+            "gif" => Some(ImageType::Gif),
+            "heic" | "heif" => Some(ImageType::Heic),
+            "jpg" | "jpeg" | "jpe" | "jfif" => Some(ImageType::Jpeg),
+            "png" => Some(ImageType::Png),
+            "tif" | "tiff" => Some(ImageType::Tiff),
+            "webp" => Some(ImageType::Webp),
+            _ => None,
+        }
+    }
+
+    pub fn from_path(path: &Path) -> io::Result<Option<ImageType>> {
+        let mut buf = [0u8; MAGIC_LEN];
+        let len = read_prefix(&mut File::open(path)?, &mut buf)?;
+        Ok(ImageType::detect(path, &buf[..len]))
+    }
+}
+
+fn is_raw_extension(ext: &OsStr) -> bool {
+    RAW_EXTENSIONS.contains(&ext.to_string_lossy().to_lowercase().as_str())
+}
+
+// Like read_exact(), but a short file isn't an error.
+fn read_prefix<R: Read>(r: &mut R, buf: &mut [u8]) -> io::Result<usize> {
+    let mut total = 0;
+    while total < buf.len() {
+        match r.read(&mut buf[total..]) {
+            Ok(0) => break,
+            Ok(len) => total += len,
+            Err(ref err) if err.kind() == io::ErrorKind::Interrupted => (),
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(total)
+}
+
+#[cfg(test)]
+mod test {
+    use std::ffi::OsStr;
+    use std::path::Path;
+
+    use super::ImageType;
+
+    #[test]
+    fn test_from_magic() {
+        assert_eq!(
+            Some(ImageType::Jpeg),
+            ImageType::from_magic(b"\xFF\xD8\xFF\xE0\x00\x10JFIF")
+        );
+        assert_eq!(
+            Some(ImageType::Png),
+            ImageType::from_magic(b"\x89PNG\r\n\x1A\n\x00\x00")
+        );
+        assert_eq!(Some(ImageType::Gif), ImageType::from_magic(b"GIF89a\x01"));
+        assert_eq!(
+            Some(ImageType::Webp),
+            ImageType::from_magic(b"RIFF\x10\x00\x00\x00WEBPVP8 ")
+        );
+        assert_eq!(
+            Some(ImageType::Tiff),
+            ImageType::from_magic(b"II*\x00\x08\x00\x00\x00")
+        );
+        assert_eq!(
+            Some(ImageType::Bmp),
+            ImageType::from_magic(b"BM\x36\x00\x0C\x00\x00\x00\x00\x00\x36\x00\x00\x00")
+        );
+        assert_eq!(
+            Some(ImageType::Heic),
+            ImageType::from_magic(b"\x00\x00\x00\x18ftypheic")
+        );
+        assert_eq!(
+            Some(ImageType::Raw),
+            ImageType::from_magic(b"\x00\x00\x00\x18ftypcrx ")
+        );
+        assert_eq!(None, ImageType::from_magic(b"\x00\x00\x00\x18ftypisom"));
+        assert_eq!(None, ImageType::from_magic(b"%PDF-1.4"));
+        assert_eq!(None, ImageType::from_magic(b""));
+        assert_eq!(None, ImageType::from_magic(b"\xFF"));
+    }
+
+    #[test]
+    fn test_detect_raw() {
+        let tiff = b"MM\x00*\x00\x00\x00\x08";
+        assert_eq!(
+            Some(ImageType::Raw),
+            ImageType::detect(Path::new("IMG_0001.CR2"), tiff)
+        );
+        assert_eq!(
+            Some(ImageType::Raw),
+            ImageType::detect(Path::new("IMG_0001.dng"), tiff)
+        );
+        assert_eq!(
+            Some(ImageType::Tiff),
+            ImageType::detect(Path::new("scan.tif"), tiff)
+        );
+    }
+
+    #[test]
+    fn test_names() {
+        for t in ImageType::ALL.iter() {
+            assert_eq!(Some(*t), ImageType::from_name(t.name()));
+        }
+        assert_eq!(None, ImageType::from_name("pdf"));
+    }
+
+    #[test]
+    fn test_from_extension() {
+        assert_eq!(
+            Some(ImageType::Jpeg),
+            ImageType::from_extension(OsStr::new("JPG"))
+        );
+        assert_eq!(
+            Some(ImageType::Jpeg),
+            ImageType::from_extension(OsStr::new("jpe"))
+        );
+        assert_eq!(
+            Some(ImageType::Png),
+            ImageType::from_extension(OsStr::new("Png"))
+        );
+        assert_eq!(
+            Some(ImageType::Raw),
+            ImageType::from_extension(OsStr::new("NEF"))
+        );
+        assert_eq!(
+            Some(ImageType::Heic),
+            ImageType::from_extension(OsStr::new("HEIC"))
+        );
+        assert_eq!(None, ImageType::from_extension(OsStr::new("txt")));
+    }
+}
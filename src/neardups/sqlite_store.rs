@@ -0,0 +1,455 @@
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use rusqlite::types::{ToSql, Value};
+use rusqlite::{Connection, Row};
+
+use super::failures::FailedFile;
+use super::fileinfo::{FileInfo, FileStamp, HashKind, HashSpec, DEFAULT_HASH_SIZE};
+use super::pcache::HashTable;
+use super::result::Result;
+use super::store::{CacheStore, LibraryRoot};
+
+// One row per file. Each hash column is indexed so that other tools (and the
+// exact-match search) can look files up by hash. Hashes that don't have a column
+// of their own are kept in `hashes`, one row per hash, by `HashSpec::name`.
+//
+// Paths are stored as text, so that other tools can read them, unless they aren't
+// valid UTF-8. Those are stored as their raw bytes, in a BLOB, so that they still
+// match the files they came from.
+const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS files (
+        path TEXT PRIMARY KEY NOT NULL,
+        a_hash TEXT NOT NULL,
+        d_hash TEXT NOT NULL,
+        p_hash TEXT NOT NULL,
+        sha2_hash TEXT NOT NULL,
+        size INTEGER NOT NULL,
+        mtime_secs INTEGER NOT NULL,
+        mtime_nanos INTEGER NOT NULL,
+        device INTEGER,
+        inode INTEGER,
+        width INTEGER NOT NULL DEFAULT 0,
+        height INTEGER NOT NULL DEFAULT 0
+    );
+    CREATE INDEX IF NOT EXISTS files_a_hash ON files (a_hash);
+    CREATE INDEX IF NOT EXISTS files_d_hash ON files (d_hash);
+    CREATE INDEX IF NOT EXISTS files_p_hash ON files (p_hash);
+    CREATE INDEX IF NOT EXISTS files_sha2_hash ON files (sha2_hash);
+    CREATE TABLE IF NOT EXISTS hashes (
+        path TEXT NOT NULL,
+        name TEXT NOT NULL,
+        hash TEXT NOT NULL,
+        PRIMARY KEY (path, name)
+    );
+    CREATE INDEX IF NOT EXISTS hashes_name_hash ON hashes (name, hash);
+    CREATE TABLE IF NOT EXISTS failures (
+        path TEXT PRIMARY KEY NOT NULL,
+        size INTEGER NOT NULL,
+        mtime_secs INTEGER NOT NULL,
+        mtime_nanos INTEGER NOT NULL,
+        device INTEGER,
+        inode INTEGER,
+        kind TEXT NOT NULL,
+        message TEXT NOT NULL
+    );
+";
+
+const COLUMNS: &str = "path, a_hash, d_hash, p_hash, sha2_hash, size, mtime_secs, mtime_nanos, \
+                       device, inode, width, height";
+const FAILURE_COLUMNS: &str = "path, size, mtime_secs, mtime_nanos, device, inode, kind, message";
+
+// Each commit waits for the disk, so changes are gathered into a transaction that
+// is committed at a checkpoint once it holds this many of them, or is this old
+// (and at the end of the run).
+const COMMIT_AFTER_CHANGES: usize = 1000;
+const COMMIT_INTERVAL_SECS: u64 = 15;
+
+// Keeps the cache in an SQLite database. Changes are written as they happen, in
+// a transaction that checkpoints commit.
+#[derive(Debug)]
+pub struct SqliteStore {
+    conn: Connection,
+    root: LibraryRoot,
+
+    // The changes in the open transaction. There is none open if this is 0.
+    pending: usize,
+    last_commit_time: Instant,
+}
+
+impl SqliteStore {
+    pub fn open(filename: &Path) -> Result<SqliteStore> {
+        let conn = Connection::open(filename)?;
+        // WAL lets other tools read the database while we are writing to it.
+        conn.execute_batch("PRAGMA journal_mode = WAL;")?;
+        conn.execute_batch(SCHEMA)?;
+        add_dimension_columns(&conn)?;
+        Ok(SqliteStore {
+            conn,
+            root: LibraryRoot::default(),
+            pending: 0,
+            last_commit_time: Instant::now(),
+        })
+    }
+
+    pub fn set_root(&mut self, root: LibraryRoot) {
+        self.root = root;
+    }
+
+    // Called before each change.
+    fn begin(&mut self) -> Result<()> {
+        if self.pending == 0 {
+            self.conn.execute_batch("BEGIN")?;
+        }
+        self.pending += 1;
+        Ok(())
+    }
+
+    fn commit(&mut self) -> Result<()> {
+        if self.pending > 0 {
+            self.conn.execute_batch("COMMIT")?;
+            self.pending = 0;
+        }
+        self.last_commit_time = Instant::now();
+        Ok(())
+    }
+}
+
+impl CacheStore for SqliteStore {
+    fn load(&mut self) -> Result<HashTable> {
+        let mut stmt = self
+            .conn
+            .prepare(&format!("SELECT {} FROM files", COLUMNS))?;
+        let rows = stmt.query_map(&[], row_to_fileinfo)?;
+
+        let mut table = HashTable::new();
+        for row in rows {
+            let mut fi = row??;
+            fi.filename = self.root.from_key(&fi.filename);
+            table.insert(fi.filename.clone(), fi);
+        }
+
+        let mut stmt = self.conn.prepare("SELECT path, name, hash FROM hashes")?;
+        let rows = stmt.query_map(&[], |row| {
+            (
+                row.get_checked::<_, Value>(0),
+                row.get_checked::<_, String>(1),
+                row.get_checked::<_, String>(2),
+            )
+        })?;
+        for row in rows {
+            let (path, name, hash) = row?;
+            let path = self.root.from_key(&value_to_path(path?));
+            if let Some(fi) = table.get_mut(&path) {
+                fi.hashes.insert(name?, hash?);
+            }
+        }
+        Ok(table)
+    }
+
+    fn insert(&mut self, fi: &FileInfo) -> Result<()> {
+        let stamp = &fi.stamp;
+        let key = path_to_value(&self.root.to_key(&fi.filename))?;
+        self.begin()?;
+        self.conn
+            .execute("DELETE FROM hashes WHERE path = ?1", &[&key])?;
+        for (name, hash) in &fi.hashes {
+            self.conn.execute(
+                "INSERT INTO hashes (path, name, hash) VALUES (?1, ?2, ?3)",
+                &[&key, name, hash],
+            )?;
+        }
+        self.conn.execute(
+            &format!(
+                "INSERT OR REPLACE INTO files ({}) \
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12)",
+                COLUMNS
+            ),
+            &[
+                &key,
+                &fi.a_hash,
+                &fi.d_hash,
+                &fi.p_hash,
+                &fi.sha2_hash,
+                &(stamp.size as i64),
+                &(stamp.mtime_secs as i64),
+                &(stamp.mtime_nanos as i64),
+                &stamp.device.map(|d| d as i64),
+                &stamp.inode.map(|i| i as i64),
+                &i64::from(fi.width),
+                &i64::from(fi.height),
+            ],
+        )?;
+        Ok(())
+    }
+
+    fn remove(&mut self, path: &Path) -> Result<()> {
+        let key = path_to_value(&self.root.to_key(path))?;
+        self.begin()?;
+        self.conn
+            .execute("DELETE FROM files WHERE path = ?1", &[&key])?;
+        self.conn
+            .execute("DELETE FROM hashes WHERE path = ?1", &[&key])?;
+        Ok(())
+    }
+
+    fn load_failures(&mut self) -> Result<Vec<FailedFile>> {
+        let mut stmt = self
+            .conn
+            .prepare(&format!("SELECT {} FROM failures", FAILURE_COLUMNS))?;
+        let rows = stmt.query_map(&[], row_to_failed_file)?;
+
+        let mut failures = Vec::new();
+        for row in rows {
+            failures.push(self.root.absolutize_failure(row??));
+        }
+        Ok(failures)
+    }
+
+    fn save_failures(&mut self, failures: &[FailedFile]) -> Result<()> {
+        // A transaction can't be opened inside another one.
+        self.commit()?;
+        let tx = self.conn.transaction()?;
+        tx.execute("DELETE FROM failures", &[])?;
+        for failed in failures {
+            let stamp = &failed.stamp;
+            tx.execute(
+                &format!(
+                    "INSERT INTO failures ({}) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)",
+                    FAILURE_COLUMNS
+                ),
+                &[
+                    &path_to_value(&self.root.to_key(&failed.path))?,
+                    &(stamp.size as i64),
+                    &(stamp.mtime_secs as i64),
+                    &(stamp.mtime_nanos as i64),
+                    &stamp.device.map(|d| d as i64),
+                    &stamp.inode.map(|i| i as i64),
+                    &failed.kind,
+                    &failed.message,
+                ],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn checkpoint(&mut self, _table: &HashTable, finished: bool) -> Result<()> {
+        let due = self.pending >= COMMIT_AFTER_CHANGES
+            || self.last_commit_time.elapsed().as_secs() >= COMMIT_INTERVAL_SECS;
+        if finished || due {
+            self.commit()?;
+        }
+        Ok(())
+    }
+
+    fn has_hash_index(&self) -> bool {
+        true
+    }
+
+    fn find_exact(&self, spec: HashSpec, hash: &str) -> Result<Vec<PathBuf>> {
+        let (query, params) = match hash_column(spec) {
+            Some(column) => (
+                format!("SELECT path FROM files WHERE {} = ?1", column),
+                vec![hash.to_string()],
+            ),
+            None => (
+                "SELECT path FROM hashes WHERE hash = ?1 AND name = ?2".to_string(),
+                vec![hash.to_string(), spec.name()],
+            ),
+        };
+        let mut stmt = self.conn.prepare(&query)?;
+        let params: Vec<&ToSql> = params.iter().map(|p| p as &ToSql).collect();
+        let rows = stmt.query_map(&params, |row| row.get_checked::<_, Value>(0))?;
+
+        let mut paths = Vec::new();
+        for row in rows {
+            paths.push(self.root.from_key(&value_to_path(row??)));
+        }
+        Ok(paths)
+    }
+}
+
+// Databases created before the dimensions were recorded don't have the columns.
+fn add_dimension_columns(conn: &Connection) -> Result<()> {
+    let mut stmt = conn.prepare("PRAGMA table_info(files)")?;
+    let rows = stmt.query_map(&[], |row| row.get_checked::<_, String>(1))?;
+    let mut has_width = false;
+    for row in rows {
+        has_width |= row?? == "width";
+    }
+    if !has_width {
+        conn.execute_batch(
+            "ALTER TABLE files ADD COLUMN width INTEGER NOT NULL DEFAULT 0;
+             ALTER TABLE files ADD COLUMN height INTEGER NOT NULL DEFAULT 0;",
+        )?;
+    }
+    Ok(())
+}
+
+// None for the hashes that are kept in the `hashes` table.
+fn hash_column(spec: HashSpec) -> Option<&'static str> {
+    match (spec.kind, spec.size) {
+        (HashKind::Mean, DEFAULT_HASH_SIZE) => Some("a_hash"),
+        (HashKind::Grad, DEFAULT_HASH_SIZE) => Some("d_hash"),
+        (HashKind::Dct, DEFAULT_HASH_SIZE) => Some("p_hash"),
+        (HashKind::Sha2, _) => Some("sha2_hash"),
+        _ => None,
+    }
+}
+
+fn path_to_value(path: &Path) -> Result<Value> {
+    match path.to_str() {
+        Some(path) => Ok(Value::Text(path.to_string())),
+        None => path_bytes(path).map(Value::Blob),
+    }
+}
+
+#[cfg(unix)]
+fn path_bytes(path: &Path) -> Result<Vec<u8>> {
+    use std::os::unix::ffi::OsStrExt;
+    Ok(path.as_os_str().as_bytes().to_vec())
+}
+
+// There's no lossless way to store these, so they aren't stored at all.
+#[cfg(not(unix))]
+fn path_bytes(_path: &Path) -> Result<Vec<u8>> {
+    Err(super::result::ItoolsError::UnsupportedFormat(
+        "path is not valid UTF-8",
+    ))
+}
+
+fn value_to_path(value: Value) -> PathBuf {
+    match value {
+        Value::Blob(bytes) => bytes_to_path(bytes),
+        Value::Text(text) => PathBuf::from(text),
+        // The columns are NOT NULL, with text affinity, so this can't happen.
+        _ => PathBuf::new(),
+    }
+}
+
+#[cfg(unix)]
+fn bytes_to_path(bytes: Vec<u8>) -> PathBuf {
+    use std::ffi::OsString;
+    use std::os::unix::ffi::OsStringExt;
+    OsString::from_vec(bytes).into()
+}
+
+#[cfg(not(unix))]
+fn bytes_to_path(bytes: Vec<u8>) -> PathBuf {
+    PathBuf::from(String::from_utf8_lossy(&bytes).into_owned())
+}
+
+fn row_to_fileinfo(row: &Row) -> ::rusqlite::Result<FileInfo> {
+    Ok(FileInfo {
+        filename: value_to_path(row.get_checked(0)?),
+        a_hash: row.get_checked(1)?,
+        d_hash: row.get_checked(2)?,
+        p_hash: row.get_checked(3)?,
+        sha2_hash: row.get_checked(4)?,
+        stamp: row_to_stamp(row, 5)?,
+        width: row.get_checked::<_, i64>(10)? as u32,
+        height: row.get_checked::<_, i64>(11)? as u32,
+        ..FileInfo::default()
+    })
+}
+
+fn row_to_failed_file(row: &Row) -> ::rusqlite::Result<FailedFile> {
+    Ok(FailedFile {
+        path: value_to_path(row.get_checked(0)?),
+        stamp: row_to_stamp(row, 1)?,
+        kind: row.get_checked(6)?,
+        message: row.get_checked(7)?,
+    })
+}
+
+// The stamp is kept in five columns, starting at `first`.
+fn row_to_stamp(row: &Row, first: i32) -> ::rusqlite::Result<FileStamp> {
+    let size: i64 = row.get_checked(first)?;
+    let mtime_secs: i64 = row.get_checked(first + 1)?;
+    let mtime_nanos: i64 = row.get_checked(first + 2)?;
+    let device: Option<i64> = row.get_checked(first + 3)?;
+    let inode: Option<i64> = row.get_checked(first + 4)?;
+
+    Ok(FileStamp {
+        size: size as u64,
+        mtime_secs: mtime_secs as u64,
+        mtime_nanos: mtime_nanos as u32,
+        device: device.map(|d| d as u64),
+        inode: inode.map(|i| i as u64),
+    })
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::{Path, PathBuf};
+
+    use super::super::failures::FailedFile;
+    use super::super::fileinfo::FileInfo;
+    use super::super::pcache::HashTable;
+    use super::super::store::CacheStore;
+    use super::SqliteStore;
+
+    fn round_trip(path: PathBuf) {
+        let mut store = SqliteStore::open(Path::new(":memory:")).unwrap();
+        let mut fi = FileInfo {
+            filename: path.clone(),
+            sha2_hash: "47DEQpj8HBSa+/TImW+5JCeuQeRkm5NMpJWZG3hSuFU=".into(),
+            ..FileInfo::default()
+        };
+        fi.hashes.insert("wavelet16".into(), "AAECAwQFBgc=".into());
+        store.insert(&fi).unwrap();
+
+        let table = store.load().unwrap();
+        let loaded = &table[&path];
+        assert_eq!(fi.sha2_hash, loaded.sha2_hash);
+        assert_eq!(fi.hashes, loaded.hashes);
+
+        store.remove(&path).unwrap();
+        assert!(store.load().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_changes_are_batched() {
+        let mut store = SqliteStore::open(Path::new(":memory:")).unwrap();
+        for name in &["/photos/a.jpg", "/photos/b.jpg"] {
+            store
+                .insert(&FileInfo {
+                    filename: PathBuf::from(name),
+                    ..FileInfo::default()
+                }).unwrap();
+        }
+        store.remove(Path::new("/photos/a.jpg")).unwrap();
+        assert_eq!(3, store.pending);
+
+        let table = HashTable::new();
+        store.checkpoint(&table, false).unwrap();
+        assert_eq!(3, store.pending);
+        // Saving the failures has its own transaction.
+        store.save_failures(&Vec::<FailedFile>::new()).unwrap();
+        assert_eq!(0, store.pending);
+
+        store
+            .insert(&FileInfo {
+                filename: PathBuf::from("/photos/c.jpg"),
+                ..FileInfo::default()
+            }).unwrap();
+        store.checkpoint(&table, true).unwrap();
+        assert_eq!(0, store.pending);
+        assert_eq!(2, store.load().unwrap().len());
+    }
+
+    #[test]
+    fn test_round_trip() {
+        round_trip(PathBuf::from("/photos/IMG_0001.JPG"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_non_utf8_path() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+
+        round_trip(PathBuf::from(OsStr::from_bytes(b"/photos/caf\xe9.jpg")));
+    }
+}
@@ -0,0 +1,202 @@
+use std::borrow::Cow;
+use std::fmt::Debug;
+use std::path::{Path, PathBuf};
+
+use super::failures::FailedFile;
+use super::fileinfo::{FileInfo, HashSpec};
+use super::pcache::HashTable;
+use super::result::{ItoolsError, Result};
+
+// Where a PersistedCache keeps its entries between runs. The PersistedCache holds
+// every entry in memory while it runs (that's what answers "contains" and what the
+// search iterates over), and tells the store about each change so that it can be
+// persisted.
+pub trait CacheStore: Debug + Send {
+    // Read every entry in the store.
+    fn load(&mut self) -> Result<HashTable>;
+
+    // Called for each entry as it is added to (or replaced in) the cache.
+    fn insert(&mut self, fi: &FileInfo) -> Result<()>;
+
+    // Called for each entry as it is removed from the cache.
+    fn remove(&mut self, path: &Path) -> Result<()>;
+
+    // Called after every change with the complete table, and once more with
+    // `finished` set when there will be no more changes. Stores that write the
+    // whole table at once decide here whether it's time to do so.
+    fn checkpoint(&mut self, table: &HashTable, finished: bool) -> Result<()>;
+
+    // The files that couldn't be hashed, as of the last call to save_failures.
+    fn load_failures(&mut self) -> Result<Vec<FailedFile>>;
+
+    // Replaces all of the remembered failures.
+    fn save_failures(&mut self, failures: &[FailedFile]) -> Result<()>;
+
+    // True if the store can look up files by hash itself, so that the search
+    // doesn't need to build its own index for exact matches.
+    fn has_hash_index(&self) -> bool {
+        false
+    }
+
+    // All of the files whose hash of the given kind is exactly `hash`.
+    fn find_exact(&self, _spec: HashSpec, _hash: &str) -> Result<Vec<PathBuf>> {
+        Err(ItoolsError::InvalidState("store has no hash index"))
+    }
+}
+
+// Stores keep paths relative to the library root (if there is one), so that the
+// library can be moved, or the tool run from another directory, without
+// invalidating the cache. Paths outside of the root are kept as they are.
+//
+// Everything outside of the stores deals in full paths.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LibraryRoot(Option<PathBuf>);
+
+impl LibraryRoot {
+    pub fn new(root: Option<PathBuf>) -> LibraryRoot {
+        LibraryRoot(root)
+    }
+
+    pub fn to_key(&self, path: &Path) -> PathBuf {
+        match self.0 {
+            Some(ref root) => path
+                .strip_prefix(root)
+                .map(Path::to_path_buf)
+                .unwrap_or_else(|_| path.to_path_buf()),
+            None => path.to_path_buf(),
+        }
+    }
+
+    // Joining an absolute key leaves it alone, so keys outside the root survive.
+    pub fn from_key(&self, key: &Path) -> PathBuf {
+        match self.0 {
+            Some(ref root) => root.join(key),
+            None => key.to_path_buf(),
+        }
+    }
+
+    pub fn relativize<'a>(&self, fi: &'a FileInfo) -> Cow<'a, FileInfo> {
+        match self.0 {
+            Some(_) => {
+                let mut relative = fi.clone();
+                relative.filename = self.to_key(&fi.filename);
+                Cow::Owned(relative)
+            }
+            None => Cow::Borrowed(fi),
+        }
+    }
+
+    pub fn relativize_failure(&self, failed: &FailedFile) -> FailedFile {
+        FailedFile {
+            path: self.to_key(&failed.path),
+            ..failed.clone()
+        }
+    }
+
+    pub fn absolutize_failure(&self, mut failed: FailedFile) -> FailedFile {
+        failed.path = self.from_key(&failed.path);
+        failed
+    }
+
+    pub fn relativize_table<'a>(&self, table: &'a HashTable) -> Cow<'a, HashTable> {
+        match self.0 {
+            Some(_) => Cow::Owned(
+                table
+                    .values()
+                    .map(|fi| {
+                        let fi = self.relativize(fi).into_owned();
+                        (fi.filename.clone(), fi)
+                    })
+                    .collect(),
+            ),
+            None => Cow::Borrowed(table),
+        }
+    }
+
+    pub fn absolutize_table(&self, table: HashTable) -> HashTable {
+        match self.0 {
+            Some(_) => table
+                .into_iter()
+                .map(|(_, mut fi)| {
+                    fi.filename = self.from_key(&fi.filename);
+                    (fi.filename.clone(), fi)
+                })
+                .collect(),
+            None => table,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum StoreKind {
+    File,
+    Sqlite,
+}
+
+impl StoreKind {
+    // Pick a store based on the cache file's extension. Anything that doesn't look
+    // like a database gets the plain file store.
+    pub fn for_file(path: &Path) -> StoreKind {
+        let ext = path
+            .extension()
+            .map(|e| e.to_string_lossy().to_lowercase())
+            .unwrap_or_default();
+        match ext.as_str() {
+            "db" | "sqlite" | "sqlite3" => StoreKind::Sqlite,
+            _ => StoreKind::File,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::{Path, PathBuf};
+
+    use super::{LibraryRoot, StoreKind};
+
+    #[test]
+    fn test_store_kind_for_file() {
+        assert_eq!(
+            StoreKind::File,
+            StoreKind::for_file(Path::new("ndups_cache"))
+        );
+        assert_eq!(
+            StoreKind::File,
+            StoreKind::for_file(Path::new("cache.yaml"))
+        );
+        assert_eq!(
+            StoreKind::Sqlite,
+            StoreKind::for_file(Path::new("cache.db"))
+        );
+        assert_eq!(
+            StoreKind::Sqlite,
+            StoreKind::for_file(Path::new("/tmp/Cache.SQLite"))
+        );
+    }
+
+    #[test]
+    fn test_library_root_keys() {
+        let root = LibraryRoot::new(Some(PathBuf::from("/photos")));
+        let inside = Path::new("/photos/2018/IMG_0001.JPG");
+        let outside = Path::new("/backup/IMG_0001.JPG");
+
+        assert_eq!(PathBuf::from("2018/IMG_0001.JPG"), root.to_key(inside));
+        assert_eq!(inside, root.from_key(&root.to_key(inside)));
+        assert_eq!(outside, root.to_key(outside));
+        assert_eq!(outside, root.from_key(&root.to_key(outside)));
+
+        let moved = LibraryRoot::new(Some(PathBuf::from("/mnt/new_disk/photos")));
+        assert_eq!(
+            PathBuf::from("/mnt/new_disk/photos/2018/IMG_0001.JPG"),
+            moved.from_key(&root.to_key(inside))
+        );
+    }
+
+    #[test]
+    fn test_no_library_root() {
+        let root = LibraryRoot::default();
+        let path = Path::new("/photos/IMG_0001.JPG");
+        assert_eq!(path, root.to_key(path));
+        assert_eq!(path, root.from_key(path));
+    }
+}
@@ -1,3 +1,6 @@
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
 use std::sync::mpsc::{Sender, SyncSender};
 use std::thread;
 
@@ -9,6 +12,29 @@ pub fn bool_to_option<T, F: FnOnce() -> T>(b: bool, f: F) -> Option<T> {
     }
 }
 
+// "foo/cache" + ".bak" => "foo/cache.bak"
+pub fn path_with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(suffix);
+    name.into()
+}
+
+// A small, fast, and deterministic pseudo-random number generator. The state must
+// not be zero.
+pub fn xorshift(state: &mut u64) -> u64 {
+    *state ^= *state << 13;
+    *state ^= *state >> 7;
+    *state ^= *state << 17;
+    *state
+}
+
+pub fn remove_file_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(ref err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        other => other,
+    }
+}
+
 pub fn spawn_with_name<T, U, F>(name: T, f: F) -> std::thread::JoinHandle<U>
 where
     T: Into<String>,
@@ -62,7 +88,9 @@ impl<T> SafeSend<T> for SyncSender<T> {
 
 #[cfg(test)]
 mod test {
-    use super::bool_to_option;
+    use std::path::{Path, PathBuf};
+
+    use super::{bool_to_option, path_with_suffix};
 
     #[test]
     fn test_bool_to_option_false() {
@@ -85,4 +113,12 @@ mod test {
 
         assert_eq!(false, called);
     }
+
+    #[test]
+    fn test_path_with_suffix() {
+        assert_eq!(
+            PathBuf::from("foo/ndups_cache.bak"),
+            path_with_suffix(Path::new("foo/ndups_cache"), ".bak")
+        );
+    }
 }
@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use ignore::Match;
+
+use super::result::Result;
+
+// Directories can hold one of these (in gitignore syntax) to keep things out of the
+// walk.
+pub const IGNORE_FILE_NAME: &str = ".ndupsignore";
+
+// Decides which entries in a walked directory are used. Excluded directories
+// aren't walked at all.
+#[derive(Debug)]
+pub struct WalkFilter {
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+    // The ignore files found so far, by the directory that they were found in.
+    ignores: HashMap<PathBuf, Gitignore>,
+}
+
+impl WalkFilter {
+    // Globs without a '/' are matched against the file name, so "*.thm" and
+    // "@eaDir" do what they look like. Others are matched against the full path.
+    pub fn new(include: &[String], exclude: &[String]) -> Result<WalkFilter> {
+        Ok(WalkFilter {
+            include: if include.is_empty() {
+                None
+            } else {
+                Some(build_glob_set(include)?)
+            },
+            exclude: build_glob_set(exclude)?,
+            ignores: HashMap::new(),
+        })
+    }
+
+    // Directories named on the command line are always walked, but their ignore
+    // files still count.
+    pub fn add_root(&mut self, dir: &Path) {
+        self.load_ignore_file(dir);
+    }
+
+    // Called for each entry below the roots, parents first.
+    pub fn is_included(&mut self, path: &Path, is_dir: bool) -> bool {
+        if matches(&self.exclude, path) || self.is_ignored(path, is_dir) {
+            return false;
+        }
+
+        if is_dir {
+            self.load_ignore_file(path);
+            return true;
+        }
+
+        self.include
+            .as_ref()
+            .map(|include| matches(include, path))
+            .unwrap_or(true)
+    }
+
+    // The deepest ignore file with something to say about the path wins, so that
+    // a subdirectory can re-include something with "!".
+    fn is_ignored(&self, path: &Path, is_dir: bool) -> bool {
+        for dir in path.ancestors().skip(1) {
+            if let Some(gitignore) = self.ignores.get(dir) {
+                match gitignore.matched(path, is_dir) {
+                    Match::Ignore(_) => return true,
+                    Match::Whitelist(_) => return false,
+                    Match::None => (),
+                }
+            }
+        }
+        false
+    }
+
+    fn load_ignore_file(&mut self, dir: &Path) {
+        let ignore_file = dir.join(IGNORE_FILE_NAME);
+        if !ignore_file.is_file() {
+            return;
+        }
+
+        let mut builder = GitignoreBuilder::new(dir);
+        if let Some(err) = builder.add(&ignore_file) {
+            eprintln!("Problem reading {}: {}", ignore_file.display(), err);
+        }
+        match builder.build() {
+            Ok(gitignore) => {
+                self.ignores.insert(dir.to_path_buf(), gitignore);
+            }
+            Err(err) => eprintln!("Unable to use {}: {}", ignore_file.display(), err),
+        }
+    }
+}
+
+fn build_glob_set(globs: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+        builder.add(Glob::new(glob)?);
+    }
+    Ok(builder.build()?)
+}
+
+fn matches(set: &GlobSet, path: &Path) -> bool {
+    set.is_match(path) || path.file_name().map(|n| set.is_match(n)).unwrap_or(false)
+}
+
+#[cfg(test)]
+mod test {
+    use std::env;
+    use std::fs;
+    use std::path::Path;
+
+    use super::{WalkFilter, IGNORE_FILE_NAME};
+
+    fn strings(v: &[&str]) -> Vec<String> {
+        v.iter().map(|s| s.to_string()).collect()
+    }
+
+    #[test]
+    fn test_include_exclude() {
+        let mut filter =
+            WalkFilter::new(&strings(&["*.jpg"]), &strings(&["@eaDir", "**/thumbs/**"])).unwrap();
+        assert!(filter.is_included(Path::new("/photos/a.jpg"), false));
+        assert!(!filter.is_included(Path::new("/photos/a.png"), false));
+        assert!(!filter.is_included(Path::new("/photos/@eaDir"), true));
+        assert!(!filter.is_included(Path::new("/photos/thumbs/a.jpg"), false));
+        // Includes don't apply to directories.
+        assert!(filter.is_included(Path::new("/photos/2018"), true));
+    }
+
+    #[test]
+    fn test_bad_glob() {
+        assert!(WalkFilter::new(&strings(&["a[b"]), &[]).is_err());
+    }
+
+    #[test]
+    fn test_ignore_file() {
+        let root = env::temp_dir().join("itools_test_ignore_file");
+        let sub = root.join("keep");
+        fs::create_dir_all(&sub).unwrap();
+        fs::write(root.join(IGNORE_FILE_NAME), "*.thm\nLightroom*/\n").unwrap();
+        fs::write(sub.join(IGNORE_FILE_NAME), "!*.thm\n").unwrap();
+
+        let mut filter = WalkFilter::new(&[], &[]).unwrap();
+        filter.add_root(&root);
+        assert!(!filter.is_included(&root.join("a.thm"), false));
+        assert!(filter.is_included(&root.join("a.jpg"), false));
+        assert!(!filter.is_included(&root.join("Lightroom Previews"), true));
+        assert!(filter.is_included(&sub, true));
+        assert!(filter.is_included(&sub.join("b.thm"), false));
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -1,42 +1,287 @@
 use std::collections::HashSet;
 use std::ffi::OsString;
-use std::path::PathBuf;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::{Path, PathBuf};
 
 use walkdir::WalkDir;
 
-use super::result::Result;
+use super::decoder::Decoder;
+use super::result::{ItoolsError, Result};
+use super::sniff::ImageType;
+use super::walk_filter::WalkFilter;
 
-lazy_static! {
-    static ref EXTENSIONS: HashSet<OsString> = {
-        vec!["gif", "jpg", "jpeg", "png"]
-            .into_iter()
-            .map(|s| OsString::from(s))
-            .collect::<HashSet<OsString>>()
-    };
+#[derive(Clone, Debug, Default)]
+pub struct WalkOptions {
+    // Only look at extensions to decide which files in a directory are images,
+    // instead of reading the start of every file.
+    pub by_extension: bool,
+    // Files in formats that the decoder won't handle are skipped.
+    pub decoder: Decoder,
+    // Globs for the files to use, and for the files and directories to skip. An
+    // empty include list means everything.
+    pub include: Vec<String>,
+    pub exclude: Vec<String>,
+    pub follow_symlinks: bool,
+    // Relative to the directories given; 1 means just their immediate contents.
+    pub max_depth: Option<usize>,
+    // Don't cross into other filesystems (e.g., network mounts under the library).
+    pub one_file_system: bool,
+    // Skip files and directories whose names start with a '.'.
+    pub skip_hidden: bool,
+}
+
+// Only this many walk errors are listed individually. The rest are just counted.
+const MAX_LISTED_ERRORS: usize = 10;
+
+#[derive(Debug, Default)]
+pub struct FileList {
+    pub files: Vec<PathBuf>,
+    pub missing: Vec<PathBuf>,
+    // Files found in directories that didn't look like images.
+    pub non_images: usize,
+    // Images found in directories that can't be decoded without a converter.
+    pub needs_converter: usize,
+    // Directories that couldn't be read, symlink loops, and so on. The rest of
+    // the walk carries on without them.
+    pub walk_errors: Vec<ItoolsError>,
 }
 
-pub fn expand_file_list(files: Vec<OsString>) -> Result<(Vec<PathBuf>, Vec<PathBuf>)> {
+pub fn expand_file_list(files: Vec<OsString>, options: &WalkOptions) -> Result<FileList> {
     let (existing, missing): (Vec<PathBuf>, Vec<PathBuf>) = files
         .into_iter()
         .map(|osstr| PathBuf::from(osstr))
         .partition(|path| path.exists());
 
-    let (directories, mut files): (Vec<PathBuf>, Vec<PathBuf>) =
+    // Use canonical paths, so that a file has the same name in the cache no matter
+    // which directory we were run from, or how it was named on the command line.
+    let existing = existing
+        .into_iter()
+        .map(|path| path.canonicalize())
+        .collect::<io::Result<Vec<PathBuf>>>()?;
+
+    // Files named on the command line are always used, whatever they look like, and
+    // whatever the filters say.
+    let (directories, files): (Vec<PathBuf>, Vec<PathBuf>) =
         existing.into_iter().partition(|path| path.is_dir());
+    let mut list = FileList {
+        missing,
+        ..FileList::default()
+    };
+    // The same file can be reached more than once, through symlinks or overlapping
+    // arguments, but should only be hashed once.
+    let mut seen = HashSet::new();
+    for file in files {
+        if seen.insert(file.clone()) {
+            list.files.push(file);
+        }
+    }
 
+    let mut filter = WalkFilter::new(&options.include, &options.exclude)?;
     for dir in directories {
-        for entry in WalkDir::new(dir) {
-            if let Ok(e) = entry {
-                let path = e.path();
-                if let Some(ext) = path.extension() {
-                    // TODO: Deal with upper-case extensions.
-                    if EXTENSIONS.contains(ext) {
-                        files.push(path.into());
+        filter.add_root(&dir);
+        let mut walk_dir = WalkDir::new(dir)
+            .follow_links(options.follow_symlinks)
+            .same_file_system(options.one_file_system);
+        if let Some(max_depth) = options.max_depth {
+            walk_dir = walk_dir.max_depth(max_depth);
+        }
+        let walker = walk_dir.into_iter().filter_entry(|e| {
+            e.depth() == 0
+                || (!(options.skip_hidden && is_hidden(e.path()))
+                    && filter.is_included(e.path(), e.file_type().is_dir()))
+        });
+
+        for entry in walker {
+            let e = match entry {
+                Ok(e) => e,
+                Err(err) => {
+                    list.walk_errors.push(err.into());
+                    continue;
+                }
+            };
+
+            // Follows symlinks, so that linked files are still found.
+            if !e.path().is_file() {
+                continue;
+            }
+            match file_kind(e.path(), options) {
+                FileKind::Image => (),
+                FileKind::NonImage => {
+                    list.non_images += 1;
+                    continue;
+                }
+                FileKind::NeedsConverter => {
+                    list.needs_converter += 1;
+                    continue;
+                }
+            }
+
+            // Anything reached through a symlink gets its real name.
+            let path = if options.follow_symlinks || e.path_is_symlink() {
+                match e.path().canonicalize() {
+                    Ok(path) => path,
+                    Err(err) => {
+                        list.walk_errors.push(err.into());
+                        continue;
                     }
                 }
+            } else {
+                e.path().to_path_buf()
+            };
+            if seen.insert(path.clone()) {
+                list.files.push(path);
             }
         }
     }
 
-    Ok((files, missing))
+    Ok(list)
+}
+
+// Read paths from a file ("-" is stdin), one per line, or separated by NULs if
+// `null_separated` (as written by `find -print0`). Empty entries are ignored.
+pub fn read_file_list(filename: &Path, null_separated: bool) -> Result<Vec<OsString>> {
+    if filename == Path::new("-") {
+        let stdin = io::stdin();
+        let lock = stdin.lock();
+        parse_file_list(lock, null_separated)
+    } else {
+        parse_file_list(BufReader::new(File::open(filename)?), null_separated)
+    }
+}
+
+fn parse_file_list<R: BufRead>(mut reader: R, null_separated: bool) -> Result<Vec<OsString>> {
+    let separator = if null_separated { b'\0' } else { b'\n' };
+    let mut files = Vec::new();
+    let mut buf = Vec::new();
+    loop {
+        buf.clear();
+        if reader.read_until(separator, &mut buf)? == 0 {
+            break;
+        }
+        if buf.last() == Some(&separator) {
+            buf.pop();
+        }
+        if !null_separated && buf.last() == Some(&b'\r') {
+            buf.pop();
+        }
+        if !buf.is_empty() {
+            files.push(bytes_to_os_string(buf.clone()));
+        }
+    }
+    Ok(files)
+}
+
+#[cfg(unix)]
+fn bytes_to_os_string(bytes: Vec<u8>) -> OsString {
+    use std::os::unix::ffi::OsStringExt;
+    OsString::from_vec(bytes)
+}
+
+#[cfg(not(unix))]
+fn bytes_to_os_string(bytes: Vec<u8>) -> OsString {
+    String::from_utf8_lossy(&bytes).into_owned().into()
+}
+
+pub fn print_walk_errors(errors: &[ItoolsError]) {
+    if errors.is_empty() {
+        return;
+    }
+
+    eprintln!("{} problems while walking directories:", errors.len());
+    for err in errors.iter().take(MAX_LISTED_ERRORS) {
+        eprintln!("   {}", err);
+    }
+    if errors.len() > MAX_LISTED_ERRORS {
+        eprintln!("...and {} more.", errors.len() - MAX_LISTED_ERRORS);
+    }
+}
+
+fn is_hidden(path: &Path) -> bool {
+    path.file_name()
+        .map(|name| name.to_string_lossy().starts_with('.'))
+        .unwrap_or(false)
+}
+
+#[derive(Debug, PartialEq)]
+enum FileKind {
+    Image,
+    // Including images in formats that have been turned off.
+    NonImage,
+    NeedsConverter,
+}
+
+fn file_kind(path: &Path, options: &WalkOptions) -> FileKind {
+    let image_type = if options.by_extension {
+        path.extension().and_then(ImageType::from_extension)
+    } else {
+        match ImageType::from_path(path) {
+            Ok(image_type) => image_type,
+            // A file that can't be read is passed along, so that the hasher can
+            // report the problem.
+            Err(_) => return FileKind::Image,
+        }
+    };
+    match image_type {
+        Some(t) if options.decoder.is_enabled(t) => FileKind::Image,
+        Some(t) if options.decoder.needs_converter(t) => FileKind::NeedsConverter,
+        _ => FileKind::NonImage,
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::ffi::OsString;
+    use std::io::Cursor;
+    use std::path::Path;
+
+    use super::{file_kind, is_hidden, parse_file_list, FileKind, WalkOptions};
+
+    #[test]
+    fn test_parse_file_list() {
+        let lines = Cursor::new("a.jpg\nb c.jpg\r\n\n/photos\n");
+        assert_eq!(
+            vec![
+                OsString::from("a.jpg"),
+                OsString::from("b c.jpg"),
+                OsString::from("/photos"),
+            ],
+            parse_file_list(lines, false).unwrap()
+        );
+
+        let nuls = Cursor::new("a.jpg\0new\nline.jpg\0\0last.jpg");
+        assert_eq!(
+            vec![
+                OsString::from("a.jpg"),
+                OsString::from("new\nline.jpg"),
+                OsString::from("last.jpg"),
+            ],
+            parse_file_list(nuls, true).unwrap()
+        );
+    }
+
+    #[test]
+    fn test_is_hidden() {
+        assert!(is_hidden(Path::new("/photos/.thumbnails")));
+        assert!(is_hidden(Path::new(".DS_Store")));
+        assert!(!is_hidden(Path::new("/photos/.hidden/IMG_0001.JPG")));
+        assert!(!is_hidden(Path::new("/")));
+    }
+
+    #[test]
+    fn test_file_kind() {
+        let mut options = WalkOptions {
+            by_extension: true,
+            ..WalkOptions::default()
+        };
+        assert_eq!(FileKind::Image, file_kind(Path::new("a.jpg"), &options));
+        assert_eq!(FileKind::NonImage, file_kind(Path::new("a.txt"), &options));
+        assert_eq!(
+            FileKind::NeedsConverter,
+            file_kind(Path::new("a.heic"), &options)
+        );
+
+        options.decoder.set_heic_converter("magick {} jpg:-");
+        assert_eq!(FileKind::Image, file_kind(Path::new("a.heic"), &options));
+    }
 }